@@ -4,22 +4,178 @@ mod physics;
 
 use wasm_bindgen::prelude::*;
 use serde::{Deserialize, Serialize};
-use glam::DVec2;
-use std::collections::HashMap;
-use crate::pathfinding::flow::FlowField;
+use glam::{DVec2, IVec2};
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use crate::pathfinding::flow::{FlowField, GROUND_MASK};
+use crate::pathfinding::hpa::{HPAGrid, PortalId};
 use crate::pathfinding::navmesh::{NavMesh, Triangle};
 use crate::physics::{RvoManager, Agent};
 
 // --- SNAPSHOT STRUCT ---
 // This represents the entire "Save State" of the simulation.
 // We derive Serialize/Deserialize to allow passing it to JS as a JSON-like object.
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SimSnapshot {
     pub tick_count: u64,
     pub rvo: RvoManager,
     pub flow_field: FlowField,
     // NavMesh is included in case we add dynamic terrain modification later.
     pub nav_mesh: NavMesh, 
+    /// Per-group flow fields (group 0 uses `flow_field`).
+    #[serde(default)]
+    pub group_fields: BTreeMap<u32, FlowField>,
+    /// Per-group, per-terrain-mask flow fields for agents that aren't plain ground units.
+    #[serde(default)]
+    pub terrain_fields: BTreeMap<u32, BTreeMap<u32, FlowField>>,
+    /// Destination of each group's latest FLOW order.
+    #[serde(default)]
+    pub group_targets: BTreeMap<u32, DVec2>,
+    /// Per-group cell usage heatmaps (only tracked while cross-traffic avoidance is on).
+    #[serde(default)]
+    pub traffic: BTreeMap<u32, Vec<f64>>,
+    /// Waypoint paths being followed, keyed by agent id.
+    #[serde(default)]
+    pub paths: BTreeMap<u32, AgentPath>,
+    /// Formations, keyed by leader id.
+    #[serde(default)]
+    pub formations: BTreeMap<u32, Formation>,
+    /// Pending arrival callbacks, keyed by agent id.
+    #[serde(default)]
+    pub arrival_callbacks: BTreeMap<u32, ArrivalCallback>,
+    /// Settings that change how ticks play out (see the matching `set_*` methods).
+    #[serde(default)]
+    pub arrival_sync: BTreeSet<u32>,
+    #[serde(default)]
+    pub bilinear_flow: bool,
+    #[serde(default)]
+    pub cross_traffic_weight: f64,
+    #[serde(default)]
+    pub export_quantum: f64,
+    #[serde(default)]
+    pub max_displacement: f64,
+    /// Next id `create_group` hands out.
+    #[serde(default)]
+    pub next_group: u32,
+    /// Budgeted path queue: pending requests, unclaimed results, next request id and budget.
+    #[serde(default)]
+    pub path_queue: Vec<PathRequest>,
+    #[serde(default)]
+    pub path_results: BTreeMap<u32, Vec<f64>>,
+    #[serde(default)]
+    pub next_path_request: u32,
+    #[serde(default = "default_path_budget")]
+    pub path_budget: usize,
+    /// The loaded HPA graph. Only full snapshots and replays carry it: it never changes once
+    /// loaded, so rewind history leaves it out, and a snapshot without one keeps the current one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hpa: Option<HPAGrid>,
+    /// Rewind history, oldest first (only included if `set_snapshot_history` is on).
+    #[serde(default)]
+    pub history: Vec<SimSnapshot>,
+}
+
+/// Blends agent state between two snapshots (e.g. predicted vs corrected, for rollback display).
+/// Returns a flat buffer in the same layout as the export buffer: [id, x, y, vx, vy, ...].
+/// Agents are matched by id; an agent present in only one snapshot is emitted unchanged.
+/// `t = 0` yields `a`, `t = 1` yields `b`.
+pub fn interpolate_snapshots(a: &SimSnapshot, b: &SimSnapshot, t: f64) -> Vec<f64> {
+    let b_agents: HashMap<u32, &Agent> = b.rvo.agents.iter().map(|agent| (agent.id, agent)).collect();
+    let mut buffer = Vec::with_capacity((a.rvo.agents.len() + b.rvo.agents.len()) * 5);
+
+    let mut push = |id: u32, pos: DVec2, vel: DVec2| {
+        buffer.extend_from_slice(&[id as f64, pos.x, pos.y, vel.x, vel.y]);
+    };
+
+    // Agents from `a`, blended toward their counterpart in `b` if it exists
+    for agent in &a.rvo.agents {
+        match b_agents.get(&agent.id) {
+            Some(other) => push(
+                agent.id,
+                agent.position.lerp(other.position, t),
+                agent.velocity.lerp(other.velocity, t),
+            ),
+            None => push(agent.id, agent.position, agent.velocity),
+        }
+    }
+
+    // Agents that only exist in `b`
+    let a_ids: HashSet<u32> = a.rvo.agents.iter().map(|agent| agent.id).collect();
+    for agent in b.rvo.agents.iter().filter(|agent| !a_ids.contains(&agent.id)) {
+        push(agent.id, agent.position, agent.velocity);
+    }
+
+    buffer
+}
+
+/// Agent position/velocity differences at or below this are not reported by `diff_snapshots`.
+const SNAPSHOT_DIFF_EPSILON: f64 = 1e-9;
+
+/// Human-readable differences between two snapshots, for tracking down where two runs
+/// diverged: tick, agents missing from either side, agent positions and velocities, and
+/// flow field cell costs (walls). Empty if they match. Agents are matched by id.
+pub fn diff_snapshots(a: &SimSnapshot, b: &SimSnapshot) -> Vec<String> {
+    let mut diffs = Vec::new();
+
+    if a.tick_count != b.tick_count {
+        diffs.push(format!("tick: {} vs {}", a.tick_count, b.tick_count));
+    }
+
+    let b_agents: HashMap<u32, &Agent> = b.rvo.agents.iter().map(|agent| (agent.id, agent)).collect();
+    for agent in &a.rvo.agents {
+        let Some(other) = b_agents.get(&agent.id) else {
+            diffs.push(format!("agent {}: only in a", agent.id));
+            continue;
+        };
+        let moved = agent.position.distance(other.position);
+        if moved > SNAPSHOT_DIFF_EPSILON {
+            diffs.push(format!(
+                "agent {}: position ({}, {}) vs ({}, {}), off by {}",
+                agent.id, agent.position.x, agent.position.y, other.position.x, other.position.y, moved
+            ));
+        }
+        if agent.velocity.distance(other.velocity) > SNAPSHOT_DIFF_EPSILON {
+            diffs.push(format!(
+                "agent {}: velocity ({}, {}) vs ({}, {})",
+                agent.id, agent.velocity.x, agent.velocity.y, other.velocity.x, other.velocity.y
+            ));
+        }
+    }
+    let a_ids: HashSet<u32> = a.rvo.agents.iter().map(|agent| agent.id).collect();
+    for agent in b.rvo.agents.iter().filter(|agent| !a_ids.contains(&agent.id)) {
+        diffs.push(format!("agent {}: only in b", agent.id));
+    }
+
+    let (fa, fb) = (&a.flow_field, &b.flow_field);
+    if fa.width != fb.width || fa.height != fb.height {
+        diffs.push(format!("flow field: {}x{} vs {}x{}", fa.width, fa.height, fb.width, fb.height));
+    } else {
+        for (idx, (&cost_a, &cost_b)) in fa.costs.iter().zip(&fb.costs).enumerate() {
+            if cost_a != cost_b {
+                diffs.push(format!("cell ({}, {}): cost {} vs {}", idx % fa.width, idx / fa.width, cost_a, cost_b));
+            }
+        }
+    }
+
+    diffs
+}
+
+/// The flow field an agent follows: its group's field (group 0 = the global one), or the
+/// group's copy for its terrain mask if it isn't a plain ground unit. None if the group
+/// was never ordered.
+fn agent_field<'a>(
+    flow_field: &'a FlowField,
+    group_fields: &'a BTreeMap<u32, FlowField>,
+    terrain_fields: &'a BTreeMap<u32, BTreeMap<u32, FlowField>>,
+    agent: &Agent,
+) -> Option<&'a FlowField> {
+    if let Some(field) = terrain_fields.get(&agent.group).and_then(|fields| fields.get(&agent.terrain_mask)) {
+        return Some(field);
+    }
+    match agent.group {
+        0 => Some(flow_field),
+        group => group_fields.get(&group),
+    }
 }
 
 // --- MAIN SIMULATION STRUCT ---
@@ -30,11 +186,86 @@ pub struct Simulation {
     // We keep a parallel vector of raw data for fast export to JS
     // Layout: [id, x, y, vel_x, vel_y, ...repeat...]
     export_buffer: Vec<f64>, 
+    // The export buffer as of the previous tick (same layout), for render interpolation.
+    prev_export_buffer: Vec<f64>,
     
     // Systems
     flow_field: FlowField,
+    // Flow fields for agent groups other than 0. BTreeMap keeps iteration deterministic.
+    group_fields: BTreeMap<u32, FlowField>,
+    // Group -> terrain mask -> field, for agents whose terrain mask isn't GROUND_MASK.
+    terrain_fields: BTreeMap<u32, BTreeMap<u32, FlowField>>,
+    // Destination of each group's latest FLOW order (FLEE orders have none), for goal reporting.
+    group_targets: BTreeMap<u32, DVec2>,
     nav_mesh: NavMesh,
     rvo: RvoManager,
+    /// Pre-built HPA graph (baked offline), if one has been loaded.
+    hpa: Option<HPAGrid>,
+
+    // Per-agent waypoint paths. An agent with a path follows it instead of its flow field.
+    paths: BTreeMap<u32, AgentPath>,
+
+    // Formations keyed by leader id. Members steer toward their slot around the leader.
+    formations: BTreeMap<u32, Formation>,
+
+    // Cross-traffic avoidance: decaying per-group heatmap of occupied cells,
+    // turned into soft costs for the other groups' fields. Weight 0 = off.
+    traffic: BTreeMap<u32, Vec<f64>>,
+    cross_traffic_weight: f64,
+
+    // Next id handed out by `create_group` (bumped past any group already in use).
+    next_group: u32,
+
+    // Groups whose flow-following members pace themselves to arrive together.
+    arrival_sync: BTreeSet<u32>,
+
+    // Flow followers blend the four surrounding cell vectors instead of snapping to one.
+    bilinear_flow: bool,
+
+    // MOVE orders carrying a callback id, keyed by agent, and the flat
+    // [id, callback_id, ...] arrivals reported during the last tick.
+    arrival_callbacks: BTreeMap<u32, ArrivalCallback>,
+    arrival_events: Vec<u32>,
+
+    // Exported positions are rounded to multiples of this step (0 = full precision).
+    // The simulation itself always keeps full precision.
+    export_quantum: f64,
+
+    // Upper bound on agent count (memory-bounded embeddings). usize::MAX = unlimited.
+    max_agents: usize,
+
+    // Diagnostics: agents that found no reachable flow target during the last tick.
+    no_target_agents: Vec<u32>,
+
+    // Debug guard: cap on how far an agent may move in one tick (0 = off), and the ids
+    // of agents it had to clamp during the last tick.
+    max_displacement: f64,
+    displacement_events: Vec<u32>,
+
+    // Attack-move: [agent, enemy] pairs of agents halted by an enemy during the last tick.
+    attack_events: Vec<u32>,
+
+    // Diagnostics: A* nodes expanded by the most recent path query.
+    last_path_expansions: Cell<usize>,
+
+    // Diagnostics: full HPA abstract searches run by the most recent `paths_from` call.
+    last_abstract_searches: Cell<usize>,
+
+    // Queued navmesh path requests, solved a bounded amount per tick (budget in A* expansions, 0 = paused).
+    path_queue: VecDeque<PathRequest>,
+    path_results: BTreeMap<u32, Vec<f64>>,
+    next_path_request: u32,
+    path_budget: usize,
+    path_budget_spent: usize,
+
+    // Rewind history: state at the start of each of the last `history_length` ticks, oldest first.
+    history: VecDeque<SimSnapshot>,
+    history_length: usize,
+    // If true, snapshots carry the rewind history (off by default to keep them small).
+    snapshot_history: bool,
+
+    // Replay being recorded (see `start_recording`), if any.
+    recording: Option<ReplayLog>,
 }
 
 // Helper struct for parsing JSON commands from JS
@@ -45,8 +276,133 @@ pub struct InputCommand {
     pub target_x: f64,
     pub target_y: f64,
     pub mode: Option<String>,
+    /// FLEE only: distance from the threat beyond which agents stop running.
+    pub max_cost: Option<f64>,
+    /// ATTACK only: how close an enemy must be for a member to halt and engage.
+    pub detect_radius: Option<f64>,
+    /// FLOW/FLEE/ATTACK only: which group's flow field to update (default 0 = global field).
+    pub group: Option<u32>,
+    /// FORMATION only: units to arrange around the target.
+    pub members: Option<Vec<u32>>,
+    /// FORMATION only: flat `[x, y, ...]` slot offsets from the target, in the formation's
+    /// frame (+x = facing, +y = to its left).
+    pub offsets: Option<Vec<f64>>,
+    /// FORMATION only: direction the formation faces at the destination (default +x).
+    pub facing_x: Option<f64>,
+    pub facing_y: Option<f64>,
+    /// Opaque id reported by `get_arrival_events` once the ordered agent (every member,
+    /// for FORMATION) reaches this order's destination.
+    pub callback_id: Option<u32>,
+}
+
+/// A waypoint list handed to an agent (e.g. the output of `find_path_navmesh`).
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AgentPath {
+    pub waypoints: Vec<DVec2>,
+    /// Index of the waypoint currently being sought (== len once the end is reached).
+    pub next: usize,
+    /// Pursuit target: if set, the path leads to this agent and is replanned as it moves.
+    #[serde(default)]
+    pub chase: Option<u32>,
+    /// Pursuit only: minimum ticks between replans (0 = every tick).
+    #[serde(default)]
+    pub replan_interval: u64,
+    /// Pursuit only: tick at which the path was last planned.
+    #[serde(default)]
+    pub planned_at: u64,
+    /// If set, the agent keeps returning to the last waypoint after arriving
+    /// (e.g. a formation slot it was shoved out of) instead of stopping wherever it is.
+    #[serde(default)]
+    pub hold: bool,
+}
+
+/// A leader plus members that keep fixed slots relative to it. Slot offsets are in the
+/// formation's local frame (+x = forward along `heading`, +y = to its left) and rotate
+/// with the heading, which follows the formation's movement direction (its leader's velocity).
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Formation {
+    pub members: Vec<(u32, DVec2)>,
+    /// Unit vector the formation faces.
+    pub heading: DVec2,
+}
+
+/// Where an agent was ordered to, and the callback id to report when it gets there.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ArrivalCallback {
+    pub destination: DVec2,
+    pub callback_id: u32,
+}
+
+/// One entry of `paths_from`: the path length to a target and its waypoints
+/// (flat `[x, y, ...]`). `cost` is `None` (undefined in JS) if the target is unreachable.
+#[derive(Serialize)]
+pub struct PathResult {
+    pub cost: Option<f64>,
+    pub path: Vec<f64>,
+}
+
+/// A navmesh path query waiting in the budgeted queue.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PathRequest {
+    id: u32,
+    start: DVec2,
+    end: DVec2,
+}
+
+/// Replay blob produced by `stop_recording`: the state when recording started,
+/// then the raw input JSON of every tick since, in order.
+#[derive(Serialize, Deserialize)]
+struct ReplayLog {
+    start: SimSnapshot,
+    inputs: Vec<String>,
+}
+
+/// Default danger radius for FLEE orders that don't specify `max_cost`.
+const DEFAULT_FLEE_COST: f64 = 20.0;
+
+/// Default enemy detection radius for ATTACK orders that don't specify `detect_radius`.
+const DEFAULT_ATTACK_RADIUS: f64 = 5.0;
+
+/// Velocity multiplier for agents standing on their flow target (0 = frozen, 1 = no damping).
+const ARRIVAL_DAMPING: f64 = 0.5;
+
+/// Per-tick decay of the cross-traffic heatmaps (closer to 1 = longer memory).
+const TRAFFIC_DECAY: f64 = 0.95;
+
+/// A formation leader must want to move at least this fast (world units per tick) before its
+/// heading turns the formation; below it the leader is settling or waiting and the heading holds.
+const FORMATION_TURN_MIN_SPEED: f64 = 0.1;
+
+/// Default per-tick A* expansion budget for queued path requests (see `set_path_budget`).
+const DEFAULT_PATH_BUDGET: usize = 10_000;
+
+fn default_path_budget() -> usize {
+    DEFAULT_PATH_BUDGET
 }
 
+/// Distance at which a path follower counts a waypoint as reached.
+const WAYPOINT_RADIUS: f64 = 0.25;
+
+/// An agent that has stopped (arrived) within this distance of its ordered destination fires
+/// its arrival callback. One cell, since flow agents stop anywhere on the target cell.
+const ARRIVAL_EVENT_RADIUS: f64 = 1.0;
+
+/// An agent that wants to move but advances less than this fraction of its preferred speed
+/// in its desired direction during a tick counts as stuck for that tick. Relative to the
+/// preferred velocity, so agents deliberately slowed down (arrival sync, slopes) aren't stuck.
+const STUCK_PROGRESS: f64 = 0.1;
+
+/// Consecutive stuck ticks before the agent starts getting unstick nudges (every tick,
+/// until it makes progress again).
+const STUCK_TICKS: u32 = 15;
+
+/// Sideways unstick nudge, as a fraction of max speed.
+const UNSTICK_NUDGE: f64 = 0.5;
+
+/// Most candidate points `spawn_ring` tries before giving up with what it has, so a tiny
+/// `spacing` (millions of rings) or a huge `count` can't stall the caller.
+const SPAWN_RING_MAX_CANDIDATES: usize = 4096;
+
 #[wasm_bindgen]
 impl Simulation {
     #[wasm_bindgen(constructor)]
@@ -57,31 +413,327 @@ impl Simulation {
         Simulation {
             tick_count: 0,
             export_buffer: Vec::new(),
+            prev_export_buffer: Vec::new(),
             flow_field: FlowField::new(100, 100),
+            group_fields: BTreeMap::new(),
+            terrain_fields: BTreeMap::new(),
+            group_targets: BTreeMap::new(),
             nav_mesh: NavMesh::new(),
             rvo: RvoManager::new(),
+            hpa: None,
+            paths: BTreeMap::new(),
+            formations: BTreeMap::new(),
+            traffic: BTreeMap::new(),
+            cross_traffic_weight: 0.0,
+            next_group: 1,
+            arrival_sync: BTreeSet::new(),
+            bilinear_flow: false,
+            arrival_callbacks: BTreeMap::new(),
+            arrival_events: Vec::new(),
+            export_quantum: 0.0,
+            max_agents: usize::MAX,
+            no_target_agents: Vec::new(),
+            max_displacement: 0.0,
+            displacement_events: Vec::new(),
+            attack_events: Vec::new(),
+            last_path_expansions: Cell::new(0),
+            last_abstract_searches: Cell::new(0),
+            path_queue: VecDeque::new(),
+            path_results: BTreeMap::new(),
+            next_path_request: 0,
+            path_budget: DEFAULT_PATH_BUDGET,
+            path_budget_spent: 0,
+            history: VecDeque::new(),
+            history_length: 0,
+            snapshot_history: false,
+            recording: None,
         }
     }
 
     // JS provides the ID now (from bitECS)
-    pub fn add_agent(&mut self, id: u32, x: f64, y: f64, radius: f64, max_speed: f64) {
-        self.rvo.add_agent(Agent {
-            id,
-            position: DVec2::new(x, y),
-            velocity: DVec2::ZERO,
-            radius,
-            max_speed,
-            pref_velocity: DVec2::ZERO,
-        });
+    // Returns false (and adds nothing) if the simulation is already at `max_agents`,
+    // or if any value is NaN/infinite.
+    pub fn add_agent(&mut self, id: u32, x: f64, y: f64, radius: f64, max_speed: f64) -> bool {
+        if self.rvo.agents.len() >= self.max_agents || ![x, y, radius, max_speed].iter().all(|v| v.is_finite()) {
+            return false;
+        }
+
+        self.rvo.add_agent(Agent::new(id, DVec2::new(x, y), radius, max_speed));
         
         // --- FIX: Rebuild buffer immediately so renderer sees unit on frame 0 ---
         self.rebuild_export_buffer();
+        true
+    }
+
+    /// Ids of all live agents, sorted. Much cheaper than a snapshot when JS only needs
+    /// to check which ids exist (e.g. to validate a command).
+    pub fn agent_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.rvo.agents.iter().map(|a| a.id).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Caps how many agents `add_agent` accepts (e.g. for mobile browsers).
+    /// Agents already in the simulation are kept even if they exceed the new cap.
+    pub fn set_max_agents(&mut self, max: usize) {
+        self.max_agents = max;
+    }
+
+    /// Enables/disables crowd relaxation (avoidance radii shrink slightly in dense packs).
+    pub fn set_crowd_relaxation(&mut self, enabled: bool) {
+        self.rvo.crowd_relaxation = enabled;
+    }
+
+    /// Enables/disables density slowdown (agents ease off as the crowd ahead thickens).
+    pub fn set_density_slowdown(&mut self, enabled: bool) {
+        self.rvo.density_slowdown = enabled;
+    }
+
+    /// Enables/disables smooth flow following: agents steer along the bilinear blend of the
+    /// surrounding cell vectors (walls excluded) instead of their cell's vector, which
+    /// removes the zig-zag on diagonals.
+    pub fn set_bilinear_flow(&mut self, enabled: bool) {
+        self.bilinear_flow = enabled;
+    }
+
+    /// Enables/disables touched-cell resets for flow field recomputes: only the cells the
+    /// previous integration reached are cleared instead of the whole grid. Worth it on
+    /// large maps where targets only reach a small area. Fields created later inherit it.
+    pub fn set_touched_reset(&mut self, enabled: bool) {
+        self.flow_field.reset_touched_only = enabled;
+        for field in self.group_fields.values_mut() {
+            field.reset_touched_only = enabled;
+        }
+        for field in self.terrain_fields.values_mut().flat_map(|fields| fields.values_mut()) {
+            field.reset_touched_only = enabled;
+        }
+    }
+
+    /// Marks an agent as navmesh-bound: it gets snapped back onto the mesh
+    /// whenever avoidance pushes it off into the void.
+    pub fn set_navmesh_bound(&mut self, id: u32, bound: bool) {
+        if let Some(agent) = self.rvo.agents.iter_mut().find(|a| a.id == id) {
+            agent.navmesh_bound = bound;
+        }
+    }
+
+    /// Freezes/unfreezes an agent (stunned, garrisoned). Frozen agents keep their
+    /// position and stay in snapshots, and others still avoid them.
+    pub fn set_frozen(&mut self, id: u32, frozen: bool) {
+        if let Some(agent) = self.rvo.agents.iter_mut().find(|a| a.id == id) {
+            agent.frozen = frozen;
+        }
+    }
+
+    /// Gives an agent a waypoint path to follow, as flat `[x, y, x, y, ...]`
+    /// (the format returned by `find_path_navmesh`). While it has a path the agent
+    /// ignores its flow field. An empty list clears the path. Paths with NaN/infinite
+    /// coordinates are ignored.
+    pub fn set_agent_path(&mut self, id: u32, waypoints: &[f64]) {
+        if !waypoints.iter().all(|v| v.is_finite()) {
+            return;
+        }
+        let waypoints: Vec<DVec2> = waypoints.chunks_exact(2).map(|p| DVec2::new(p[0], p[1])).collect();
+        if waypoints.is_empty() {
+            self.paths.remove(&id);
+        } else {
+            self.paths.insert(id, AgentPath { waypoints, next: 0, chase: None, replan_interval: 0, planned_at: 0, hold: false });
+            self.mark_ordered(id);
+        }
+    }
+
+    /// Makes an agent pursue another one. The path (HPA if loaded, else navmesh) is
+    /// recomputed at most every `replan_interval` ticks; in between the agent follows
+    /// its existing path. It replans early only if the last attempt found no route.
+    /// Stops when either agent is gone.
+    pub fn set_agent_chase(&mut self, id: u32, target_id: u32, replan_interval: u32) {
+        let path = AgentPath {
+            waypoints: Vec::new(), // Planned on the next tick
+            next: 0,
+            chase: Some(target_id),
+            replan_interval: replan_interval as u64,
+            planned_at: 0,
+            hold: false,
+        };
+        self.paths.insert(id, path);
+        self.mark_ordered(id);
+    }
+
+    /// Groups agents into a formation around `leader`. `offsets` holds one local slot per
+    /// member as flat `[x, y, ...]` (+x = forward, +y = left of the formation's heading).
+    /// The leader keeps steering as usual; members steer to their rotated slots instead of
+    /// their own orders. Replaces any formation the leader already had.
+    pub fn create_formation(&mut self, leader: u32, members: &[u32], offsets: &[f64]) -> Result<(), String> {
+        if offsets.len() != members.len() * 2 {
+            return Err(format!("Expected {} offset values for {} members, got {}", members.len() * 2, members.len(), offsets.len()));
+        }
+        if !offsets.iter().all(|v| v.is_finite()) {
+            return Err("Formation offsets must be finite".to_string());
+        }
+        let Some(leader_agent) = self.rvo.agents.iter().find(|a| a.id == leader) else {
+            return Err(format!("Unknown leader {}", leader));
+        };
+
+        // Start facing wherever the leader faces (+x if it has no heading yet)
+        let heading = if leader_agent.facing != DVec2::ZERO { leader_agent.facing } else { DVec2::X };
+        let members = members
+            .iter()
+            .zip(offsets.chunks_exact(2))
+            .map(|(&id, o)| (id, DVec2::new(o[0], o[1])))
+            .collect();
+
+        self.formations.insert(leader, Formation { members, heading });
+        Ok(())
+    }
+
+    /// Dissolves the formation led by `leader`; members go back to their own orders.
+    pub fn disband_formation(&mut self, leader: u32) {
+        self.formations.remove(&leader);
+    }
+
+    /// Drops an agent's path; it goes back to following its flow field.
+    pub fn clear_agent_path(&mut self, id: u32) {
+        self.paths.remove(&id);
+    }
+
+    /// Makes an agent a separation-only prop (loot, corpses): it ignores flow fields, paths
+    /// and formations and only moves to get out of overlaps. Others still avoid it.
+    pub fn set_separation_only(&mut self, id: u32, enabled: bool) {
+        if let Some(agent) = self.rvo.agents.iter_mut().find(|a| a.id == id) {
+            agent.separation_only = enabled;
+        }
+    }
+
+    /// Limits how fast an agent can turn (radians per tick, 0 = instant). With
+    /// `turn_in_place`, it rotates on the spot until roughly facing its destination
+    /// before it starts moving (tank-style), instead of strafing.
+    pub fn set_agent_turning(&mut self, id: u32, max_turn_rate: f64, turn_in_place: bool) {
+        if !max_turn_rate.is_finite() {
+            return;
+        }
+        if let Some(agent) = self.rvo.agents.iter_mut().find(|a| a.id == id) {
+            agent.max_turn_rate = max_turn_rate.max(0.0);
+            agent.turn_in_place = turn_in_place;
+        }
+    }
+
+    /// Assigns an agent to a flow group (0 = global flow field).
+    pub fn set_agent_group(&mut self, id: u32, group: u32) {
+        if let Some(agent) = self.rvo.agents.iter_mut().find(|a| a.id == id) {
+            agent.group = group;
+        }
+    }
+
+    /// Sets the speed an agent normally walks at (clamped to its max speed; 0 = max speed).
+    /// It may still speed up to its max speed to dodge others.
+    pub fn set_pref_speed(&mut self, id: u32, speed: f64) {
+        if !speed.is_finite() {
+            return;
+        }
+        if let Some(agent) = self.rvo.agents.iter_mut().find(|a| a.id == id) {
+            agent.pref_speed = speed.clamp(0.0, agent.max_speed);
+        }
+    }
+
+    /// Sets which terrain types an agent may enter (bit per terrain type, e.g. 0b11 for
+    /// amphibious units that cross water). Takes effect on its group's next FLOW/FLEE order.
+    pub fn set_agent_terrain_mask(&mut self, id: u32, mask: u32) {
+        if let Some(agent) = self.rvo.agents.iter_mut().find(|a| a.id == id) {
+            agent.terrain_mask = mask;
+        }
+    }
+
+    /// Sets a map cell's terrain type (0 = ground, 1 = water).
+    pub fn set_terrain(&mut self, x: usize, y: usize, terrain: u8) {
+        self.flow_field.set_terrain(x, y, terrain);
+    }
+
+    /// Sets the environmental force (current, conveyor, wind) on a map cell.
+    /// Agents on it drift by (fx, fy) per tick on top of their own steering.
+    /// NaN/infinite forces are ignored.
+    pub fn set_force(&mut self, x: usize, y: usize, fx: f64, fy: f64) {
+        self.flow_field.set_force(x, y, fx, fy);
+    }
+
+    /// Returns a group id no agent or flow field uses yet, for a new commander group.
+    /// Ids only grow, so returns undefined once they run out (`u32::MAX` is never handed out).
+    pub fn create_group(&mut self) -> Option<u32> {
+        let used = self
+            .rvo
+            .agents
+            .iter()
+            .map(|a| a.group)
+            .chain(self.group_fields.keys().copied())
+            .max()
+            .unwrap_or(0);
+        let id = self.next_group.max(used.checked_add(1)?);
+        self.next_group = id.checked_add(1)?;
+        Some(id)
+    }
+
+    /// Assigns several agents to a group at once (see `set_agent_group`).
+    pub fn add_to_group(&mut self, group: u32, ids: &[u32]) {
+        let ids: HashSet<u32> = ids.iter().copied().collect();
+        for agent in self.rvo.agents.iter_mut().filter(|a| ids.contains(&a.id)) {
+            agent.group = group;
+        }
+    }
+
+    /// Orders a whole group at once, instead of one MOVE command per member:
+    /// "FLOW" sends it to (x, y), "ATTACK" attack-moves it there (default detection radius),
+    /// "FLEE" makes it run from there (default flee distance).
+    /// Takes effect immediately. Returns false for an unknown mode.
+    pub fn move_group(&mut self, group: u32, x: f64, y: f64, mode: &str) -> bool {
+        match mode {
+            "FLOW" => {
+                self.order_group_flow(group, x, y);
+            }
+            "ATTACK" => self.order_group_attack(group, x, y, DEFAULT_ATTACK_RADIUS),
+            "FLEE" => self.order_group_flee(group, x, y, DEFAULT_FLEE_COST),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Enables cross-traffic avoidance: each group's field gets a soft cost on cells
+    /// recently used by other groups, so crossing crowds route around each other.
+    /// The weight scales the heatmap into extra path cost. 0 disables it; NaN/infinite
+    /// weights are ignored.
+    pub fn set_cross_traffic_weight(&mut self, weight: f64) {
+        if !weight.is_finite() {
+            return;
+        }
+        self.cross_traffic_weight = weight;
+        if weight == 0.0 {
+            self.traffic.clear();
+        }
+    }
+
+    /// Group arrival: members of `group` following its flow field slow down in proportion to how
+    /// much closer they are than the group's furthest member, so they all arrive at about the
+    /// same time instead of the front runners stopping while stragglers catch up.
+    pub fn set_group_arrival_sync(&mut self, group: u32, enabled: bool) {
+        if enabled {
+            self.arrival_sync.insert(group);
+        } else {
+            self.arrival_sync.remove(&group);
+        }
     }
 
     // The Main Deterministic Loop
     pub fn tick(&mut self, input_json: String) {
+        if self.history_length > 0 {
+            while self.history.len() >= self.history_length {
+                self.history.pop_front();
+            }
+            self.history.push_back(self.make_snapshot_without_history());
+        }
         self.tick_count += 1;
 
+        if let Some(log) = &mut self.recording {
+            log.inputs.push(input_json.clone());
+        }
+
         // 1. Process Inputs
         // We parse the JSON string sent from JS. 
         // In a real network scenario, this JSON comes from the server "Tick Bundle".
@@ -89,46 +741,280 @@ impl Simulation {
         
         for input in inputs {
             if input.action == "MOVE" {
-                if input.mode.as_deref() == Some("FLOW") {
-                    // Update the global flow field (Dijkstra)
-                    self.flow_field.generate_target(input.target_x, input.target_y);
-                } else {
-                    // Direct unit command (fallback)
-                    self.rvo.update_agent_state(
-                        input.id, 
-                        DVec2::new(input.target_x, input.target_y), // Temporary pos hack
-                        DVec2::ZERO // Reset velocity
-                    );
+                // A new order replaces the agent's pending callback (or cancels it, if it has none).
+                // FORMATION orders register one per member instead.
+                if input.mode.as_deref() != Some("FORMATION") {
+                    let destination = DVec2::new(input.target_x, input.target_y);
+                    match input.callback_id {
+                        Some(callback_id) => {
+                            self.arrival_callbacks.insert(input.id, ArrivalCallback { destination, callback_id });
+                        }
+                        None => {
+                            self.arrival_callbacks.remove(&input.id);
+                        }
+                    }
+                }
+
+                match input.mode.as_deref() {
+                    Some("FLOW") => {
+                        // Update the group's flow field (Dijkstra)
+                        self.order_group_flow(input.group.unwrap_or(0), input.target_x, input.target_y);
+                    }
+                    Some("ATTACK") => {
+                        // Flow to the target, halting for enemies met on the way
+                        let radius = input.detect_radius.unwrap_or(DEFAULT_ATTACK_RADIUS);
+                        self.order_group_attack(input.group.unwrap_or(0), input.target_x, input.target_y, radius);
+                    }
+                    Some("FLEE") => {
+                        // Invert the group's flow field: agents run away from the target
+                        let max_cost = input.max_cost.unwrap_or(DEFAULT_FLEE_COST);
+                        self.order_group_flee(input.group.unwrap_or(0), input.target_x, input.target_y, max_cost);
+                    }
+                    Some("FORMATION") => {
+                        // Composite order: walk to the destination and line up there
+                        let facing = DVec2::new(input.facing_x.unwrap_or(1.0), input.facing_y.unwrap_or(0.0));
+                        self.order_formation_move(
+                            DVec2::new(input.target_x, input.target_y),
+                            facing,
+                            &input.members.unwrap_or_default(),
+                            &input.offsets.unwrap_or_default(),
+                            input.callback_id,
+                        );
+                    }
+                    Some("STOP") => {
+                        // Halt and hold the current spot until the next order
+                        self.mark_ordered(input.id);
+                        self.order_stop(input.id);
+                    }
+                    _ => {
+                        // Direct unit command (fallback)
+                        self.mark_ordered(input.id);
+                        self.rvo.update_agent_state(
+                            input.id, 
+                            DVec2::new(input.target_x, input.target_y), // Temporary pos hack
+                            DVec2::ZERO // Reset velocity
+                        );
+                        // Not a seek mode: drop the old motion so it doesn't carry over
+                        self.reset_velocity(input.id);
+                    }
                 }
             }
         }
 
+        // Pursuits: refresh paths whose replan cooldown expired (or that ran out)
+        self.update_chases();
+
+        // Formations: turn with the group and hand each member its slot position
+        let slots = self.update_formations();
+
+        // Group arrival: the latest expected arrival (remaining cost / speed) in each synced group
+        let group_etas = self.group_arrival_etas(&slots);
+
+        // Attack-move: agents with an enemy in detection range hold this tick
+        let engaged = self.attack_move_engagements();
+        self.attack_events.clear();
+
         // 2. Pathfinding (Flow Field Integration)
         // Every agent looks at the flow field tile underneath them to get their desired direction.
-        for i in 0..self.rvo.agents.len() {
-            let agent_pos = self.rvo.agents[i].position;
-            let flow_dir = self.flow_field.get_direction(agent_pos.x, agent_pos.y);
-            
+        let mut arrived = vec![false; self.rvo.agents.len()];
+        self.no_target_agents.clear();
+        let flow_ordered = !self.group_fields.is_empty() || self.flow_field.has_target();
+        for (agent, arrived) in self.rvo.agents.iter_mut().zip(arrived.iter_mut()) {
+            if agent.frozen {
+                continue;
+            }
+            let agent_pos = agent.position;
+
+            // Props never navigate: they only move when the separation pass pushes them
+            if agent.separation_only {
+                agent.pref_velocity = DVec2::ZERO;
+                continue;
+            }
+
+            // Formation members seek their slot (clamped so they settle into it)
+            if let Some(&slot) = slots.get(&agent.id) {
+                agent.pref_velocity = (slot - agent_pos).clamp_length_max(agent.cruise_speed());
+                continue;
+            }
+
+            // Agents with an explicit path seek their current waypoint instead
+            if let Some(path) = self.paths.get_mut(&agent.id) {
+                while path.next < path.waypoints.len()
+                    && agent_pos.distance(path.waypoints[path.next]) <= WAYPOINT_RADIUS
+                {
+                    path.next += 1;
+                }
+
+                match path.waypoints.get(path.next) {
+                    // Clamped so the agent slows onto each waypoint instead of overshooting it
+                    Some(&waypoint) => agent.pref_velocity = (waypoint - agent_pos).clamp_length_max(agent.cruise_speed()),
+                    // Holding: drift back onto the end point if pushed off it
+                    None if path.hold && !path.waypoints.is_empty() => {
+                        let end = path.waypoints[path.waypoints.len() - 1];
+                        agent.pref_velocity = (end - agent_pos).clamp_length_max(agent.cruise_speed());
+                        *arrived = agent_pos.distance(end) <= WAYPOINT_RADIUS;
+                    }
+                    None => {
+                        agent.pref_velocity = DVec2::ZERO;
+                        *arrived = true;
+                    }
+                }
+                continue;
+            }
+
+            // No FLOW/FLEE order given yet: idle in place, facing +x until the first move.
+            // Nothing was mis-assigned, so this isn't reported as "no target".
+            if !flow_ordered {
+                agent.pref_velocity = DVec2::ZERO;
+                if agent.facing == DVec2::ZERO {
+                    agent.facing = DVec2::X;
+                }
+                continue;
+            }
+
+            // Group 0 follows the global field; a group that was never ordered has no field yet.
+            let Some(field) = agent_field(&self.flow_field, &self.group_fields, &self.terrain_fields, agent) else {
+                agent.pref_velocity = DVec2::ZERO;
+                self.no_target_agents.push(agent.id);
+                continue;
+            };
+
+            // Field never given a target, or the target can't be reached from here
+            if !field.has_route(agent_pos.x, agent_pos.y) {
+                agent.pref_velocity = DVec2::ZERO;
+                self.no_target_agents.push(agent.id);
+                continue;
+            }
+
+            // On the target cell there is no downhill neighbor: stop seeking.
+            if field.is_target(agent_pos.x, agent_pos.y) {
+                agent.pref_velocity = DVec2::ZERO;
+                *arrived = true;
+                continue;
+            }
+
+            // Attack-moving into an enemy: stop and let JS start the fight. Resumes once it's gone.
+            if let Some(&enemy) = engaged.get(&agent.id) {
+                agent.pref_velocity = DVec2::ZERO;
+                self.attack_events.extend([agent.id, enemy]);
+                continue;
+            }
+
+            // Crowds hitting an obstacle head-on split between its sides by id
+            let flow_dir = field.get_direction_split(agent_pos.x, agent_pos.y, agent.id);
+            let next_cell = agent_pos.round() + flow_dir;
+
             // Set the "Preferred Velocity" for the physics engine
-            self.rvo.agents[i].pref_velocity = flow_dir * self.rvo.agents[i].max_speed;
+            agent.pref_velocity = if flow_dir != DVec2::ZERO && field.is_target(next_cell.x, next_cell.y) {
+                // Final approach: aim at the target cell center so fast units don't overshoot and orbit it
+                (next_cell - agent_pos).clamp_length_max(agent.cruise_speed())
+            } else {
+                let flow_dir = if self.bilinear_flow { field.get_direction_bilinear(agent_pos.x, agent_pos.y) } else { flow_dir };
+                // Climbing slows agents down (no-op on flat maps)
+                flow_dir * agent.cruise_speed() * field.slope_speed_factor(agent_pos.x, agent_pos.y, flow_dir)
+            };
+
+            // Group arrival: pace to land together with the group's furthest member
+            if let (Some(&group_eta), Some(cost)) = (group_etas.get(&agent.group), field.cost_to_target(agent_pos.x, agent_pos.y)) {
+                if group_eta > 0.0 && agent.cruise_speed() > 0.0 {
+                    agent.pref_velocity *= (cost / agent.cruise_speed() / group_eta).min(1.0);
+                }
+            }
         }
 
+        // Heading: rotate toward the desired direction, tank-style units pivot first
+        self.rvo.apply_turning();
+
+        // Congestion: slow down when walking into a dense crowd (no-op unless enabled)
+        self.rvo.apply_density_slowdown();
+
         // 3. Physics (RVO / Collision Avoidance)
         // We calculate new velocities based on neighbors to avoid overlapping.
+        self.rvo.update_effective_radii();
+        self.rvo.update_spatial_hash();
         let mut new_velocities = Vec::new();
         for i in 0..self.rvo.agents.len() {
             new_velocities.push(self.rvo.compute_new_velocity(i));
         }
 
         // 4. Update State
+        self.displacement_events.clear();
         for (i, vel) in new_velocities.into_iter().enumerate() {
             let agent = &mut self.rvo.agents[i];
+            // Frozen agents stop dead: no integration, and a zero velocity so others see a static obstacle
+            if agent.frozen {
+                agent.velocity = DVec2::ZERO;
+                continue;
+            }
+            // Arrived agents only move when shoved; damp it so they settle instead of drifting off.
+            let vel = if arrived[i] { vel * ARRIVAL_DAMPING } else { vel };
+            // Environmental forces (currents, conveyors) push regardless of intent
+            let vel = vel + self.flow_field.get_force(agent.position.x, agent.position.y);
+            // Wedged for a while: sidestep to break the deadlock (left for even ids, right for odd).
+            // If that doesn't free it within another STUCK_TICKS, that side is walled off (an
+            // inside corner), so it sidesteps the other way until it gets going again.
+            let vel = if agent.stuck_ticks >= STUCK_TICKS {
+                let left = agent.id.is_multiple_of(2) != (agent.stuck_ticks >= STUCK_TICKS * 2);
+                let side = if left { 1.0 } else { -1.0 };
+                vel + agent.pref_velocity.normalize_or_zero().perp() * side * UNSTICK_NUDGE * agent.max_speed
+            } else {
+                vel
+            };
+            // Debug guard: a corrupted agent (absurd speed or force) gets clamped and reported
+            // instead of jumping across the map
+            let vel = if self.max_displacement > 0.0 && (!vel.is_finite() || vel.length() > self.max_displacement) {
+                self.displacement_events.push(agent.id);
+                if vel.is_finite() { vel.clamp_length_max(self.max_displacement) } else { DVec2::ZERO }
+            } else {
+                vel
+            };
+            let old_position = agent.position;
             agent.velocity = vel;
             agent.position += vel;
+
+            // Kinematic clamp: navmesh agents pushed off the mesh are projected back onto it
+            if agent.navmesh_bound {
+                if let Some(clamped) = self.nav_mesh.clamp_to_mesh(agent.position) {
+                    agent.position = clamped;
+                }
+            }
+
+            // Stuck timer: counts ticks spent trying to move without getting anywhere.
+            // Sliding sideways (e.g. from the nudge itself) isn't progress.
+            let wants_to_move = agent.pref_velocity != DVec2::ZERO && !arrived[i];
+            let progress = (agent.position - old_position).dot(agent.pref_velocity.normalize_or_zero());
+            if wants_to_move && progress < agent.pref_velocity.length() * STUCK_PROGRESS {
+                agent.stuck_ticks += 1;
+            } else {
+                agent.stuck_ticks = 0;
+            }
+        }
+
+        // Arrival callbacks: report (once) every agent that stopped at its ordered destination
+        self.arrival_events.clear();
+        for (agent, &arrived) in self.rvo.agents.iter().zip(&arrived) {
+            if !arrived {
+                continue;
+            }
+            if let Some(callback) = self.arrival_callbacks.get(&agent.id) {
+                if agent.position.distance(callback.destination) <= ARRIVAL_EVENT_RADIUS {
+                    self.arrival_events.extend_from_slice(&[agent.id, callback.callback_id]);
+                    self.arrival_callbacks.remove(&agent.id);
+                }
+            }
+        }
+
+        // 5. Record where each group walked (cross-traffic avoidance)
+        if self.cross_traffic_weight > 0.0 {
+            self.update_traffic();
         }
 
-        // 5. Populate Export Buffer
+        // 6. Populate Export Buffer (keeping last tick's for interpolation)
+        std::mem::swap(&mut self.prev_export_buffer, &mut self.export_buffer);
         self.rebuild_export_buffer();
+
+        // 7. Spend this tick's pathfinding budget on queued requests
+        self.process_path_queue();
     }
 
     // --- SNAPSHOTS (PHASE 3) ---
@@ -136,84 +1022,2672 @@ impl Simulation {
     /// Serializes the entire simulation state into a JS Object.
     /// This uses `serde-wasm-bindgen` to convert Rust structs -> JS Objects.
     pub fn get_snapshot(&self) -> JsValue {
-        let snap = SimSnapshot {
-            tick_count: self.tick_count,
-            rvo: self.rvo.clone(),             // Requires #[derive(Clone)] on RvoManager
-            flow_field: self.flow_field.clone(), // Requires #[derive(Clone)] on FlowField
-            nav_mesh: self.nav_mesh.clone(),     // Requires #[derive(Clone)] on NavMesh
-        };
-        serde_wasm_bindgen::to_value(&snap).unwrap()
+        serde_wasm_bindgen::to_value(&self.make_snapshot()).unwrap()
     }
 
     /// Restores the simulation state from a JS Object.
     pub fn load_snapshot(&mut self, val: JsValue) {
         let snap: SimSnapshot = serde_wasm_bindgen::from_value(val).unwrap();
-        
-        self.tick_count = snap.tick_count;
-        self.rvo = snap.rvo;
-        self.flow_field = snap.flow_field;
-        self.nav_mesh = snap.nav_mesh;
+        self.apply_snapshot(snap);
+    }
 
-        // CRITICAL: Rebuild the export buffer immediately.
-        // If we don't do this, the JS renderer will read an empty buffer 
-        // for one frame, causing all units to flicker/disappear.
-        self.rebuild_export_buffer();
+    /// Serializes the full state to bytes (for storage or the network) as JSON.
+    /// Finite floats are written in shortest round-trip form and parsed back exactly, so
+    /// they survive `load_snapshot_bytes` bit-for-bit and lockstep peers don't drift after a
+    /// save/restore. JSON can't represent NaN or infinity, so the setters ignore non-finite
+    /// values and the state stays finite.
+    pub fn get_snapshot_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.make_snapshot()).unwrap()
     }
 
-    // --- ID REMAPPING (PHASE 3 FIX) ---
+    /// Restores the state saved by `get_snapshot_bytes`.
+    pub fn load_snapshot_bytes(&mut self, data: &[u8]) -> Result<(), String> {
+        let snap: SimSnapshot = serde_json::from_slice(data).map_err(|e| format!("Invalid snapshot data: {}", e))?;
+        self.apply_snapshot(snap);
+        Ok(())
+    }
 
-    /// Updates Agent IDs to match a new set of IDs provided by JS.
-    /// This is required after loading a snapshot, as bitECS will assign new internal IDs.
-    pub fn remap_ids(&mut self, old_ids: &[u32], new_ids: &[u32]) {
-        if old_ids.len() != new_ids.len() {
-            // In production, you might want to log an error or return a Result
-            return;
+    /// FNV-1a hash of the full state (same data as `get_snapshot_bytes`, minus rewind history,
+    /// with positions quantized like the export buffer).
+    /// Peers, or a replay and its original run, are in sync iff their checksums match.
+    pub fn get_checksum(&self) -> u64 {
+        let mut snap = self.make_snapshot_without_history();
+        for agent in &mut snap.rvo.agents {
+            agent.position = DVec2::new(self.quantize(agent.position.x), self.quantize(agent.position.y));
         }
+        let bytes = serde_json::to_vec(&snap).unwrap();
+        bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+    }
 
-        // Build a lookup map: Old ID -> New ID
-        let mut map = HashMap::new();
-        for (i, &old_id) in old_ids.iter().enumerate() {
-            map.insert(old_id, new_ids[i]);
-        }
+    /// Starts recording a replay: the current state plus every tick's inputs from now on.
+    /// Restarts from scratch if a recording was already running.
+    pub fn start_recording(&mut self) {
+        let mut start = self.make_snapshot_without_history();
+        start.hpa = self.hpa.clone();
+        self.recording = Some(ReplayLog { start, inputs: Vec::new() });
+    }
 
-        // Apply to all agents
-        for agent in &mut self.rvo.agents {
-            if let Some(&new_id) = map.get(&agent.id) {
-                agent.id = new_id;
-            }
+    /// Stops recording and returns the replay blob (JSON) for `replay`.
+    /// Empty if nothing was being recorded.
+    pub fn stop_recording(&mut self) -> String {
+        match self.recording.take() {
+            Some(log) => serde_json::to_string(&log).unwrap(),
+            None => String::new(),
         }
-
-        // Rebuild buffer so the very next render call uses the correct new IDs
-        self.rebuild_export_buffer();
     }
 
-    // --- ZERO-COPY MEMORY INTEROP ---
+    /// Restores the state a replay started from and re-runs all of its ticks. The run is
+    /// deterministic, so it ends with the same `get_checksum` as the recorded session.
+    /// The replay carries every setting ticks depend on, including the HPA graph (or its absence).
+    /// Only inputs are logged: `tick` takes no `dt` (every tick is one fixed step), so there is
+    /// no frame timing to record. A recording in progress is discarded, since the replay
+    /// replaces the state it started from and would log its ticks as if they were new input.
+    pub fn replay(&mut self, blob: &str) -> Result<(), String> {
+        let mut log: ReplayLog = serde_json::from_str(blob).map_err(|e| format!("Invalid replay data: {}", e))?;
+        self.recording = None;
+        let hpa = log.start.hpa.take();
+        self.apply_snapshot(log.start);
+        self.hpa = hpa;
+        for input_json in log.inputs {
+            self.tick(input_json);
+        }
+        Ok(())
+    }
 
-    /// Returns a pointer to the start of the Float64Array in Wasm memory.
-    pub fn get_state_ptr(&self) -> *const f64 {
-        self.export_buffer.as_ptr()
+    /// Number of ticks to re-simulate after rolling back to a snapshot taken at `snapshot_tick`.
+    /// Returns `None` if the snapshot is ahead of the current tick, which means the peers desynced.
+    pub fn ticks_since(&self, snapshot_tick: u64) -> Option<u64> {
+        self.tick_count.checked_sub(snapshot_tick)
     }
 
-    /// Returns the length (element count) of the buffer.
-    pub fn get_state_len(&self) -> usize {
-        self.export_buffer.len()
+    /// Ids of agents standing on a wall or outside the map (e.g. after loading a snapshot
+    /// saved before the map changed), so the caller can relocate them.
+    pub fn validate_agents(&self) -> Vec<u32> {
+        self.rvo.agents.iter()
+            .filter(|a| !self.flow_field.is_walkable_at(a.position.x, a.position.y))
+            .map(|a| a.id)
+            .collect()
     }
 
-    // --- INTERNAL HELPERS ---
+    /// Moves every agent reported by `validate_agents` to the center of the nearest walkable
+    /// cell and stops it. Returns the ids that were moved (none if the map has no walkable cell).
+    pub fn repair_agents(&mut self) -> Vec<u32> {
+        let mut repaired = Vec::new();
+        for agent in self.rvo.agents.iter_mut() {
+            if self.flow_field.is_walkable_at(agent.position.x, agent.position.y) {
+                continue;
+            }
+            if let Some(pos) = self.flow_field.nearest_walkable(agent.position.x, agent.position.y) {
+                agent.position = pos;
+                agent.velocity = DVec2::ZERO;
+                repaired.push(agent.id);
+            }
+        }
+        self.rebuild_export_buffer();
+        repaired
+    }
 
-    fn rebuild_export_buffer(&mut self) {
-        self.export_buffer.clear();
-        
-        // Ensure capacity to prevent frequent reallocations
-        // 5 floats per agent: [id, x, y, vx, vy]
-        self.export_buffer.reserve(self.rvo.agents.len() * 5);
+    /// Keeps the state from before each of the last `length` ticks, so `rewind` can go back
+    /// up to that many ticks. 0 (the default) disables history.
+    pub fn set_history_length(&mut self, length: usize) {
+        self.history_length = length;
+        while self.history.len() > length {
+            self.history.pop_front();
+        }
+    }
+
+    /// Restores the state from `ticks` ticks ago. Returns false (and changes nothing)
+    /// if that is further back than the recorded history.
+    pub fn rewind(&mut self, ticks: usize) -> bool {
+        if ticks == 0 || ticks > self.history.len() {
+            return false;
+        }
+        self.history.truncate(self.history.len() - ticks + 1);
+        let snap = self.history.pop_back().unwrap();
+        let history = std::mem::take(&mut self.history);
+        self.apply_snapshot(snap);
+        self.history = history;
+        true
+    }
+
+    /// If true, `get_snapshot`/`get_snapshot_bytes` include the rewind history, so a
+    /// restored simulation can still rewind. Off by default to keep snapshots small.
+    pub fn set_snapshot_history(&mut self, include: bool) {
+        self.snapshot_history = include;
+    }
+
+    fn make_snapshot(&self) -> SimSnapshot {
+        let mut snap = self.make_snapshot_without_history();
+        snap.hpa = self.hpa.clone();
+        if self.snapshot_history {
+            snap.history = self.history.iter().cloned().collect();
+        }
+        snap
+    }
+
+    fn make_snapshot_without_history(&self) -> SimSnapshot {
+        SimSnapshot {
+            tick_count: self.tick_count,
+            rvo: self.rvo.clone(),             // Requires #[derive(Clone)] on RvoManager
+            flow_field: self.flow_field.clone(), // Requires #[derive(Clone)] on FlowField
+            nav_mesh: self.nav_mesh.clone(),     // Requires #[derive(Clone)] on NavMesh
+            group_fields: self.group_fields.clone(),
+            terrain_fields: self.terrain_fields.clone(),
+            group_targets: self.group_targets.clone(),
+            traffic: self.traffic.clone(),
+            paths: self.paths.clone(),
+            formations: self.formations.clone(),
+            arrival_callbacks: self.arrival_callbacks.clone(),
+            arrival_sync: self.arrival_sync.clone(),
+            bilinear_flow: self.bilinear_flow,
+            cross_traffic_weight: self.cross_traffic_weight,
+            export_quantum: self.export_quantum,
+            max_displacement: self.max_displacement,
+            next_group: self.next_group,
+            path_queue: self.path_queue.iter().cloned().collect(),
+            path_results: self.path_results.clone(),
+            next_path_request: self.next_path_request,
+            path_budget: self.path_budget,
+            hpa: None,
+            history: Vec::new(),
+        }
+    }
+
+    fn apply_snapshot(&mut self, snap: SimSnapshot) {
+        self.tick_count = snap.tick_count;
+        self.rvo = snap.rvo;
+        self.flow_field = snap.flow_field;
+        self.nav_mesh = snap.nav_mesh;
+        self.group_fields = snap.group_fields;
+        self.terrain_fields = snap.terrain_fields;
+        self.group_targets = snap.group_targets;
+        self.traffic = snap.traffic;
+        self.paths = snap.paths;
+        self.formations = snap.formations;
+        self.arrival_callbacks = snap.arrival_callbacks;
+        self.arrival_sync = snap.arrival_sync;
+        self.bilinear_flow = snap.bilinear_flow;
+        self.cross_traffic_weight = snap.cross_traffic_weight;
+        self.export_quantum = snap.export_quantum;
+        self.max_displacement = snap.max_displacement;
+        self.next_group = snap.next_group;
+        self.path_queue = snap.path_queue.into();
+        self.path_results = snap.path_results;
+        self.next_path_request = snap.next_path_request;
+        self.path_budget = snap.path_budget;
+        if snap.hpa.is_some() {
+            self.hpa = snap.hpa;
+        }
+        // History from another timeline is useless: replace it (with nothing, if the snapshot has none)
+        self.history = snap.history.into();
+
+        // CRITICAL: Rebuild the export buffer immediately.
+        // If we don't do this, the JS renderer will read an empty buffer 
+        // for one frame, causing all units to flicker/disappear.
+        self.rebuild_export_buffer();
+    }
+
+    /// Serializes only the agents (positions, velocities, params) into a JS Object.
+    /// Much lighter than `get_snapshot` since the map and flow field are left out.
+    pub fn get_agents_snapshot(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.rvo.agents).unwrap()
+    }
+
+    /// Restores the agents saved by `get_agents_snapshot`.
+    /// The map, flow field and tick count are left untouched.
+    pub fn load_agents_snapshot(&mut self, val: JsValue) {
+        let agents: Vec<Agent> = serde_wasm_bindgen::from_value(val).unwrap();
+        self.restore_agents(agents);
+    }
+
+    fn restore_agents(&mut self, agents: Vec<Agent>) {
+        self.rvo.agents = agents;
+
+        // Same as load_snapshot: keep the renderer in sync this frame.
+        self.rebuild_export_buffer();
+    }
+
+    // --- HPA PATHFINDING ---
+
+    /// Installs a pre-built HPA graph, skipping the (slow) runtime `build`.
+    /// `data` must be `serde_json::to_vec(&HPAGrid)` output (e.g. baked offline by tooling),
+    /// not bincode: snapshots already use serde_json, and this avoids a second format and
+    /// dependency. The graph's grid must have the same dimensions as the flow field.
+    pub fn load_hpa_bytes(&mut self, data: &[u8]) -> Result<(), String> {
+        let hpa: HPAGrid = serde_json::from_slice(data).map_err(|e| format!("Invalid HPA data: {}", e))?;
+
+        if hpa.grid.width as usize != self.flow_field.width || hpa.grid.height as usize != self.flow_field.height {
+            return Err(format!(
+                "HPA grid is {}x{} but the flow field is {}x{}",
+                hpa.grid.width, hpa.grid.height, self.flow_field.width, self.flow_field.height
+            ));
+        }
+
+        self.hpa = Some(hpa);
+        Ok(())
+    }
+
+    /// Portal distribution of the loaded HPA graph (`{ clusters, min_portals, max_portals,
+    /// mean_portals, total_portals, total_edges }`), for tuning `cluster_size`.
+    /// Returns undefined if no graph is loaded.
+    pub fn get_portal_stats(&self) -> JsValue {
+        match &self.hpa {
+            Some(hpa) => serde_wasm_bindgen::to_value(&hpa.portal_stats()).unwrap(),
+            None => JsValue::UNDEFINED,
+        }
+    }
+
+    /// Occupied cells of the avoidance spatial hash (`[{ min_x, min_y, max_x, max_y, count }]`),
+    /// bucketed from the current agent positions. For diagnosing neighbor-query misses.
+    pub fn get_spatial_debug(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.rvo.build_spatial_hash().buckets()).unwrap()
+    }
+
+    /// Finds a grid path using the loaded HPA graph.
+    /// Returns a flat list `[x0, y0, x1, y1, ...]`, or an empty list if there is
+    /// no path or no HPA graph has been loaded.
+    pub fn find_path_hpa(&self, start_x: i32, start_y: i32, end_x: i32, end_y: i32) -> Vec<i32> {
+        let Some(hpa) = &self.hpa else {
+            return Vec::new();
+        };
+
+        let (path, expansions) = hpa.find_path_with_stats(IVec2::new(start_x, start_y), IVec2::new(end_x, end_y));
+        self.last_path_expansions.set(expansions);
+
+        path.map(|path| path.iter().flat_map(|p| [p.x, p.y]).collect())
+            .unwrap_or_default()
+    }
+
+    /// True walked length of the HPA grid path (1 per cardinal step, sqrt(2) per diagonal),
+    /// for comparing routes. Returns undefined if there is no path or no HPA graph is loaded.
+    pub fn find_path_length_hpa(&self, start_x: i32, start_y: i32, end_x: i32, end_y: i32) -> Option<f64> {
+        let hpa = self.hpa.as_ref()?;
+        hpa.find_path_with_length(IVec2::new(start_x, start_y), IVec2::new(end_x, end_y))
+            .map(|(length, _)| length)
+    }
+
+    /// Debug audit of HPA path quality: `[hpa_cost, optimal_cost]` (10 per cardinal step) for
+    /// the same query, the optimum from a full-grid A*. Too slow for runtime use on big maps.
+    /// Returns undefined if there is no path or no HPA graph has been loaded.
+    pub fn debug_path_quality_hpa(&self, start_x: i32, start_y: i32, end_x: i32, end_y: i32) -> Option<Vec<u32>> {
+        let (hpa_cost, optimal_cost) = self.hpa.as_ref()?.path_quality(IVec2::new(start_x, start_y), IVec2::new(end_x, end_y))?;
+        Some(vec![hpa_cost, optimal_cost])
+    }
+
+    /// Ids of the portals the HPA abstract search traverses, in order, without
+    /// reconstructing the grid path. Empty if both points share a cluster.
+    /// Returns undefined if there is no path or no HPA graph has been loaded.
+    pub fn find_portal_path_hpa(&self, start_x: i32, start_y: i32, end_x: i32, end_y: i32) -> Option<Vec<u32>> {
+        let portals = self.hpa.as_ref()?.find_portal_path(IVec2::new(start_x, start_y), IVec2::new(end_x, end_y))?;
+        Some(portals.iter().map(|p| p.0 as u32).collect())
+    }
+
+    /// Portals reachable from HPA portal `portal` for at most `max_cost` (10 per cell), as a
+    /// flat list `[id0, cost0, id1, cost1, ...]` sorted by id, including `portal` itself at 0.
+    /// Empty for an unknown portal or if no HPA graph has been loaded.
+    pub fn portals_within_hpa(&self, portal: u32, max_cost: u32) -> Vec<u32> {
+        let Some(hpa) = &self.hpa else {
+            return Vec::new();
+        };
+
+        hpa.portals_within(PortalId(portal as usize), max_cost)
+            .iter()
+            .flat_map(|&(id, cost)| [id.0 as u32, cost])
+            .collect()
+    }
+
+    /// Finds a smoothed path on the navmesh.
+    /// Returns a flat list `[x0, y0, x1, y1, ...]`, or an empty list if either point is off the mesh.
+    pub fn find_path_navmesh(&self, start_x: f64, start_y: f64, end_x: f64, end_y: f64) -> Vec<f64> {
+        let (path, expansions) = self.nav_mesh.find_path_with_stats(DVec2::new(start_x, start_y), DVec2::new(end_x, end_y));
+        self.last_path_expansions.set(expansions);
+
+        path.iter().flat_map(|p| [p.x, p.y]).collect()
+    }
+
+    /// Whether a disc at (x, y) would overlap any agent other than `ignore_id`.
+    /// Lets AI check that a destination isn't crowded before committing to a move.
+    /// Only agents are considered, not walls.
+    pub fn is_position_clear(&self, x: f64, y: f64, radius: f64, ignore_id: u32) -> bool {
+        self.rvo.is_position_clear(DVec2::new(x, y), radius, ignore_id)
+    }
+
+    /// Up to `count` spawn points around (x, y) as flat `[x, y, ...]`, for JS to `add_agent`.
+    /// Candidates spiral outward ring by ring (`spacing` apart) and are kept if they lie on a
+    /// walkable cell in straight view of the center (not behind a wall), a disc of `radius`
+    /// there overlaps no agent, and they are at least `spacing` from every point already
+    /// picked. May return fewer points if the area is too cramped, or once
+    /// `SPAWN_RING_MAX_CANDIDATES` candidates have been tried.
+    pub fn spawn_ring(&self, center_x: f64, center_y: f64, count: usize, radius: f64, spacing: f64) -> Vec<f64> {
+        let center = DVec2::new(center_x, center_y);
+        let mut points: Vec<DVec2> = Vec::with_capacity(count.min(SPAWN_RING_MAX_CANDIDATES));
+        if spacing <= 0.0 || !center.is_finite() {
+            return Vec::new();
+        }
+
+        // Walkable all along the segment from the center, sampled every quarter cell
+        let in_view = |p: DVec2| {
+            let steps = (center.distance(p) * 4.0).ceil() as usize;
+            (0..=steps).all(|i| {
+                let q = center.lerp(p, i as f64 / steps.max(1) as f64);
+                self.flow_field.is_walkable_at(q.x, q.y)
+            })
+        };
+
+        // Past the map's diagonal every candidate is off the map
+        let max_ring = ((self.flow_field.width.pow(2) + self.flow_field.height.pow(2)) as f64).sqrt() / spacing;
+        let mut ring = 0;
+        let mut candidates = 0;
+        while points.len() < count && ring as f64 <= max_ring + 1.0 && candidates < SPAWN_RING_MAX_CANDIDATES {
+            let ring_radius = ring as f64 * spacing;
+            // Enough slots that neighbors on the ring are about `spacing` apart
+            let slots = if ring == 0 { 1 } else { (std::f64::consts::TAU * ring_radius / spacing).floor() as usize };
+            for slot in 0..slots {
+                if candidates == SPAWN_RING_MAX_CANDIDATES {
+                    break;
+                }
+                candidates += 1;
+                let angle = std::f64::consts::TAU * slot as f64 / slots as f64;
+                let p = center + DVec2::from_angle(angle) * ring_radius;
+                if in_view(p)
+                    && self.rvo.is_position_clear(p, radius, u32::MAX)
+                    && points.iter().all(|q| q.distance(p) >= spacing)
+                {
+                    points.push(p);
+                    if points.len() == count {
+                        break;
+                    }
+                }
+            }
+            ring += 1;
+        }
+        points.iter().flat_map(|p| [p.x, p.y]).collect()
+    }
+
+    /// Snaps (x, y) onto the navmesh, e.g. for a click just off the walkable area:
+    /// `[tri_id, x, y]` of the nearest mesh point, or an empty array if there is no mesh.
+    pub fn closest_point_navmesh(&self, x: f64, y: f64) -> Vec<f64> {
+        match self.nav_mesh.closest_point(DVec2::new(x, y)) {
+            Some((tri_id, p)) => vec![tri_id as f64, p.x, p.y],
+            None => Vec::new(),
+        }
+    }
+
+    /// Ids of the agents standing inside navmesh triangle `tri_id` (e.g. "who is in this room").
+    /// Empty for an invalid triangle id. Agents on a shared edge count for both triangles.
+    pub fn agents_in_triangle(&self, tri_id: usize) -> Vec<u32> {
+        // No agent spatial index yet: a straight scan, the point test is cheap anyway
+        self.rvo.agents
+            .iter()
+            .filter(|agent| self.nav_mesh.triangle_contains(tri_id, agent.position))
+            .map(|agent| agent.id)
+            .collect()
+    }
+
+    /// Whether (x, y) lies inside a navmesh region made of several triangles (a "room"),
+    /// e.g. for room-based triggers. Unknown triangle ids are ignored.
+    pub fn point_in_region(&self, x: f64, y: f64, tris: &[usize]) -> bool {
+        self.nav_mesh.point_in_region(DVec2::new(x, y), tris)
+    }
+
+    /// The three triangles adjacent to navmesh triangle `tri_id`, one per edge (-1 where the
+    /// edge is a border). Empty for an invalid triangle id.
+    pub fn triangle_neighbors(&self, tri_id: usize) -> Vec<i32> {
+        match self.nav_mesh.triangles.get(tri_id) {
+            Some(tri) => tri.neighbors.iter().map(|n| n.map_or(-1, |idx| idx as i32)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Paths from an agent's current position to several targets (flat `[tx, ty, ...]`),
+    /// returned as an array of `{ cost, path }` in target order. Uses the HPA graph if one
+    /// is loaded (sharing the abstract search between targets in the same cluster),
+    /// otherwise the navmesh. Returns an empty array if the agent doesn't exist.
+    pub fn paths_from(&self, id: u32, targets: &[f64]) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.compute_paths_from(id, targets)).unwrap()
+    }
+
+    /// Route a flow-following agent would take from its position (e.g. a HUD line for the
+    /// selected unit), as a flat `[x, y, ...]` polyline: the agent's position, then the cell
+    /// centers where the route turns, ending at the target cell. Stops early after
+    /// `max_steps` cells or at a cell without a vector. Empty if the agent doesn't exist or
+    /// its field has no target yet.
+    pub fn trace_flow_path(&self, id: u32, max_steps: usize) -> Vec<f64> {
+        let Some(agent) = self.rvo.agents.iter().find(|a| a.id == id) else {
+            return Vec::new();
+        };
+        let Some(field) = agent_field(&self.flow_field, &self.group_fields, &self.terrain_fields, agent) else {
+            return Vec::new();
+        };
+        if !field.has_target() {
+            return Vec::new();
+        }
+
+        let mut line = vec![agent.position.x, agent.position.y];
+        let mut cell = agent.position.round();
+        let mut heading = DVec2::ZERO;
+        for _ in 0..max_steps {
+            if field.is_target(cell.x, cell.y) {
+                break;
+            }
+            let dir = field.get_direction_split(cell.x, cell.y, agent.id);
+            if dir == DVec2::ZERO {
+                break;
+            }
+            // Only corners are kept: straight runs collapse into one segment
+            if dir != heading {
+                line.extend([cell.x, cell.y]);
+                heading = dir;
+            }
+            cell += dir;
+        }
+        line.extend([cell.x, cell.y]);
+        line
+    }
 
+    /// Remaining flow cost to a group's objective (group 0 = the global field) at each of
+    /// the flat `[x, y, ...]` points, in order. `f64::MAX` for walls, unreachable and
+    /// out-of-bounds points; all of them if the group was never ordered.
+    pub fn get_flow_costs(&self, group: u32, points: &[f64]) -> Vec<f64> {
+        let points: Vec<(f64, f64)> = points.chunks_exact(2).map(|p| (p[0], p[1])).collect();
+        let field = match group {
+            0 => Some(&self.flow_field),
+            group => self.group_fields.get(&group),
+        };
+        match field {
+            Some(field) => field.cost_at_cells(&points),
+            None => vec![f64::MAX; points.len()],
+        }
+    }
+
+    /// Chokepoint overlay for a group's field (group 0 = the global field): per cell, row-major,
+    /// how many cells route through it (see `FlowField::flow_accumulation`). Empty if the
+    /// group was never ordered.
+    pub fn get_flow_accumulation(&self, group: u32) -> Vec<u32> {
+        let field = match group {
+            0 => Some(&self.flow_field),
+            group => self.group_fields.get(&group),
+        };
+        field.map(FlowField::flow_accumulation).unwrap_or_default()
+    }
+
+    /// Each agent's current goal as flat `[id, goal_x, goal_y, has_goal, ...]`, in export
+    /// buffer order (e.g. for move-order markers). The goal is the formation slot, the end
+    /// of the agent's path, or its group's FLOW destination, whichever it is following.
+    /// Agents without one (fleeing, never ordered, props) get `0, 0, 0`.
+    pub fn get_goals_flat(&self) -> Vec<f64> {
+        let positions: HashMap<u32, DVec2> = self.rvo.agents.iter().map(|a| (a.id, a.position)).collect();
+        let mut slots = HashMap::new();
+        for (leader, formation) in &self.formations {
+            if let Some(&leader_pos) = positions.get(leader) {
+                for &(id, offset) in &formation.members {
+                    slots.insert(id, leader_pos + formation.heading.rotate(offset));
+                }
+            }
+        }
+
+        let mut goals = Vec::with_capacity(self.rvo.agents.len() * 4);
         for agent in &self.rvo.agents {
-            self.export_buffer.push(agent.id as f64);
-            self.export_buffer.push(agent.position.x);
-            self.export_buffer.push(agent.position.y);
-            self.export_buffer.push(agent.velocity.x);
-            self.export_buffer.push(agent.velocity.y);
+            let goal = if agent.separation_only {
+                None
+            } else if let Some(&slot) = slots.get(&agent.id) {
+                Some(slot)
+            } else if let Some(path) = self.paths.get(&agent.id) {
+                path.waypoints.last().copied()
+            } else {
+                self.group_targets.get(&agent.group).copied()
+            };
+            match goal {
+                Some(goal) => goals.extend_from_slice(&[agent.id as f64, goal.x, goal.y, 1.0]),
+                None => goals.extend_from_slice(&[agent.id as f64, 0.0, 0.0, 0.0]),
+            }
+        }
+        goals
+    }
+
+    /// Debug guard against tunneling: agents may move at most `max` per tick; faster moves
+    /// (from bad inputs, huge forces, NaNs) are clamped and reported by
+    /// `get_displacement_events`. 0 disables it; NaN/infinite values are ignored.
+    pub fn set_max_displacement(&mut self, max: f64) {
+        if max.is_finite() {
+            self.max_displacement = max.max(0.0);
+        }
+    }
+
+    /// Sets the team an agent fights for (agents on different teams are enemies to ATTACK orders).
+    pub fn set_agent_team(&mut self, id: u32, team: u32) {
+        if let Some(agent) = self.rvo.agents.iter_mut().find(|a| a.id == id) {
+            agent.team = team;
+        }
+    }
+
+    /// Attack-moving agents that held for an enemy during the last tick, as flat
+    /// `[agent_id, enemy_id, ...]` (the nearest enemy in range). Reported every tick
+    /// the agent stays halted, so JS can start or keep up the fight.
+    pub fn get_attack_events(&self) -> Vec<u32> {
+        self.attack_events.clone()
+    }
+
+    /// Ids of agents the displacement guard clamped during the last tick.
+    pub fn get_displacement_events(&self) -> Vec<u32> {
+        self.displacement_events.clone()
+    }
+
+    /// Ids of agents that idled during the last tick because their flow field has no
+    /// reachable target (group never ordered, or cut off from the target).
+    /// Agents following an explicit path are not checked, and nobody is reported before the
+    /// first FLOW/FLEE order. Useful to catch mis-assigned units.
+    pub fn get_no_target_agents(&self) -> Vec<u32> {
+        self.no_target_agents.clone()
+    }
+
+    /// Arrivals detected during the last tick, as flat `[agent_id, callback_id, ...]`, for
+    /// MOVE orders that carried a `callback_id`. Each order reports at most once.
+    pub fn get_arrival_events(&self) -> Vec<u32> {
+        self.arrival_events.clone()
+    }
+
+    /// Number of A* nodes expanded by the most recent `find_path_hpa` / `find_path_navmesh` call.
+    /// Use this to judge how expensive queries are on a given map.
+    pub fn get_last_path_expansions(&self) -> usize {
+        self.last_path_expansions.get()
+    }
+
+    /// Number of full HPA abstract searches the most recent `paths_from` call ran. Targets
+    /// sharing start and end clusters reuse one search, so this is usually well below the
+    /// target count. 0 when no HPA graph is loaded.
+    pub fn get_last_abstract_searches(&self) -> usize {
+        self.last_abstract_searches.get()
+    }
+
+    /// Queues a navmesh path query to be solved during upcoming ticks, within the budget set by
+    /// `set_path_budget`. Returns a request id for `take_path_result`. Requests with
+    /// NaN/infinite coordinates aren't queued; their result is empty straight away.
+    pub fn request_path_navmesh(&mut self, start_x: f64, start_y: f64, end_x: f64, end_y: f64) -> u32 {
+        let id = self.next_path_request;
+        self.next_path_request = self.next_path_request.wrapping_add(1);
+        if ![start_x, start_y, end_x, end_y].iter().all(|v| v.is_finite()) {
+            self.path_results.insert(id, Vec::new());
+            return id;
+        }
+        self.path_queue.push_back(PathRequest {
+            id,
+            start: DVec2::new(start_x, start_y),
+            end: DVec2::new(end_x, end_y),
+        });
+        id
+    }
+
+    /// Max A* expansions spent on queued path requests per tick (default `DEFAULT_PATH_BUDGET`,
+    /// 0 = pause the queue). Requests are solved in order; a search that doesn't fit in what's
+    /// left waits for the next tick, and one that doesn't fit in a whole tick's budget is
+    /// dropped with an empty result. No tick ever spends more than the budget.
+    pub fn set_path_budget(&mut self, expansions: usize) {
+        self.path_budget = expansions;
+    }
+
+    /// Returns (and forgets) the result of a queued request: a flat `[x0, y0, x1, y1, ...]` list,
+    /// empty if there is no path (or the search is too big for the budget). Returns undefined
+    /// while the request is still pending.
+    pub fn take_path_result(&mut self, request_id: u32) -> Option<Vec<f64>> {
+        self.path_results.remove(&request_id)
+    }
+
+    /// Number of queued path requests not yet solved.
+    pub fn get_pending_path_requests(&self) -> usize {
+        self.path_queue.len()
+    }
+
+    /// A* expansions spent on queued path requests during the last tick.
+    pub fn get_path_budget_spent(&self) -> usize {
+        self.path_budget_spent
+    }
+
+    // --- ID REMAPPING (PHASE 3 FIX) ---
+
+    /// Updates Agent IDs to match a new set of IDs provided by JS.
+    /// This is required after loading a snapshot, as bitECS will assign new internal IDs.
+    pub fn remap_ids(&mut self, old_ids: &[u32], new_ids: &[u32]) {
+        if old_ids.len() != new_ids.len() {
+            // In production, you might want to log an error or return a Result
+            return;
+        }
+
+        // Build a lookup map: Old ID -> New ID
+        let mut map = HashMap::new();
+        for (i, &old_id) in old_ids.iter().enumerate() {
+            map.insert(old_id, new_ids[i]);
+        }
+
+        // Apply to all agents
+        for agent in &mut self.rvo.agents {
+            if let Some(&new_id) = map.get(&agent.id) {
+                agent.id = new_id;
+            }
+        }
+
+        // Paths are keyed by id: move them along with their agents
+        let paths = std::mem::take(&mut self.paths);
+        self.paths = paths
+            .into_iter()
+            .map(|(id, path)| (map.get(&id).copied().unwrap_or(id), path))
+            .collect();
+
+        // Same for formations (keyed by leader, listing member ids)
+        let formations = std::mem::take(&mut self.formations);
+        self.formations = formations
+            .into_iter()
+            .map(|(leader, mut formation)| {
+                for (id, _) in &mut formation.members {
+                    *id = map.get(id).copied().unwrap_or(*id);
+                }
+                (map.get(&leader).copied().unwrap_or(leader), formation)
+            })
+            .collect();
+
+        let callbacks = std::mem::take(&mut self.arrival_callbacks);
+        self.arrival_callbacks = callbacks
+            .into_iter()
+            .map(|(id, callback)| (map.get(&id).copied().unwrap_or(id), callback))
+            .collect();
+
+        // Previous-tick buffer too, so render interpolation pairs up the same agents
+        for id in self.prev_export_buffer.iter_mut().step_by(5) {
+            if let Some(&new_id) = map.get(&(*id as u32)) {
+                *id = new_id as f64;
+            }
+        }
+
+        // Rebuild buffer so the very next render call uses the correct new IDs
+        self.rebuild_export_buffer();
+    }
+
+    // --- ZERO-COPY MEMORY INTEROP ---
+
+    /// Rounds exported positions to multiples of `step` (e.g. 1/1024), so peers syncing
+    /// at lower precision see identical values despite last-bit float noise.
+    /// Only the export buffer and `get_checksum` are affected; the simulation keeps full
+    /// precision. 0 (or NaN) disables it; infinite steps are ignored.
+    pub fn set_export_quantization(&mut self, step: f64) {
+        if step.is_infinite() {
+            return;
+        }
+        self.export_quantum = if step > 0.0 { step } else { 0.0 };
+        self.rebuild_export_buffer();
+    }
+
+    /// Rewrites the export buffer from the current agent state without ticking.
+    /// Call once after a batch of direct agent mutations so the renderer sees them.
+    /// `tick` (and snapshot loads) already refresh the buffer automatically.
+    /// Note: the buffer may reallocate, so re-read `get_state_ptr` afterwards.
+    pub fn refresh_buffer(&mut self) {
+        self.rebuild_export_buffer();
+    }
+
+    /// Pre-allocates room for `n` agents in total (capped at `max_agents`) before a known
+    /// batch add, e.g. a wave spawn. Until the count exceeds `n`, `add_agent` then never
+    /// reallocates the export buffer, so `get_state_ptr` stays valid across the adds.
+    /// Ticks still swap in the previous-tick buffer, so re-read the pointer after each tick.
+    /// A hint only: sizes that can't be allocated (e.g. a bogus count from JS) are ignored.
+    pub fn reserve_agents(&mut self, n: usize) {
+        let n = n.min(self.max_agents);
+        let floats = n.saturating_mul(5);
+        let _ = self.rvo.agents.try_reserve(n.saturating_sub(self.rvo.agents.len()));
+        let _ = self.export_buffer.try_reserve(floats.saturating_sub(self.export_buffer.len()));
+        let _ = self.prev_export_buffer.try_reserve(floats.saturating_sub(self.prev_export_buffer.len()));
+    }
+
+    /// Returns a pointer to the start of the Float64Array in Wasm memory.
+    pub fn get_state_ptr(&self) -> *const f64 {
+        self.export_buffer.as_ptr()
+    }
+
+    /// Returns the length (element count) of the buffer.
+    pub fn get_state_len(&self) -> usize {
+        self.export_buffer.len()
+    }
+
+    /// Pointer to the export buffer as it was after the previous tick, for interpolating
+    /// between the two. Ids are kept in step with `remap_ids`. Empty before the first tick.
+    pub fn get_prev_state_ptr(&self) -> *const f64 {
+        self.prev_export_buffer.as_ptr()
+    }
+
+    /// Length (element count) of the previous-tick buffer.
+    pub fn get_prev_state_len(&self) -> usize {
+        self.prev_export_buffer.len()
+    }
+
+    // --- INTERNAL HELPERS ---
+
+    /// Stamps the current tick as the agent's latest order (contested-spot priority).
+    fn mark_ordered(&mut self, id: u32) {
+        let tick = self.tick_count;
+        if let Some(agent) = self.rvo.agents.iter_mut().find(|a| a.id == id) {
+            agent.last_order_tick = tick;
+        }
+    }
+
+    /// Same as `mark_ordered`, for every agent of a group (FLOW/FLEE orders).
+    fn mark_group_ordered(&mut self, group: u32) {
+        let tick = self.tick_count;
+        for agent in self.rvo.agents.iter_mut().filter(|a| a.group == group) {
+            agent.last_order_tick = tick;
+        }
+    }
+
+    /// Zeroes an agent's velocity when it leaves a seek mode (FLOW, FLEE, FORMATION, paths)
+    /// for one that doesn't steer (STOP, direct), so the old motion doesn't make it lurch.
+    /// Switching between seek modes keeps the velocity, so motion stays continuous.
+    fn reset_velocity(&mut self, id: u32) {
+        if let Some(agent) = self.rvo.agents.iter_mut().find(|a| a.id == id) {
+            agent.velocity = DVec2::ZERO;
+            agent.pref_velocity = DVec2::ZERO;
+        }
+    }
+
+    /// STOP order: the agent halts and holds its current position (drifting back if shoved)
+    /// until it gets a path or its group gets a FLOW/FLEE order. It leaves any formation.
+    fn order_stop(&mut self, id: u32) {
+        let Some(position) = self.rvo.agents.iter().find(|a| a.id == id).map(|a| a.position) else {
+            return;
+        };
+        self.reset_velocity(id);
+        for formation in self.formations.values_mut() {
+            formation.members.retain(|&(member, _)| member != id);
+        }
+        self.paths.insert(id, AgentPath { waypoints: vec![position], next: 0, chase: None, replan_interval: 0, planned_at: 0, hold: true });
+    }
+
+    /// Drops the holding paths (STOP, FORMATION moves) of `group`'s members,
+    /// so a new group order moves them again.
+    fn release_holds(&mut self, group: u32) {
+        let members: HashSet<u32> = self.rvo.agents.iter().filter(|a| a.group == group).map(|a| a.id).collect();
+        self.paths.retain(|id, path| !(path.hold && members.contains(id)));
+    }
+
+    /// FLOW order: points `group`'s field (and its terrain variants) at the target.
+    /// A plain move, so it also cancels the members' attack-move.
+    /// A bad target (NaN, infinite, off the map) is ignored outright, leaving the group on its
+    /// previous order (holds, attack radius, fields and all). Returns false in that case.
+    fn order_group_flow(&mut self, group: u32, target_x: f64, target_y: f64) -> bool {
+        // Every group field shares the global field's dimensions, so one check covers them all
+        if self.flow_field.target_cell(target_x, target_y).is_err() {
+            return false;
+        }
+        self.mark_group_ordered(group);
+        self.release_holds(group);
+        self.set_attack_radius(group, 0.0);
+        let _ = self.order_field(group).generate_target(target_x, target_y);
+        self.group_targets.insert(group, DVec2::new(target_x, target_y));
+        self.order_terrain_fields(group, |field| {
+            let _ = field.generate_target(target_x, target_y);
+        });
+        true
+    }
+
+    /// ATTACK order: a FLOW order whose members halt for enemies within `radius`.
+    fn order_group_attack(&mut self, group: u32, target_x: f64, target_y: f64, radius: f64) {
+        if self.order_group_flow(group, target_x, target_y) {
+            self.set_attack_radius(group, radius.max(0.0));
+        }
+    }
+
+    fn set_attack_radius(&mut self, group: u32, radius: f64) {
+        for agent in self.rvo.agents.iter_mut().filter(|a| a.group == group) {
+            agent.attack_radius = radius;
+        }
+    }
+
+    /// FLEE order: inverts `group`'s field (and its terrain variants) around the threat.
+    fn order_group_flee(&mut self, group: u32, x: f64, y: f64, max_cost: f64) {
+        self.mark_group_ordered(group);
+        self.release_holds(group);
+        self.set_attack_radius(group, 0.0);
+        self.group_targets.remove(&group);
+        let field = self.order_field(group);
+        field.generate_flee_target(x, y, max_cost);
+        self.order_terrain_fields(group, |field| {
+            field.generate_flee_target(x, y, max_cost);
+        });
+    }
+
+    /// The flow field a FLOW/FLEE order for `group` should regenerate.
+    /// Group 0 is the global field. Other groups get a fresh copy of the global map
+    /// (walls, heights) so they always see the latest terrain.
+    fn order_field(&mut self, group: u32) -> &mut FlowField {
+        let soft_costs = self.cross_traffic_costs(group);
+
+        if group == 0 {
+            self.flow_field.set_soft_costs(soft_costs);
+            return &mut self.flow_field;
         }
+
+        let mut field = self.flow_field.clone();
+        field.set_soft_costs(soft_costs);
+        self.group_fields.insert(group, field);
+        self.group_fields.get_mut(&group).unwrap()
     }
-}
\ No newline at end of file
+
+    /// Regenerates the per-terrain-mask fields of `group` after a FLOW/FLEE order: one copy of
+    /// the group's field per distinct non-ground mask among its agents, with `regenerate`
+    /// re-applying the order under that mask.
+    fn order_terrain_fields(&mut self, group: u32, regenerate: impl Fn(&mut FlowField)) {
+        let masks: BTreeSet<u32> = self
+            .rvo
+            .agents
+            .iter()
+            .filter(|a| a.group == group && a.terrain_mask != GROUND_MASK)
+            .map(|a| a.terrain_mask)
+            .collect();
+        if masks.is_empty() {
+            self.terrain_fields.remove(&group);
+            return;
+        }
+
+        let base = match group {
+            0 => &self.flow_field,
+            group => &self.group_fields[&group],
+        };
+        let fields = masks
+            .into_iter()
+            .map(|mask| {
+                let mut field = base.clone();
+                field.terrain_mask = mask;
+                regenerate(&mut field);
+                (mask, field)
+            })
+            .collect();
+        self.terrain_fields.insert(group, fields);
+    }
+
+    /// One `PathResult` per target, in order. Empty if the agent doesn't exist.
+    fn compute_paths_from(&self, id: u32, targets: &[f64]) -> Vec<PathResult> {
+        let Some(agent) = self.rvo.agents.iter().find(|a| a.id == id) else {
+            return Vec::new();
+        };
+        let targets: Vec<DVec2> = targets.chunks_exact(2).map(|t| DVec2::new(t[0], t[1])).collect();
+
+        // Cost is the walked length of the path
+        let result = |points: Vec<DVec2>| {
+            if points.is_empty() {
+                return PathResult { cost: None, path: Vec::new() };
+            }
+            let cost = points.windows(2).map(|w| w[0].distance(w[1])).sum();
+            PathResult { cost: Some(cost), path: points.iter().flat_map(|p| [p.x, p.y]).collect() }
+        };
+
+        match &self.hpa {
+            Some(hpa) => {
+                let start = agent.position.round().as_ivec2();
+                let requests: Vec<(IVec2, IVec2)> = targets.iter().map(|t| (start, t.round().as_ivec2())).collect();
+
+                let batch = hpa.request_paths(&requests);
+                self.last_abstract_searches.set(batch.abstract_searches);
+                batch
+                    .paths
+                    .into_iter()
+                    .map(|path| result(path.unwrap_or_default().iter().map(|p| p.as_dvec2()).collect()))
+                    .collect()
+            }
+            None => {
+                self.last_abstract_searches.set(0);
+                targets
+                    .iter()
+                    .map(|&target| result(self.nav_mesh.find_path(agent.position, target)))
+                    .collect()
+            }
+        }
+    }
+
+    /// Nearest enemy within detection range of each attack-moving agent, by agent id.
+    /// Props (separation-only agents) are never enemies.
+    fn attack_move_engagements(&self) -> HashMap<u32, u32> {
+        let mut engaged = HashMap::new();
+        if self.rvo.agents.iter().all(|a| a.attack_radius <= 0.0) {
+            return engaged;
+        }
+
+        // Fresh hash: the avoidance one is from last tick's positions
+        let hash = self.rvo.build_spatial_hash();
+        for agent in self.rvo.agents.iter().filter(|a| a.attack_radius > 0.0 && !a.frozen) {
+            // An empty hash means it couldn't be built (zero radii): check everyone instead
+            let candidates = if hash.cells.is_empty() {
+                (0..self.rvo.agents.len()).collect()
+            } else {
+                hash.within(agent.position, agent.attack_radius)
+            };
+            let nearest = candidates
+                .into_iter()
+                .map(|i| &self.rvo.agents[i])
+                .filter(|other| other.team != agent.team && !other.separation_only)
+                .map(|other| (agent.position.distance_squared(other.position), other.id))
+                .filter(|&(dist_sq, _)| dist_sq <= agent.attack_radius * agent.attack_radius)
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+            if let Some((_, enemy)) = nearest {
+                engaged.insert(agent.id, enemy);
+            }
+        }
+        engaged
+    }
+
+    /// For each arrival-synced group, the longest expected time to target (remaining flow
+    /// cost / cruise speed) among its members that are following the group's field.
+    fn group_arrival_etas(&self, slots: &HashMap<u32, DVec2>) -> BTreeMap<u32, f64> {
+        let mut etas = BTreeMap::new();
+        for agent in &self.rvo.agents {
+            if !self.arrival_sync.contains(&agent.group) || agent.frozen || agent.cruise_speed() <= 0.0 {
+                continue;
+            }
+            if slots.contains_key(&agent.id) || self.paths.contains_key(&agent.id) {
+                continue;
+            }
+            let Some(field) = agent_field(&self.flow_field, &self.group_fields, &self.terrain_fields, agent) else {
+                continue;
+            };
+            if let Some(cost) = field.cost_to_target(agent.position.x, agent.position.y) {
+                let eta = etas.entry(agent.group).or_insert(0.0);
+                *eta = f64::max(*eta, cost / agent.cruise_speed());
+            }
+        }
+        etas
+    }
+
+    /// Updates formation headings from their leaders' movement and returns
+    /// each member's world slot position. Formations whose leader is gone are dropped.
+    fn update_formations(&mut self) -> HashMap<u32, DVec2> {
+        let agents: HashMap<u32, &Agent> = self.rvo.agents.iter().map(|a| (a.id, a)).collect();
+        let mut slots = HashMap::new();
+
+        self.formations.retain(|leader_id, formation| {
+            let Some(leader) = agents.get(leader_id) else { return false };
+
+            // Heading follows where the leader wants to go, not where it is pushed: a shove from
+            // a member (or anyone else) would otherwise rotate every slot, the members chase the
+            // new slots, shove the leader again and the formation spins. Members' catch-up moves
+            // are left out for the same reason. Kept as-is while the leader is (nearly) idle.
+            if leader.pref_velocity.length() > FORMATION_TURN_MIN_SPEED {
+                formation.heading = leader.pref_velocity.normalize();
+            }
+
+            for &(id, offset) in &formation.members {
+                slots.insert(id, leader.position + formation.heading.rotate(offset));
+            }
+            true
+        });
+
+        slots
+    }
+
+    fn update_chases(&mut self) {
+        let positions: HashMap<u32, DVec2> = self.rvo.agents.iter().map(|a| (a.id, a.position)).collect();
+
+        let mut lost = Vec::new();
+        let mut replans = Vec::new();
+        for (&id, path) in &self.paths {
+            let Some(target) = path.chase else { continue };
+            let (Some(&from), Some(&to)) = (positions.get(&id), positions.get(&target)) else {
+                lost.push(id);
+                continue;
+            };
+
+            let due = self.tick_count >= path.planned_at + path.replan_interval;
+            // No route (or not planned yet): retry right away instead of idling out the cooldown
+            let blocked = path.waypoints.is_empty();
+            if due || blocked {
+                replans.push((id, self.plan_path(from, to)));
+            }
+        }
+
+        for id in lost {
+            self.paths.remove(&id);
+        }
+        for (id, waypoints) in replans {
+            if let Some(path) = self.paths.get_mut(&id) {
+                path.waypoints = waypoints;
+                path.next = 0;
+                path.planned_at = self.tick_count;
+            }
+        }
+    }
+
+    /// Solves queued path requests until this tick's budget is used up.
+    fn process_path_queue(&mut self) {
+        let budget = self.path_budget;
+        let mut spent = 0;
+
+        while let Some(request) = self.path_queue.front() {
+            let remaining = budget - spent;
+            if remaining == 0 {
+                break;
+            }
+
+            match self.nav_mesh.find_path_limited(request.start, request.end, remaining) {
+                Ok((path, expansions)) => {
+                    spent += expansions;
+                    self.path_results.insert(request.id, path.iter().flat_map(|p| [p.x, p.y]).collect());
+                    self.path_queue.pop_front();
+                }
+                Err(expansions) => {
+                    spent += expansions;
+                    // Even a whole tick's budget wasn't enough: it never will be, so give up
+                    // on it instead of blocking the queue. Otherwise retry next tick.
+                    if remaining == budget {
+                        self.path_results.insert(request.id, Vec::new());
+                        self.path_queue.pop_front();
+                    }
+                    break;
+                }
+            }
+        }
+
+        self.path_budget_spent = spent;
+    }
+
+    /// Composite FORMATION order: places the slots around `target` (rotated to face `facing`),
+    /// gives each slot to a member (matching their left-to-right order, so units don't cross
+    /// each other's paths), and sends every member along a path to its slot. Members of an existing
+    /// formation leave it. Extra members or extra slots are ignored. With a `callback_id`, each
+    /// member reports its arrival at its own slot.
+    fn order_formation_move(&mut self, target: DVec2, facing: DVec2, members: &[u32], offsets: &[f64], callback_id: Option<u32>) {
+        let heading = match facing.normalize_or_zero() {
+            DVec2::ZERO => DVec2::X,
+            heading => heading,
+        };
+        let slots: Vec<DVec2> = offsets.chunks_exact(2)
+            .map(|o| target + heading.rotate(DVec2::new(o[0], o[1])))
+            .collect();
+
+        let mut units: Vec<(u32, DVec2)> = Vec::new();
+        for &id in members {
+            if units.iter().any(|&(u, _)| u == id) {
+                continue;
+            }
+            if let Some(agent) = self.rvo.agents.iter().find(|a| a.id == id) {
+                units.push((id, agent.position));
+            }
+        }
+
+        // Pair units and slots in left-to-right order across the facing direction, so
+        // nobody has to cross another unit's line. Ties fall back to depth, then id / slot order.
+        let left = heading.perp();
+        units.sort_by(|a, b| {
+            a.1.dot(left).total_cmp(&b.1.dot(left))
+                .then(a.1.dot(heading).total_cmp(&b.1.dot(heading)))
+                .then(a.0.cmp(&b.0))
+        });
+        let mut slot_order: Vec<usize> = (0..slots.len()).collect();
+        slot_order.sort_by(|&a, &b| {
+            let (sa, sb) = (slots[a] - target, slots[b] - target);
+            sa.dot(left).total_cmp(&sb.dot(left))
+                .then(sa.dot(heading).total_cmp(&sb.dot(heading)))
+                .then(a.cmp(&b))
+        });
+
+        for (&(id, pos), s) in units.iter().zip(slot_order) {
+            let slot = slots[s];
+            // No route (or no map loaded): head straight for the slot
+            let mut waypoints = self.plan_path(pos, slot);
+            if waypoints.last() != Some(&slot) {
+                waypoints.push(slot);
+            }
+
+            for formation in self.formations.values_mut() {
+                formation.members.retain(|&(member, _)| member != id);
+            }
+            self.paths.insert(id, AgentPath { waypoints, next: 0, chase: None, replan_interval: 0, planned_at: 0, hold: true });
+            self.mark_ordered(id);
+            match callback_id {
+                Some(callback_id) => {
+                    self.arrival_callbacks.insert(id, ArrivalCallback { destination: slot, callback_id });
+                }
+                None => {
+                    self.arrival_callbacks.remove(&id);
+                }
+            }
+        }
+    }
+
+    /// Path between two world points: HPA if a graph is loaded, otherwise the navmesh.
+    /// Empty if there is no route.
+    fn plan_path(&self, from: DVec2, to: DVec2) -> Vec<DVec2> {
+        match &self.hpa {
+            Some(hpa) => hpa
+                .find_path(from.round().as_ivec2(), to.round().as_ivec2())
+                .map(|path| path.iter().map(|p| p.as_dvec2()).collect())
+                .unwrap_or_default(),
+            None => self.nav_mesh.find_path(from, to),
+        }
+    }
+
+    /// Soft cost overlay for `group`: the weighted sum of every *other* group's heatmap.
+    /// Empty if cross-traffic avoidance is off.
+    fn cross_traffic_costs(&self, group: u32) -> Vec<f64> {
+        if self.cross_traffic_weight == 0.0 {
+            return Vec::new();
+        }
+
+        let mut costs = vec![0.0; self.flow_field.width * self.flow_field.height];
+        for (_, heat) in self.traffic.iter().filter(|(&g, _)| g != group) {
+            for (cost, h) in costs.iter_mut().zip(heat) {
+                *cost += h * self.cross_traffic_weight;
+            }
+        }
+        costs
+    }
+
+    /// Decays every group's heatmap, then stamps each agent's current cell.
+    fn update_traffic(&mut self) {
+        let width = self.flow_field.width;
+        let size = width * self.flow_field.height;
+
+        for heat in self.traffic.values_mut() {
+            heat.iter_mut().for_each(|h| *h *= TRAFFIC_DECAY);
+        }
+
+        for agent in &self.rvo.agents {
+            let ix = agent.position.x.round() as isize;
+            let iy = agent.position.y.round() as isize;
+            if !self.flow_field.in_bounds(ix, iy) { continue; }
+
+            let heat = self.traffic.entry(agent.group).or_insert_with(|| vec![0.0; size]);
+            heat[iy as usize * width + ix as usize] += 1.0;
+        }
+    }
+
+    /// Rounds an exported coordinate to the export quantization step (unchanged if disabled).
+    fn quantize(&self, v: f64) -> f64 {
+        let quantum = self.export_quantum;
+        if quantum > 0.0 { (v / quantum).round() * quantum } else { v }
+    }
+
+    fn rebuild_export_buffer(&mut self) {
+        self.export_buffer.clear();
+        
+        // Ensure capacity to prevent frequent reallocations
+        // 5 floats per agent: [id, x, y, vx, vy]
+        self.export_buffer.reserve(self.rvo.agents.len() * 5);
+
+        for agent in &self.rvo.agents {
+            self.export_buffer.push(agent.id as f64);
+            self.export_buffer.push(self.quantize(agent.position.x));
+            self.export_buffer.push(self.quantize(agent.position.y));
+            self.export_buffer.push(agent.velocity.x);
+            self.export_buffer.push(agent.velocity.y);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pathfinding::hpa::GridMap;
+
+    fn flow_to(sim: &mut Simulation, x: f64, y: f64) {
+        sim.tick(format!(r#"[{{"id":0,"action":"MOVE","mode":"FLOW","target_x":{},"target_y":{}}}]"#, x, y));
+    }
+
+    fn run(sim: &mut Simulation, ticks: usize) {
+        for _ in 0..ticks {
+            sim.tick("[]".into());
+        }
+    }
+
+    fn agent(sim: &Simulation, id: u32) -> Agent {
+        *sim.rvo.agents.iter().find(|a| a.id == id).unwrap()
+    }
+
+    #[test]
+    fn agents_snapshot_restores_agents_but_not_the_map() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 20.0, 10.0, 0.5, 1.0);
+        let saved = sim.rvo.agents.clone();
+
+        flow_to(&mut sim, 50.0, 50.0);
+        run(&mut sim, 10);
+        sim.flow_field.set_obstacle(30, 30, true);
+        sim.restore_agents(saved);
+
+        assert_eq!(agent(&sim, 1).position, DVec2::new(10.0, 10.0));
+        assert_eq!(agent(&sim, 2).position, DVec2::new(20.0, 10.0));
+        assert_eq!(&sim.export_buffer[..3], &[1.0, 10.0, 10.0]);
+        assert_eq!(sim.tick_count, 11);
+        assert_eq!(sim.flow_field.cell(30, 30).unwrap().0, 255);
+        assert!(sim.flow_field.is_target(50.0, 50.0));
+    }
+
+    #[test]
+    fn paths_from_reports_shared_abstract_searches() {
+        let mut hpa = HPAGrid::new(GridMap::new(100, 100), 10);
+        hpa.build();
+        let mut sim = Simulation::new();
+        sim.hpa = Some(hpa);
+        sim.add_agent(1, 5.0, 5.0, 0.5, 1.0);
+
+        let targets: Vec<f64> = (0..20).flat_map(|i| [80.0 + (i % 5) as f64, 80.0 + (i / 5) as f64]).collect();
+        let results = sim.compute_paths_from(1, &targets);
+        assert!(results.iter().all(|r| r.cost.is_some()));
+        assert!(sim.get_last_abstract_searches() < 20);
+    }
+
+    #[test]
+    fn agent_on_its_target_cell_comes_to_rest() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 0.7);
+        flow_to(&mut sim, 20.0, 10.0);
+        run(&mut sim, 40);
+
+        let target = DVec2::new(20.0, 10.0);
+        let mut last = agent(&sim, 1).position;
+        for _ in 0..20 {
+            sim.tick("[]".into());
+            let now = agent(&sim, 1);
+            assert!(now.position.distance(target) < 0.5, "left the target cell: {:?}", now.position);
+            assert!(now.position.distance(last) < 1e-3, "still moving: {:?} -> {:?}", last, now.position);
+            last = now.position;
+        }
+    }
+
+    /// Ticks a 10x10 block packed at 0.85 spacing (radius 0.5) until no agent moves
+    /// more than 0.01 in a tick, returning the tick count.
+    fn ticks_until_packed_block_settles(relax: bool) -> usize {
+        let mut sim = Simulation::new();
+        sim.set_crowd_relaxation(relax);
+        for i in 0..100 {
+            let (x, y) = ((i % 10) as f64, (i / 10) as f64);
+            sim.add_agent(i + 1, 20.0 + x * 0.85, 20.0 + y * 0.85, 0.5, 1.0);
+        }
+        (1..1000)
+            .find(|_| {
+                let before: Vec<DVec2> = sim.rvo.agents.iter().map(|a| a.position).collect();
+                sim.tick("[]".into());
+                sim.rvo.agents.iter().zip(&before).all(|(a, b)| a.position.distance(*b) < 0.01)
+            })
+            .expect("packed block never settled")
+    }
+
+    #[test]
+    fn crowd_relaxation_settles_a_packed_block_sooner() {
+        let fixed = ticks_until_packed_block_settles(false);
+        let relaxed = ticks_until_packed_block_settles(true);
+        assert!(relaxed < fixed, "relaxed {} vs fixed {}", relaxed, fixed);
+    }
+
+    #[test]
+    fn loaded_hpa_bytes_answer_paths_without_a_build() {
+        // Wall across x = 50 with a single gap at y = 90
+        let mut walls = vec![0u8; 100 * 100];
+        for y in 0..100 {
+            if y != 90 {
+                walls[y * 100 + 50] = 1;
+            }
+        }
+        let mut baked = HPAGrid::new(GridMap::from_flat(100, 100, &walls).unwrap(), 10);
+        baked.build();
+        let data = serde_json::to_vec(&baked).unwrap();
+
+        let mut sim = Simulation::new();
+        sim.load_hpa_bytes(&data).unwrap();
+        let path = sim.find_path_hpa(10, 10, 90, 10);
+        assert_eq!(&path[..2], &[10, 10]);
+        assert_eq!(&path[path.len() - 2..], &[90, 10]);
+        assert!(path.chunks(2).any(|p| p == [50, 90]));
+
+        let mut small = HPAGrid::new(GridMap::new(50, 50), 10);
+        small.build();
+        assert!(sim.load_hpa_bytes(&serde_json::to_vec(&small).unwrap()).is_err());
+        // Non-JSON payloads (e.g. bincode) are reported, not installed
+        let err = sim.load_hpa_bytes(&[100, 0, 0, 0, 0, 0, 0, 0, 10, 0]).unwrap_err();
+        assert!(err.starts_with("Invalid HPA data"), "{}", err);
+        assert_eq!(sim.find_path_hpa(10, 10, 90, 10), path);
+    }
+
+    #[test]
+    fn deep_overlaps_separate_at_a_bounded_speed() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 20.0, 20.0, 2.0, 1.0);
+        sim.add_agent(2, 20.2, 20.0, 2.0, 1.0);
+
+        for _ in 0..40 {
+            let before = [agent(&sim, 1).position, agent(&sim, 2).position];
+            run(&mut sim, 1);
+            for (id, was) in [1, 2].into_iter().zip(before) {
+                let step = agent(&sim, id).position.distance(was);
+                assert!(step <= 0.5 + 1e-9, "agent {} flung {}", id, step);
+            }
+        }
+        let gap = agent(&sim, 1).position.distance(agent(&sim, 2).position);
+        assert!(gap > 3.9, "still overlapping at {}", gap);
+    }
+
+    #[test]
+    fn interpolation_halfway_is_the_midpoint() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 40.0, 40.0, 0.5, 1.0);
+        let a = sim.make_snapshot();
+
+        sim.rvo.update_agent_state(1, DVec2::new(14.0, 12.0), DVec2::ZERO);
+        sim.rvo.agents.retain(|a| a.id != 2);
+        sim.add_agent(3, 60.0, 60.0, 0.5, 1.0);
+        let b = sim.make_snapshot();
+
+        let blended = interpolate_snapshots(&a, &b, 0.5);
+        assert_eq!(&blended[..3], &[1.0, 12.0, 11.0]);
+        // Agents on only one side are passed through unchanged
+        assert_eq!(&blended[5..8], &[2.0, 40.0, 40.0]);
+        assert_eq!(&blended[10..13], &[3.0, 60.0, 60.0]);
+        assert_eq!(blended.len(), 15);
+    }
+
+    #[test]
+    fn max_agents_rejects_additions_past_the_cap() {
+        let mut sim = Simulation::new();
+        sim.set_max_agents(2);
+        assert!(sim.add_agent(1, 10.0, 10.0, 0.5, 1.0));
+        assert!(sim.add_agent(2, 20.0, 10.0, 0.5, 1.0));
+        assert!(!sim.add_agent(3, 30.0, 10.0, 0.5, 1.0));
+        assert_eq!(sim.rvo.agents.len(), 2);
+    }
+
+    #[test]
+    fn a_current_carries_an_idle_agent_downstream() {
+        let mut sim = Simulation::new();
+        for x in 0..100 {
+            sim.set_force(x, 20, 0.2, 0.0);
+        }
+        sim.add_agent(1, 20.0, 20.0, 0.5, 1.0);
+
+        let mut last_x = 20.0;
+        for _ in 0..10 {
+            run(&mut sim, 1);
+            let pos = agent(&sim, 1).position;
+            assert!(pos.x > last_x, "no drift at {:?}", pos);
+            last_x = pos.x;
+        }
+        assert!((agent(&sim, 1).position - DVec2::new(22.0, 20.0)).length() < 1e-6);
+
+        // Non-finite forces are ignored and keep whatever was there
+        sim.set_force(22, 20, f64::NAN, 0.0);
+        sim.set_force(22, 20, 0.0, f64::INFINITY);
+        sim.set_force(40, 40, f64::NEG_INFINITY, 0.0);
+        assert_eq!(sim.flow_field.get_force(22.0, 20.0), DVec2::new(0.2, 0.0));
+        assert_eq!(sim.flow_field.get_force(40.0, 40.0), DVec2::ZERO);
+    }
+
+    #[test]
+    fn navmesh_bound_agents_are_snapped_back_onto_the_mesh() {
+        // Walkable strip x in 10..30, y in 10..20; a current pushes east off its end
+        let mut walls = vec![1u8; 100 * 100];
+        for y in 10..20 {
+            for x in 10..30 {
+                walls[y * 100 + x] = 0;
+            }
+        }
+        let mut sim = Simulation::new();
+        sim.nav_mesh = NavMesh::from_grid(&GridMap::from_flat(100, 100, &walls).unwrap());
+        for x in 20..40 {
+            for y in 10..20 {
+                sim.set_force(x, y, 0.5, 0.0);
+            }
+        }
+        sim.add_agent(1, 25.0, 13.0, 0.5, 1.0);
+        sim.add_agent(2, 25.0, 17.0, 0.5, 1.0);
+        sim.set_navmesh_bound(1, true);
+
+        run(&mut sim, 30);
+        let on_mesh = |p: DVec2| sim.nav_mesh.closest_point(p).unwrap().1 == p;
+        assert!(on_mesh(agent(&sim, 1).position));
+        // Unbound agents are free to leave
+        assert!(!on_mesh(agent(&sim, 2).position));
+    }
+
+    /// Cells a group's flow field leads through from `from` to its target.
+    fn group_route(sim: &Simulation, group: u32, from: DVec2) -> HashSet<(i32, i32)> {
+        let field = &sim.group_fields[&group];
+        let mut cell = from;
+        let mut cells = HashSet::new();
+        while cells.insert((cell.x as i32, cell.y as i32)) {
+            cell += field.get_direction(cell.x, cell.y);
+        }
+        cells
+    }
+
+    /// Two groups cross head-on along y = 50. After 20 ticks both are re-ordered; returns how
+    /// many cells of each new route the other group occupied during those ticks.
+    fn head_on_route_overlap(weight: f64) -> usize {
+        let mut sim = Simulation::new();
+        sim.set_cross_traffic_weight(weight);
+        for i in 0..5 {
+            sim.add_agent(i + 1, 10.0 + i as f64, 50.0, 0.4, 1.0);
+            sim.add_agent(i + 11, 90.0 - i as f64, 50.0, 0.4, 1.0);
+        }
+        sim.add_to_group(1, &[1, 2, 3, 4, 5]);
+        sim.add_to_group(2, &[11, 12, 13, 14, 15]);
+
+        let mut walked: HashMap<u32, HashSet<(i32, i32)>> = HashMap::new();
+        sim.move_group(1, 90.0, 50.0, "FLOW");
+        sim.move_group(2, 10.0, 50.0, "FLOW");
+        for _ in 0..20 {
+            run(&mut sim, 1);
+            for a in &sim.rvo.agents {
+                let cell = a.position.round();
+                walked.entry(a.group).or_default().insert((cell.x as i32, cell.y as i32));
+            }
+        }
+        sim.move_group(1, 90.0, 50.0, "FLOW");
+        sim.move_group(2, 10.0, 50.0, "FLOW");
+
+        let east = group_route(&sim, 1, DVec2::new(10.0, 50.0));
+        let west = group_route(&sim, 2, DVec2::new(90.0, 50.0));
+        east.intersection(&walked[&2]).count() + west.intersection(&walked[&1]).count()
+    }
+
+    #[test]
+    fn cross_traffic_separates_opposing_routes() {
+        let ignoring = head_on_route_overlap(0.0);
+        let avoiding = head_on_route_overlap(1.0);
+        assert!(avoiding < ignoring / 2, "{} cells of other traffic avoiding vs {} ignoring", avoiding, ignoring);
+    }
+
+    /// Positions of two agents added at the same point, after `ticks` ticks.
+    fn coincident_pair_after(ticks: usize) -> (DVec2, DVec2) {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 30.0, 30.0, 0.5, 1.0);
+        sim.add_agent(2, 30.0, 30.0, 0.5, 1.0);
+        run(&mut sim, ticks);
+        (agent(&sim, 1).position, agent(&sim, 2).position)
+    }
+
+    #[test]
+    fn coincident_agents_separate_deterministically() {
+        let (a, b) = coincident_pair_after(20);
+        assert!(a.is_finite() && b.is_finite());
+        assert!(a.distance(b) >= 1.0 - 1e-6, "still overlapping: {:?} {:?}", a, b);
+        assert_eq!((a, b), coincident_pair_after(20));
+    }
+
+    #[test]
+    fn frozen_agent_stops_dead_and_still_blocks() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 20.0, 20.0, 0.5, 1.0);
+        sim.add_agent(2, 12.0, 20.0, 0.5, 1.0);
+        flow_to(&mut sim, 40.0, 20.0);
+        run(&mut sim, 3);
+
+        sim.set_frozen(1, true);
+        let stunned_at = agent(&sim, 1).position;
+        let mut closest = f64::INFINITY;
+        for _ in 0..60 {
+            run(&mut sim, 1);
+            assert_eq!(agent(&sim, 1).position, stunned_at);
+            closest = closest.min(agent(&sim, 2).position.distance(stunned_at));
+        }
+        // Agent 2 got past without walking through the stunned one
+        assert!(agent(&sim, 2).position.x > stunned_at.x + 1.0);
+        assert!(closest > 0.9, "came within {}", closest);
+    }
+
+    #[test]
+    fn path_follower_visits_waypoints_in_order() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        let waypoints = [DVec2::new(15.0, 10.0), DVec2::new(15.0, 16.0), DVec2::new(9.0, 16.0)];
+        sim.set_agent_path(1, &waypoints.iter().flat_map(|p| [p.x, p.y]).collect::<Vec<_>>());
+
+        let mut visited = Vec::new();
+        for _ in 0..40 {
+            run(&mut sim, 1);
+            let pos = agent(&sim, 1).position;
+            if let Some(i) = waypoints.iter().position(|w| w.distance(pos) <= WAYPOINT_RADIUS) {
+                if visited.last() != Some(&i) {
+                    visited.push(i);
+                }
+            }
+        }
+        assert_eq!(visited, vec![0, 1, 2]);
+        // Arrived: stays at the last waypoint
+        assert!(agent(&sim, 1).position.distance(waypoints[2]) <= WAYPOINT_RADIUS);
+    }
+
+    #[test]
+    fn paths_from_orders_costs_by_distance() {
+        let mut hpa = HPAGrid::new(GridMap::new(100, 100), 10);
+        hpa.build();
+        let mut sim = Simulation::new();
+        sim.hpa = Some(hpa);
+        sim.add_agent(1, 5.0, 5.0, 0.5, 1.0);
+
+        let results = sim.compute_paths_from(1, &[60.0, 5.0, 15.0, 5.0, 35.0, 5.0]);
+        assert_eq!(results.len(), 3);
+        let costs: Vec<f64> = results.iter().map(|r| r.cost.unwrap()).collect();
+        assert!(costs[1] < costs[2] && costs[2] < costs[0], "{:?}", costs);
+        for (r, target) in results.iter().zip([[60.0, 5.0], [15.0, 5.0], [35.0, 5.0]]) {
+            assert_eq!(&r.path[..2], &[5.0, 5.0]);
+            assert_eq!(&r.path[r.path.len() - 2..], &target);
+        }
+    }
+
+    #[test]
+    fn snapshot_bytes_keep_floats_bit_exact() {
+        let awkward = [0.1 + 0.2, 1.0 / 3.0, 5e-324, 123456.789e10, -0.0];
+        let mut sim = Simulation::new();
+        for (i, &v) in awkward.iter().enumerate() {
+            sim.add_agent(i as u32 + 1, v, 1.0 - v, 0.5, 1.0);
+        }
+        let bytes = sim.get_snapshot_bytes();
+
+        let mut restored = Simulation::new();
+        restored.load_snapshot_bytes(&bytes).unwrap();
+        for (i, &v) in awkward.iter().enumerate() {
+            let pos = agent(&restored, i as u32 + 1).position;
+            assert_eq!(pos.x.to_bits(), v.to_bits());
+            assert_eq!(pos.y.to_bits(), (1.0 - v).to_bits());
+        }
+    }
+
+    #[test]
+    fn position_clear_only_where_no_agent_overlaps() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 20.0, 20.0, 0.5, 1.0);
+
+        assert!(!sim.is_position_clear(20.8, 20.0, 0.5, 0));
+        assert!(sim.is_position_clear(21.2, 20.0, 0.5, 0));
+        // The agent itself can be ignored, e.g. when checking its own destination
+        assert!(sim.is_position_clear(20.8, 20.0, 0.5, 1));
+    }
+
+    /// Drives a 5x5 block through a 3-cell gap in a wall at x = 30 for 150 ticks. Returns the
+    /// mean speed of agents in the approach (25 < x < 30) and the overlap summed over all pairs and ticks.
+    fn squeeze_through_gap(slowdown: bool) -> (f64, f64) {
+        let mut sim = Simulation::new();
+        sim.set_density_slowdown(slowdown);
+        for y in (0..100).filter(|y| !(19..=21).contains(y)) {
+            sim.flow_field.set_obstacle(30, y, true);
+        }
+        for i in 0..25 {
+            sim.add_agent(i + 1, 10.0 + (i % 5) as f64 * 1.2, 17.0 + (i / 5) as f64 * 1.2, 0.5, 1.0);
+        }
+        flow_to(&mut sim, 45.0, 20.0);
+
+        let (mut speed, mut samples, mut overlap) = (0.0, 0, 0.0);
+        for _ in 0..150 {
+            run(&mut sim, 1);
+            let agents = &sim.rvo.agents;
+            for (i, a) in agents.iter().enumerate() {
+                if a.position.x > 25.0 && a.position.x < 30.0 {
+                    speed += a.velocity.length();
+                    samples += 1;
+                }
+                for b in &agents[i + 1..] {
+                    overlap += (a.radius + b.radius - a.position.distance(b.position)).max(0.0);
+                }
+            }
+        }
+        (speed / samples as f64, overlap)
+    }
+
+    #[test]
+    fn density_slowdown_eases_into_a_chokepoint() {
+        let (rushing_speed, rushing_overlap) = squeeze_through_gap(false);
+        let (easing_speed, easing_overlap) = squeeze_through_gap(true);
+        assert!(easing_speed < rushing_speed, "approach speed {} vs {}", easing_speed, rushing_speed);
+        assert!(easing_overlap < rushing_overlap * 0.75, "overlap {} vs {}", easing_overlap, rushing_overlap);
+    }
+
+    #[test]
+    fn refresh_buffer_publishes_direct_mutations() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 20.0, 20.0, 0.5, 1.0);
+        run(&mut sim, 1);
+
+        sim.rvo.update_agent_state(1, DVec2::new(11.0, 12.0), DVec2::ZERO);
+        sim.rvo.update_agent_state(2, DVec2::new(25.0, 26.0), DVec2::ZERO);
+        assert_eq!(&sim.export_buffer[1..3], &[10.0, 10.0]);
+
+        sim.refresh_buffer();
+        assert_eq!(&sim.export_buffer[1..3], &[11.0, 12.0]);
+        assert_eq!(&sim.export_buffer[6..8], &[25.0, 26.0]);
+    }
+
+    #[test]
+    fn chasers_replan_at_most_once_per_interval() {
+        let mut sim = Simulation::new();
+        sim.hpa = Some({
+            let mut hpa = HPAGrid::new(GridMap::new(100, 100), 10);
+            hpa.build();
+            hpa
+        });
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 60.0, 40.0, 0.5, 1.0);
+        sim.set_agent_chase(1, 2, 5);
+
+        let mut plans = Vec::new();
+        for t in 0..40 {
+            // The quarry keeps moving, so every replan sees a new goal
+            sim.rvo.update_agent_state(2, DVec2::new(60.0, 40.0 + t as f64 * 0.5), DVec2::ZERO);
+            run(&mut sim, 1);
+            let planned_at = sim.paths[&1].planned_at;
+            if plans.last() != Some(&planned_at) {
+                plans.push(planned_at);
+            }
+        }
+        assert!(plans.len() >= 6, "{:?}", plans);
+        assert!(plans.windows(2).all(|w| w[1] - w[0] >= 5), "{:?}", plans);
+    }
+
+    #[test]
+    fn quantized_export_hides_sub_step_differences() {
+        let exported = |x: f64| {
+            let mut sim = Simulation::new();
+            sim.set_export_quantization(1.0 / 64.0);
+            sim.add_agent(1, x, 10.0, 0.5, 1.0);
+            sim.refresh_buffer();
+            (sim.export_buffer[1], sim.get_checksum(), sim.rvo.agents[0].position.x)
+        };
+        let (a, b) = (exported(10.0 + 1e-9), exported(10.0 + 2e-9));
+        assert_eq!(a.0, b.0);
+        assert_eq!(a.0, 10.0);
+        assert_eq!(a.1, b.1);
+        // The simulation itself keeps full precision
+        assert_ne!(a.2, b.2);
+    }
+
+    #[test]
+    fn agents_in_unordered_groups_are_reported_without_a_target() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 20.0, 10.0, 0.5, 1.0);
+        sim.set_agent_group(2, 7);
+        run(&mut sim, 1);
+        assert!(sim.get_no_target_agents().is_empty());
+
+        flow_to(&mut sim, 50.0, 50.0);
+        assert_eq!(sim.get_no_target_agents(), vec![2]);
+    }
+
+    #[test]
+    fn fresher_order_claims_the_contested_spot() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 20.0, 17.0, 0.5, 1.0);
+        sim.add_agent(2, 20.0, 23.0, 0.5, 1.0);
+        sim.add_to_group(1, &[1]);
+        sim.add_to_group(2, &[2]);
+        let spot = DVec2::new(20.0, 20.0);
+
+        sim.move_group(1, spot.x, spot.y, "FLOW");
+        run(&mut sim, 2);
+        sim.move_group(2, spot.x, spot.y, "FLOW");
+        run(&mut sim, 60);
+
+        let (older, fresher) = (agent(&sim, 1).position.distance(spot), agent(&sim, 2).position.distance(spot));
+        assert!(fresher < 0.5, "fresher order is {} from the spot", fresher);
+        assert!(older > fresher, "older order {} vs fresher {}", older, fresher);
+    }
+
+    #[test]
+    fn turn_in_place_pivots_before_reversing() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 20.0, 20.0, 0.5, 1.0);
+        sim.set_agent_turning(1, 0.5, true);
+        flow_to(&mut sim, 40.0, 20.0);
+        run(&mut sim, 5);
+        assert!(agent(&sim, 1).facing.dot(DVec2::X) > 0.99);
+
+        // Target straight behind: pivot on the spot first
+        flow_to(&mut sim, 0.0, 20.0);
+        let pivot_at = agent(&sim, 1).position;
+        let mut pivot_ticks = 1;
+        while agent(&sim, 1).position == pivot_at {
+            assert!(pivot_ticks < 20, "never started moving");
+            run(&mut sim, 1);
+            pivot_ticks += 1;
+        }
+        // Half a turn at 0.5 rad per tick takes several ticks
+        assert!(pivot_ticks >= 5, "moved after {} ticks", pivot_ticks);
+        assert!(agent(&sim, 1).velocity.x < 0.0);
+        assert!(agent(&sim, 1).facing.dot(-DVec2::X) > 0.9);
+    }
+
+    #[test]
+    fn agents_in_triangle_lists_only_those_inside() {
+        let mut sim = Simulation::new();
+        sim.nav_mesh = NavMesh::from_grid(&GridMap::new(4, 4));
+        // Triangle 0 is the lower-right half of cell (0, 0): (-0.5, -0.5), (0.5, -0.5), (0.5, 0.5)
+        sim.add_agent(1, 0.3, -0.3, 0.1, 1.0);
+        sim.add_agent(2, -0.3, 0.3, 0.1, 1.0);
+        sim.add_agent(3, 0.4, 0.0, 0.1, 1.0);
+        sim.add_agent(4, 2.0, 2.0, 0.1, 1.0);
+
+        assert_eq!(sim.agents_in_triangle(0), vec![1, 3]);
+        assert_eq!(sim.agents_in_triangle(1), vec![2]);
+        assert!(sim.agents_in_triangle(999).is_empty());
+    }
+
+    /// Distance from `member` to its slot at `offset` in `leader`'s formation.
+    fn slot_error(sim: &Simulation, leader: u32, member: u32, offset: DVec2) -> f64 {
+        let slot = agent(sim, leader).position + sim.formations[&leader].heading.rotate(offset);
+        agent(sim, member).position.distance(slot)
+    }
+
+    #[test]
+    fn member_pushing_past_an_idle_leader_settles_in_its_slot() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        // Slot is on the far side of the leader, so the member shoves it on the way
+        sim.add_agent(2, 8.0, 10.0, 0.5, 1.0);
+        sim.create_formation(1, &[2], &[2.0, 0.0]).unwrap();
+        run(&mut sim, 50);
+
+        let heading = sim.formations[&1].heading;
+        for _ in 0..100 {
+            run(&mut sim, 1);
+            assert_eq!(sim.formations[&1].heading, heading, "shoves turned the formation");
+            assert!(slot_error(&sim, 1, 2, DVec2::new(2.0, 0.0)) < 0.1);
+        }
+    }
+
+    #[test]
+    fn four_unit_formation_keeps_its_shape_around_a_corner() {
+        let mut sim = Simulation::new();
+        let offsets = [DVec2::new(0.0, 2.0), DVec2::new(-2.0, 0.0), DVec2::new(-2.0, 2.0)];
+        sim.add_agent(1, 20.0, 20.0, 0.5, 1.0);
+        for (id, o) in (2..).zip(&offsets) {
+            sim.add_agent(id, 20.0 + o.x, 20.0 + o.y, 0.5, 1.0);
+        }
+        let flat: Vec<f64> = offsets.iter().flat_map(|o| [o.x, o.y]).collect();
+        sim.create_formation(1, &[2, 3, 4], &flat).unwrap();
+        sim.set_agent_path(1, &[40.0, 20.0, 40.0, 40.0]);
+
+        // First leg: members trail their slots by at most one step
+        for _ in 0..18 {
+            run(&mut sim, 1);
+            for (id, o) in (2..).zip(&offsets) {
+                assert!(slot_error(&sim, 1, id, *o) <= 1.0 + 1e-9);
+            }
+        }
+        // Around the corner and parked: the square is back, turned onto the second leg, and stays put
+        run(&mut sim, 40);
+        let heading = sim.formations[&1].heading;
+        assert!(heading.dot(DVec2::Y) > 0.99, "heading {:?}", heading);
+        for _ in 0..40 {
+            run(&mut sim, 1);
+            assert_eq!(sim.formations[&1].heading, heading);
+            for (id, o) in (2..).zip(&offsets) {
+                assert!(slot_error(&sim, 1, id, *o) < 0.1, "member {} off its slot", id);
+            }
+        }
+    }
+
+    #[test]
+    fn ticks_since_rejects_future_snapshots() {
+        let mut sim = Simulation::new();
+        run(&mut sim, 10);
+        assert_eq!(sim.ticks_since(4), Some(6));
+        assert_eq!(sim.ticks_since(10), Some(0));
+        assert_eq!(sim.ticks_since(11), None);
+    }
+
+    #[test]
+    fn restored_snapshots_replay_tie_breaks_and_keep_history() {
+        let mut sim = Simulation::new();
+        // Coincident agents: which way each is pushed is a tie-break
+        sim.add_agent(1, 30.0, 30.0, 0.5, 1.0);
+        sim.add_agent(2, 30.0, 30.0, 0.5, 1.0);
+        sim.set_history_length(5);
+        run(&mut sim, 3);
+
+        let bare = sim.get_snapshot_bytes();
+        sim.set_snapshot_history(true);
+        let mut restored = Simulation::new();
+        restored.load_snapshot_bytes(&sim.get_snapshot_bytes()).unwrap();
+        restored.set_history_length(5);
+
+        run(&mut sim, 10);
+        run(&mut restored, 10);
+        assert_eq!(restored.get_checksum(), sim.get_checksum());
+        assert!(sim.rewind(5) && restored.rewind(5));
+        assert_eq!(restored.get_checksum(), sim.get_checksum());
+
+        // Without the flag the snapshot carries no history to rewind into
+        let mut bare_restored = Simulation::new();
+        bare_restored.load_snapshot_bytes(&bare).unwrap();
+        assert!(!bare_restored.rewind(1));
+    }
+
+    #[test]
+    fn queued_paths_complete_across_ticks_within_the_budget() {
+        let mut sim = Simulation::new();
+        sim.nav_mesh = NavMesh::from_grid(&GridMap::new(30, 30));
+        let queries: Vec<(DVec2, DVec2)> = (0..12)
+            .map(|i| (DVec2::new(1.0, i as f64 * 2.0), DVec2::new(20.0, 28.0 - i as f64)))
+            .collect();
+        let costs: Vec<usize> = queries.iter().map(|&(a, b)| sim.nav_mesh.find_path_with_stats(a, b).1).collect();
+        let budget = *costs.iter().max().unwrap();
+        sim.set_path_budget(budget);
+        let ids: Vec<u32> = queries.iter().map(|&(a, b)| sim.request_path_navmesh(a.x, a.y, b.x, b.y)).collect();
+
+        let mut ticks = 0;
+        while sim.get_pending_path_requests() > 0 {
+            run(&mut sim, 1);
+            ticks += 1;
+            assert!(sim.get_path_budget_spent() <= budget);
+            assert!(ticks <= queries.len(), "queue stalled");
+        }
+        assert!(ticks > 1, "budget of {} fit all {} searches ({:?}) in one tick", budget, queries.len(), costs);
+        for (&id, &(a, b)) in ids.iter().zip(&queries) {
+            let expected: Vec<f64> = sim.nav_mesh.find_path(a, b).iter().flat_map(|p| [p.x, p.y]).collect();
+            assert!(!expected.is_empty());
+            assert_eq!(sim.take_path_result(id), Some(expected));
+        }
+
+        // A paused queue does nothing; a search bigger than a whole tick's budget is dropped
+        let (a, b) = queries[0];
+        let id = sim.request_path_navmesh(a.x, a.y, b.x, b.y);
+        sim.set_path_budget(0);
+        run(&mut sim, 3);
+        assert_eq!((sim.get_pending_path_requests(), sim.get_path_budget_spent()), (1, 0));
+        sim.set_path_budget(costs[0] - 1);
+        run(&mut sim, 1);
+        assert_eq!(sim.get_pending_path_requests(), 0);
+        assert!(sim.get_path_budget_spent() < costs[0]);
+        assert_eq!(sim.take_path_result(id), Some(vec![]));
+    }
+
+    #[test]
+    fn formation_order_lines_units_up_at_the_destination() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 30.0, 5.0, 0.5, 1.0);
+        sim.add_agent(2, 10.0, 5.0, 0.5, 1.0);
+        sim.add_agent(3, 20.0, 5.0, 0.5, 1.0);
+        // A line across the facing (north): +y in the formation frame is west
+        sim.tick(r#"[{"id":0,"action":"MOVE","mode":"FORMATION","target_x":20,"target_y":30,
+            "members":[1,2,3],"offsets":[0,-2,0,0,0,2],"facing_x":0,"facing_y":1,"callback_id":7}]"#.into());
+        let mut events = Vec::new();
+        for _ in 0..60 {
+            run(&mut sim, 1);
+            events.extend(sim.get_arrival_events());
+        }
+
+        // West-most unit takes the west end, so nobody crosses over
+        for (id, slot) in [(2, DVec2::new(18.0, 30.0)), (3, DVec2::new(20.0, 30.0)), (1, DVec2::new(22.0, 30.0))] {
+            assert!(agent(&sim, id).position.distance(slot) < 0.3, "unit {} at {:?}", id, agent(&sim, id).position);
+        }
+        // Each member reports its arrival once
+        let mut arrivals: Vec<&[u32]> = events.chunks(2).collect();
+        arrivals.sort();
+        assert_eq!(arrivals, [[1, 7], [2, 7], [3, 7]]);
+    }
+
+    #[test]
+    fn agents_restored_onto_walls_are_reported_and_repaired() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.2, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 20.0, 20.0, 0.5, 1.0);
+        let saved = sim.rvo.agents.clone();
+
+        // The map changed since the save: agent 1's cell is now a wall, and agent 3 is off the map
+        sim.flow_field.set_obstacle(10, 10, true);
+        let mut stray = saved[1];
+        stray.id = 3;
+        stray.position = DVec2::new(-3.0, 4.0);
+        sim.restore_agents(saved.into_iter().chain([stray]).collect());
+        assert_eq!(sim.validate_agents(), vec![1, 3]);
+
+        assert_eq!(sim.repair_agents(), vec![1, 3]);
+        assert_eq!(agent(&sim, 1).position, DVec2::new(11.0, 10.0));
+        assert_eq!(agent(&sim, 2).position, DVec2::new(20.0, 20.0));
+        assert_eq!(agent(&sim, 3).position, DVec2::new(0.0, 4.0));
+        assert!(sim.validate_agents().is_empty());
+    }
+
+    /// Tick at which each of three agents, starting 10, 20 and 30 units out, comes within 3 of the target.
+    fn group_arrival_ticks(sync: bool) -> Vec<usize> {
+        let mut sim = Simulation::new();
+        let target = DVec2::new(40.0, 20.0);
+        sim.add_agent(1, 30.0, 16.0, 0.5, 1.0);
+        sim.add_agent(2, 20.0, 20.0, 0.5, 1.0);
+        sim.add_agent(3, 10.0, 24.0, 0.5, 1.0);
+        sim.set_group_arrival_sync(0, sync);
+        flow_to(&mut sim, target.x, target.y);
+
+        let mut arrived = vec![0; 3];
+        for tick in 1..100 {
+            run(&mut sim, 1);
+            for (id, at) in (1..).zip(arrived.iter_mut()) {
+                if *at == 0 && agent(&sim, id).position.distance(target) < 3.0 {
+                    *at = tick;
+                }
+            }
+        }
+        assert!(arrived.iter().all(|&t| t > 0), "{:?}", arrived);
+        arrived
+    }
+
+    #[test]
+    fn arrival_sync_brings_the_group_in_together() {
+        let spread = |ticks: Vec<usize>| ticks.iter().max().unwrap() - ticks.iter().min().unwrap();
+        assert!(spread(group_arrival_ticks(false)) >= 15);
+        assert!(spread(group_arrival_ticks(true)) <= 6);
+    }
+
+    #[test]
+    fn invalid_flow_targets_leave_the_previous_order_alone() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 12.0, 10.0, 0.5, 1.0);
+        sim.add_to_group(3, &[1, 2]);
+        sim.move_group(3, 40.0, 20.0, "ATTACK");
+        sim.tick(r#"[{"id":2,"action":"MOVE","mode":"STOP","target_x":0,"target_y":0}]"#.into());
+        run(&mut sim, 2);
+        let integration = sim.group_fields[&3].integration.clone();
+        let ordered_at = agent(&sim, 1).last_order_tick;
+
+        for (x, y) in [(f64::NAN, 20.0), (40.0, f64::NAN), (-5.0, 20.0), (40.0, -3.0), (f64::INFINITY, 20.0), (1e9, 20.0)] {
+            sim.move_group(3, x, y, "FLOW");
+            sim.move_group(3, x, y, "ATTACK");
+        }
+        assert_eq!(sim.group_fields[&3].integration, integration);
+        assert_eq!(sim.group_targets[&3], DVec2::new(40.0, 20.0));
+        assert_eq!(agent(&sim, 1).attack_radius, DEFAULT_ATTACK_RADIUS);
+        assert_eq!(agent(&sim, 1).last_order_tick, ordered_at);
+        assert!(sim.paths[&2].hold, "the STOP hold was released");
+    }
+
+    #[test]
+    fn arrival_callback_fires_once_with_its_id() {
+        let mut sim = Simulation::new();
+        sim.add_agent(5, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(6, 10.0, 30.0, 0.5, 1.0);
+        sim.tick(r#"[{"id":5,"action":"MOVE","mode":"FLOW","target_x":20,"target_y":10,"callback_id":42}]"#.into());
+        assert!(sim.get_arrival_events().is_empty());
+
+        let mut events = Vec::new();
+        let mut fired_at = None;
+        for tick in 0..40 {
+            run(&mut sim, 1);
+            if !sim.get_arrival_events().is_empty() {
+                fired_at.get_or_insert(tick);
+            }
+            events.extend(sim.get_arrival_events());
+        }
+        assert_eq!(events, vec![5, 42]);
+        // Not before the unit is actually there (10 units at speed 1)
+        assert!(fired_at.unwrap() >= 8, "fired at tick {:?}", fired_at);
+    }
+
+    #[test]
+    fn ground_units_route_around_water_that_amphibious_units_cross() {
+        let mut sim = Simulation::new();
+        // Water band at x = 20..=22, with a land bridge from y = 35 up
+        for y in 0..35 {
+            for x in 20..23 {
+                sim.set_terrain(x, y, 1);
+            }
+        }
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 10.0, 14.0, 0.5, 1.0);
+        sim.set_agent_terrain_mask(2, 0b11);
+        flow_to(&mut sim, 30.0, 12.0);
+
+        let on_water = |p: DVec2| (19.5..22.5).contains(&p.x) && p.y < 34.5;
+        let (mut ground_max_y, mut amphibious_max_y) = (0.0f64, 0.0f64);
+        let mut amphibious_wet = false;
+        for _ in 0..120 {
+            run(&mut sim, 1);
+            let (ground, amphibious) = (agent(&sim, 1).position, agent(&sim, 2).position);
+            assert!(!on_water(ground), "ground unit waded in at {:?}", ground);
+            amphibious_wet |= on_water(amphibious);
+            ground_max_y = ground_max_y.max(ground.y);
+            amphibious_max_y = amphibious_max_y.max(amphibious.y);
+        }
+        assert!(ground_max_y > 34.0, "ground unit never took the bridge");
+        assert!(amphibious_wet && amphibious_max_y < 20.0);
+        for id in [1, 2] {
+            assert!(agent(&sim, id).position.distance(DVec2::new(30.0, 12.0)) < 2.0, "unit {} didn't arrive", id);
+        }
+    }
+
+    #[test]
+    fn agent_ids_lists_the_live_set_sorted() {
+        let mut sim = Simulation::new();
+        assert!(sim.agent_ids().is_empty());
+        for id in [7, 3, 12, 5] {
+            sim.add_agent(id, id as f64, 10.0, 0.5, 1.0);
+        }
+        assert_eq!(sim.agent_ids(), vec![3, 5, 7, 12]);
+
+        sim.rvo.agents.retain(|a| a.id != 5);
+        sim.add_agent(1, 40.0, 10.0, 0.5, 1.0);
+        assert_eq!(sim.agent_ids(), vec![1, 3, 7, 12]);
+    }
+
+    #[test]
+    fn one_group_move_steers_every_member() {
+        let mut sim = Simulation::new();
+        let target = DVec2::new(50.0, 50.0);
+        let group = sim.create_group().unwrap();
+        assert_ne!(group, 0);
+        for (id, x) in [(1, 10.0), (2, 20.0), (3, 30.0)] {
+            sim.add_agent(id, x, 10.0, 0.5, 1.0);
+        }
+        sim.add_agent(4, 10.0, 80.0, 0.5, 1.0);
+        sim.add_to_group(group, &[1, 2, 3]);
+        assert!(sim.move_group(group, target.x, target.y, "FLOW"));
+        assert!(!sim.move_group(group, target.x, target.y, "DANCE"));
+        let start: Vec<f64> = [1, 2, 3].iter().map(|&id| agent(&sim, id).position.distance(target)).collect();
+        run(&mut sim, 1);
+
+        // Flow vectors run along the grid, so "toward" means within about 45 degrees
+        for id in [1, 2, 3] {
+            let a = agent(&sim, id);
+            let toward = (target - a.position).normalize();
+            assert!(a.pref_velocity.normalize().dot(toward) > 0.6, "member {} wants {:?}", id, a.pref_velocity);
+        }
+        run(&mut sim, 20);
+        for (id, before) in [1, 2, 3].into_iter().zip(start) {
+            assert!(agent(&sim, id).position.distance(target) < before - 10.0, "member {} stalled", id);
+        }
+        // Outsiders aren't ordered
+        assert_eq!(agent(&sim, 4).pref_velocity, DVec2::ZERO);
+        assert_ne!(sim.create_group().unwrap(), group);
+    }
+
+    #[test]
+    fn create_group_runs_out_instead_of_overflowing() {
+        let mut sim = Simulation::new();
+        sim.next_group = u32::MAX - 1;
+        assert_eq!(sim.create_group(), Some(u32::MAX - 1));
+        assert_eq!(sim.create_group(), None);
+
+        // An agent already in the last group leaves nothing above it either
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_to_group(u32::MAX, &[1]);
+        assert_eq!(sim.create_group(), None);
+    }
+
+    #[test]
+    fn path_quality_reports_hpa_and_optimal_costs() {
+        let mut sim = Simulation::new();
+        assert_eq!(sim.debug_path_quality_hpa(1, 1, 18, 18), None);
+        let mut hpa = HPAGrid::new(GridMap::new(20, 20), 10);
+        hpa.build();
+        sim.hpa = Some(hpa);
+
+        // 17 + 17 straight steps at 10 each at best; HPA can only match or exceed that
+        let quality = sim.debug_path_quality_hpa(1, 1, 18, 18).unwrap();
+        assert_eq!(quality[1], 340);
+        assert!(quality[0] >= quality[1]);
+    }
+
+    #[test]
+    fn agents_idle_cleanly_before_the_first_order() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 20.0, 10.0, 0.5, 1.0);
+        sim.set_agent_group(2, 4);
+        run(&mut sim, 30);
+
+        for (id, x) in [(1, 10.0), (2, 20.0)] {
+            let a = agent(&sim, id);
+            assert_eq!((a.position, a.velocity, a.pref_velocity), (DVec2::new(x, 10.0), DVec2::ZERO, DVec2::ZERO));
+            assert_eq!(a.facing, DVec2::X);
+            assert_eq!(a.stuck_ticks, 0);
+        }
+        assert!(sim.get_no_target_agents().is_empty());
+
+        // Once something is ordered, the never-ordered group is flagged as before
+        flow_to(&mut sim, 40.0, 10.0);
+        run(&mut sim, 1);
+        assert_eq!(sim.get_no_target_agents(), vec![2]);
+    }
+
+    #[test]
+    fn replays_carry_settings_queue_and_hpa() {
+        let mut sim = Simulation::new();
+        // Wall with a gap, so an HPA chase and a straight-line chase diverge
+        let mut grid = GridMap::new(100, 100);
+        for y in 0..30 {
+            grid.set_obstacle(IVec2::new(20, y), true);
+        }
+        let mut hpa = HPAGrid::new(grid.clone(), 10);
+        hpa.build();
+        sim.hpa = Some(hpa);
+        sim.nav_mesh = NavMesh::from_grid(&grid);
+        for (id, x, y) in [(1, 30.0, 10.0), (2, 12.0, 14.0), (3, 10.0, 10.0), (4, 14.0, 12.0)] {
+            sim.add_agent(id, x, y, 0.5, 1.0);
+        }
+        let group = sim.create_group().unwrap();
+        sim.add_to_group(group, &[2, 4]);
+        sim.set_group_arrival_sync(group, true);
+        sim.set_bilinear_flow(true);
+        sim.set_cross_traffic_weight(2.0);
+        sim.set_export_quantization(1.0 / 64.0);
+        sim.set_max_displacement(0.8);
+        sim.set_agent_chase(3, 1, 5);
+        sim.set_path_budget(5);
+        let request = sim.request_path_navmesh(2.0, 2.0, 40.0, 40.0);
+
+        sim.start_recording();
+        sim.tick(format!(r#"[{{"id":0,"action":"MOVE","mode":"FLOW","target_x":40,"target_y":20,"group":{}}}]"#, group));
+        run(&mut sim, 9);
+        let blob = sim.stop_recording();
+
+        // A fresh simulation with none of the settings, no HPA graph and an empty queue
+        let mut replayed = Simulation::new();
+        replayed.replay(&blob).unwrap();
+        assert_eq!(replayed.get_checksum(), sim.get_checksum());
+        assert_eq!(replayed.get_pending_path_requests(), sim.get_pending_path_requests());
+        assert_eq!(replayed.take_path_result(request), sim.take_path_result(request));
+        assert_eq!(replayed.create_group(), sim.create_group());
+        assert!(replayed.hpa.is_some());
+
+        // ...and a replay recorded without HPA drops one loaded on the replaying side
+        let mut plain = Simulation::new();
+        plain.start_recording();
+        run(&mut plain, 2);
+        sim.replay(&plain.stop_recording()).unwrap();
+        assert!(sim.hpa.is_none());
+        assert_eq!(sim.get_checksum(), plain.get_checksum());
+    }
+
+    #[test]
+    fn remapped_ids_keep_interpolation_pairs_aligned() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 20.0, 10.0, 0.5, 1.0);
+        flow_to(&mut sim, 40.0, 10.0);
+        run(&mut sim, 3);
+
+        sim.remap_ids(&[1, 2], &[101, 102]);
+        let ids = |buffer: &[f64]| -> Vec<f64> { buffer.iter().step_by(5).copied().collect() };
+        assert_eq!(ids(&sim.export_buffer), vec![101.0, 102.0]);
+        assert_eq!(ids(&sim.prev_export_buffer), ids(&sim.export_buffer));
+        // Previous positions stay with their agents: one step behind, not swapped or reset
+        for (prev, now) in sim.prev_export_buffer.chunks(5).zip(sim.export_buffer.chunks(5)) {
+            assert!((now[1] - prev[1] - 1.0).abs() < 1e-9 && now[2] == prev[2], "{:?} -> {:?}", prev, now);
+        }
+    }
+
+    #[test]
+    fn separation_only_pile_spreads_apart_then_stays_put() {
+        let mut sim = Simulation::new();
+        for id in 1..=5 {
+            sim.add_agent(id, 20.0 + id as f64 * 0.05, 20.0, 0.5, 1.0);
+            sim.set_separation_only(id, true);
+        }
+        // A flow order must not make them seek
+        flow_to(&mut sim, 60.0, 20.0);
+        run(&mut sim, 60);
+
+        let settled: Vec<Agent> = (1..=5).map(|id| agent(&sim, id)).collect();
+        for (i, a) in settled.iter().enumerate() {
+            for b in &settled[i + 1..] {
+                assert!(a.position.distance(b.position) > 0.9, "{} and {} still overlap", a.id, b.id);
+            }
+            assert!(a.position.distance(DVec2::new(20.0, 20.0)) < 3.0, "{} drifted to {:?}", a.id, a.position);
+        }
+
+        run(&mut sim, 30);
+        for a in &settled {
+            assert!(agent(&sim, a.id).position.distance(a.position) < 1e-3, "{} kept moving", a.id);
+        }
+    }
+
+    #[test]
+    fn triangle_neighbors_match_the_mesh_adjacency() {
+        let mut sim = Simulation::new();
+        let mut walls = vec![0u8; 9];
+        walls[4] = 1;
+        sim.nav_mesh = NavMesh::from_grid(&GridMap::from_flat(3, 3, &walls).unwrap());
+        let tris = &sim.nav_mesh.triangles;
+        assert!(!tris.is_empty());
+
+        let mut borders = 0;
+        for tri in tris {
+            let expected: Vec<i32> = (0..3).map(|i| {
+                let (a, b) = (tri.vertices[i], tri.vertices[(i + 1) % 3]);
+                let has = |other: &Triangle, p: DVec2| other.vertices.iter().any(|v| v.distance(p) < 1e-9);
+                tris.iter()
+                    .find(|other| other.id != tri.id && has(other, a) && has(other, b))
+                    .map_or(-1, |other| other.id as i32)
+            }).collect();
+            assert_eq!(sim.triangle_neighbors(tri.id), expected, "triangle {}", tri.id);
+            borders += expected.iter().filter(|&&n| n == -1).count();
+        }
+        // The outer edge and the walled-off centre cell are both borders
+        assert_eq!(borders, 16);
+
+        assert!(sim.triangle_neighbors(tris.len()).is_empty());
+    }
+
+    #[test]
+    fn two_agents_wedged_in_a_corner_work_themselves_free() {
+        // An inside corner: a wall straight ahead and another along the corner agent's
+        // left. Both head for a waypoint behind the wall, so they press into the corner
+        // and into each other.
+        let mut sim = Simulation::new();
+        let mut walls = vec![0u8; 30 * 30];
+        for y in 10..=18 {
+            for x in 8..=17 {
+                walls[y * 30 + x] = u8::from(x >= 15 || y >= 16);
+            }
+        }
+        sim.nav_mesh = NavMesh::from_grid(&GridMap::from_flat(30, 30, &walls).unwrap());
+        sim.add_agent(1, 14.0, 14.0, 0.5, 1.0);
+        sim.add_agent(2, 14.0, 15.0, 0.5, 1.0);
+        for id in [1, 2] {
+            sim.set_navmesh_bound(id, true);
+            sim.set_agent_path(id, &[25.0, 15.0]);
+        }
+
+        let mut wedged = [false; 2];
+        let mut freed = [0; 2];
+        for tick in 1..=150 {
+            run(&mut sim, 1);
+            for (i, id) in [1, 2].into_iter().enumerate() {
+                let a = agent(&sim, id);
+                wedged[i] |= a.stuck_ticks >= STUCK_TICKS;
+                if freed[i] == 0 && a.position.x > 18.0 {
+                    freed[i] = tick;
+                }
+            }
+        }
+        assert_eq!(wedged, [true; 2], "the corner never wedged them");
+        assert!(freed.iter().all(|&t| t > 0), "still wedged: {:?}", freed);
+    }
+
+    #[test]
+    fn agents_slowed_by_arrival_sync_are_not_stuck() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 38.0, 20.0, 0.5, 1.0);
+        sim.add_agent(2, 2.0, 20.0, 0.5, 1.0);
+        sim.set_group_arrival_sync(0, true);
+        flow_to(&mut sim, 40.0, 20.0);
+
+        let start = agent(&sim, 1).position;
+        for _ in 0..STUCK_TICKS * 2 {
+            run(&mut sim, 1);
+            let a = agent(&sim, 1);
+            assert!(a.pref_velocity.length() < a.cruise_speed() * STUCK_PROGRESS, "not slowed: {:?}", a.pref_velocity);
+            assert_eq!(a.stuck_ticks, 0);
+        }
+        let end = agent(&sim, 1).position;
+        assert!(end.x > start.x && (end.y - start.y).abs() < 1e-6, "{:?} -> {:?}", start, end);
+    }
+
+    #[test]
+    fn goals_export_reports_each_agents_destination() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 12.0, 10.0, 0.5, 1.0);
+        sim.add_agent(3, 14.0, 10.0, 0.5, 1.0);
+        sim.set_agent_group(1, 4);
+        sim.set_agent_group(3, 5);
+        sim.move_group(4, 40.0, 20.0, "FLOW");
+        sim.set_agent_path(2, &[20.0, 10.0, 30.0, 5.0]);
+        run(&mut sim, 2);
+
+        assert_eq!(sim.get_goals_flat(), vec![
+            1.0, 40.0, 20.0, 1.0,
+            2.0, 30.0, 5.0, 1.0,
+            3.0, 0.0, 0.0, 0.0,
+        ]);
+    }
+
+    #[test]
+    fn stop_mid_motion_halts_without_a_lurch() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        flow_to(&mut sim, 40.0, 10.0);
+        run(&mut sim, 10);
+        assert!(agent(&sim, 1).velocity.length() > 0.9);
+
+        let before = agent(&sim, 1).position;
+        sim.tick(r#"[{"id":1,"action":"MOVE","mode":"STOP","target_x":0,"target_y":0}]"#.into());
+        let stopped = agent(&sim, 1);
+        assert_eq!((stopped.velocity, stopped.pref_velocity), (DVec2::ZERO, DVec2::ZERO));
+        assert!(stopped.position.distance(before) < 1e-9, "lurched from {:?} to {:?}", before, stopped.position);
+
+        let mut last = stopped.position;
+        for _ in 0..20 {
+            run(&mut sim, 1);
+            let a = agent(&sim, 1);
+            assert!(a.position.distance(last) < 1e-6, "crept from {:?} to {:?}", last, a.position);
+            last = a.position;
+        }
+
+        // A seek-to-seek switch keeps the momentum: a path straight ahead carries on at speed
+        flow_to(&mut sim, 40.0, 10.0);
+        run(&mut sim, 10);
+        sim.set_agent_path(1, &[40.0, 10.0]);
+        run(&mut sim, 1);
+        assert!(agent(&sim, 1).velocity.x > 0.9);
+    }
+
+    #[test]
+    fn spawn_ring_spreads_points_beside_a_wall() {
+        let mut sim = Simulation::new();
+        for y in 0..40 {
+            sim.flow_field.set_obstacle(21, y, true);
+        }
+        sim.add_agent(1, 19.0, 22.0, 0.5, 1.0);
+
+        let points: Vec<DVec2> = sim.spawn_ring(20.0, 20.0, 8, 0.5, 1.5).chunks(2).map(|p| DVec2::new(p[0], p[1])).collect();
+        assert_eq!(points.len(), 8);
+        for (i, p) in points.iter().enumerate() {
+            assert!(sim.flow_field.is_walkable_at(p.x, p.y), "{:?} is in the wall", p);
+            assert!(p.x < 20.5, "{:?} is behind the wall", p);
+            assert!(sim.is_position_clear(p.x, p.y, 0.5, u32::MAX), "{:?} overlaps the agent", p);
+            for q in &points[i + 1..] {
+                assert!(p.distance(*q) >= 1.5 - 1e-9, "{:?} and {:?} are too close", p, q);
+            }
+        }
+    }
+
+    #[test]
+    fn spawn_ring_gives_up_with_a_partial_result() {
+        let sim = Simulation::new();
+        let points = sim.spawn_ring(20.0, 20.0, usize::MAX, 0.0, 1e-6);
+        assert!(!points.is_empty());
+        assert!(points.len() / 2 <= SPAWN_RING_MAX_CANDIDATES);
+
+        // Plenty of room, but more points than the cap allows candidates for
+        let points = sim.spawn_ring(50.0, 50.0, 100_000, 0.0, 0.5);
+        assert!(!points.is_empty() && points.len() / 2 <= SPAWN_RING_MAX_CANDIDATES, "{}", points.len() / 2);
+    }
+
+    #[test]
+    fn displacement_guard_clamps_absurd_moves_and_reports_them() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 20.0, 20.0, 0.5, 1.0);
+        sim.add_agent(3, 30.0, 30.0, 0.5, 1.0);
+        sim.set_force(10, 10, 500.0, 0.0);
+        // set_force refuses NaN, so corrupt the layer directly
+        let cell = 20 * sim.flow_field.width + 20;
+        sim.flow_field.forces[cell] = DVec2::new(f64::NAN, 0.0);
+        sim.set_max_displacement(2.0);
+        run(&mut sim, 1);
+
+        assert_eq!(sim.get_displacement_events(), vec![1, 2]);
+        assert!((agent(&sim, 1).position - DVec2::new(12.0, 10.0)).length() < 1e-9, "{:?}", agent(&sim, 1).position);
+        assert_eq!((agent(&sim, 2).position, agent(&sim, 2).velocity), (DVec2::new(20.0, 20.0), DVec2::ZERO));
+        assert_eq!(agent(&sim, 3).position, DVec2::new(30.0, 30.0));
+
+        // Events are per tick: agent 1 is off the bad cell now
+        run(&mut sim, 1);
+        assert_eq!(sim.get_displacement_events(), vec![2]);
+
+        // Off by default: the same push goes through unchecked
+        let mut unguarded = Simulation::new();
+        unguarded.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        unguarded.set_force(10, 10, 500.0, 0.0);
+        run(&mut unguarded, 1);
+        assert!(unguarded.get_displacement_events().is_empty());
+        assert!(agent(&unguarded, 1).position.x > 400.0);
+    }
+
+    #[test]
+    fn agents_walk_at_pref_speed_and_only_sprint_to_dodge() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.set_pref_speed(1, 0.5);
+        flow_to(&mut sim, 40.0, 10.0);
+        run(&mut sim, 5);
+        for _ in 0..20 {
+            run(&mut sim, 1);
+            assert!((agent(&sim, 1).velocity.length() - 0.5).abs() < 1e-9, "{:?}", agent(&sim, 1).velocity);
+        }
+
+        // Head-on: the walker dodges a fast unit coming the other way
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 20.0, 0.5, 1.0);
+        sim.add_agent(2, 30.0, 20.0, 0.5, 1.0);
+        sim.set_pref_speed(1, 0.5);
+        sim.set_agent_group(2, 2);
+        sim.move_group(0, 40.0, 20.0, "FLOW");
+        sim.move_group(2, 0.0, 20.0, "FLOW");
+        let mut fastest: f64 = 0.0;
+        for _ in 0..40 {
+            run(&mut sim, 1);
+            let speed = agent(&sim, 1).velocity.length();
+            assert!(speed <= 1.0 + 1e-9, "{}", speed);
+            fastest = fastest.max(speed);
+        }
+        assert!(fastest > 0.5 + 1e-3, "never sped up to dodge: {}", fastest);
+        // and settles back to walking pace once clear
+        assert!((agent(&sim, 1).velocity.length() - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reserved_buffers_stay_put_through_a_wave_spawn() {
+        let mut sim = Simulation::new();
+        sim.reserve_agents(1000);
+        let (state, agents) = (sim.get_state_ptr(), sim.rvo.agents.as_ptr());
+        for id in 0..1000 {
+            assert!(sim.add_agent(id, (id % 40) as f64 + 5.0, (id / 40) as f64 + 5.0, 0.3, 1.0));
+            assert_eq!(sim.get_state_ptr(), state, "export buffer moved at agent {}", id);
+        }
+        assert_eq!(sim.rvo.agents.as_ptr(), agents);
+        assert_eq!(sim.export_buffer.len(), 5000);
+
+        // A bogus count is only a hint, not an abort
+        sim.reserve_agents(usize::MAX);
+        sim.set_max_agents(usize::MAX - 1);
+        sim.reserve_agents(usize::MAX);
+    }
+
+    #[test]
+    fn traced_flow_route_runs_from_the_agent_to_the_target() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.3, 10.2, 0.5, 1.0);
+        assert!(sim.trace_flow_path(1, 100).is_empty());
+        for y in 5..=20 {
+            sim.flow_field.set_obstacle(20, y, true);
+        }
+        flow_to(&mut sim, 30.0, 12.0);
+
+        let start = agent(&sim, 1).position;
+        let line: Vec<DVec2> = sim.trace_flow_path(1, 100).chunks(2).map(|p| DVec2::new(p[0], p[1])).collect();
+        assert_eq!(line[0], start);
+        assert_eq!(*line.last().unwrap(), DVec2::new(30.0, 12.0));
+        // Cell to cell along the grid, and around the wall rather than through it
+        for leg in line[1..].windows(2) {
+            assert!(leg[0].x == leg[1].x || leg[0].y == leg[1].y, "{:?}", leg);
+            for i in 0..=20 {
+                let p = leg[0].lerp(leg[1], i as f64 / 20.0);
+                assert!(sim.flow_field.is_walkable_at(p.x, p.y), "{:?} crosses the wall", leg);
+            }
+        }
+        // Turning points only: no three in a row on one line
+        for w in line[1..].windows(3) {
+            assert!(!(w[0].x == w[2].x || w[0].y == w[2].y), "{:?}", w);
+        }
+
+        // A short budget stops partway, within that many cells of the agent
+        let short: Vec<f64> = sim.trace_flow_path(1, 5);
+        let end = DVec2::new(short[short.len() - 2], short[short.len() - 1]);
+        assert!(end.distance(start.round()) <= 5.0 && end != DVec2::new(30.0, 12.0));
+        assert!(sim.trace_flow_path(99, 100).is_empty());
+    }
+
+    #[test]
+    fn snapshot_diff_reports_a_moved_agent_and_a_new_wall() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 20.0, 10.0, 0.5, 1.0);
+        let a = sim.make_snapshot();
+        assert!(diff_snapshots(&a, &a.clone()).is_empty());
+
+        sim.rvo.agents.iter_mut().find(|agent| agent.id == 2).unwrap().position.y += 0.5;
+        sim.flow_field.set_obstacle(7, 3, true);
+        let b = sim.make_snapshot();
+        assert_eq!(diff_snapshots(&a, &b), vec![
+            "agent 2: position (20, 10) vs (20, 10.5), off by 0.5".to_string(),
+            "cell (7, 3): cost 1 vs 255".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn attack_move_halts_for_an_enemy_and_resumes_when_it_leaves() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 25.0, 10.0, 0.5, 1.0);
+        sim.set_agent_group(1, 3);
+        sim.set_agent_group(2, 5);
+        sim.set_agent_team(1, 1);
+        sim.set_agent_team(2, 2);
+        sim.move_group(3, 40.0, 10.0, "ATTACK");
+
+        let mut halted_at = None;
+        for tick in 0..30 {
+            run(&mut sim, 1);
+            if !sim.get_attack_events().is_empty() {
+                halted_at = Some(tick);
+                break;
+            }
+        }
+        assert!(halted_at.is_some(), "never engaged");
+        assert_eq!(sim.get_attack_events(), vec![1, 2]);
+        let held = agent(&sim, 1).position;
+        assert!(held.distance(DVec2::new(25.0, 10.0)) <= DEFAULT_ATTACK_RADIUS + 1.0, "{:?}", held);
+        run(&mut sim, 5);
+        assert_eq!(sim.get_attack_events(), vec![1, 2]);
+        assert_eq!(agent(&sim, 1).pref_velocity, DVec2::ZERO);
+        assert!(agent(&sim, 1).position.distance(held) < 0.1, "kept walking: {:?}", agent(&sim, 1).position);
+
+        // The enemy leaves: the attack-move carries on to the destination
+        sim.rvo.agents.iter_mut().find(|a| a.id == 2).unwrap().position = DVec2::new(25.0, 60.0);
+        run(&mut sim, 40);
+        assert!(sim.get_attack_events().is_empty());
+        assert!(agent(&sim, 1).position.distance(DVec2::new(40.0, 10.0)) < 1.5, "{:?}", agent(&sim, 1).position);
+    }
+
+    #[test]
+    fn crowd_splits_around_a_central_pillar() {
+        let mut sim = Simulation::new();
+        for y in 18..=22 {
+            for x in 30..=32 {
+                sim.flow_field.set_obstacle(x, y, true);
+            }
+        }
+        // Three files: two flanking ones and one dead on the pillar's axis, where both
+        // ways around cost exactly the same
+        for col in 0..8 {
+            for (row, y) in [16.0, 20.0, 24.0].into_iter().enumerate() {
+                sim.add_agent(col * 3 + row as u32 + 1, 24.0 - col as f64 * 2.0, y, 0.3, 1.0);
+            }
+        }
+        flow_to(&mut sim, 50.0, 20.0);
+
+        // How far above (+) or below (-) the pillar's axis each agent passes it
+        let mut passed: HashMap<u32, f64> = HashMap::new();
+        for _ in 0..80 {
+            run(&mut sim, 1);
+            for a in &sim.rvo.agents {
+                if (30.0..=32.0).contains(&a.position.x) {
+                    passed.entry(a.id).or_insert(a.position.y - 20.0);
+                }
+            }
+        }
+        assert_eq!(passed.len(), 24, "some agents never got past");
+        let sides = |ids: Vec<u32>| -> (usize, usize) {
+            let above = ids.iter().filter(|id| passed[id] > 0.0).count();
+            (above, ids.len() - above)
+        };
+        let (above, below) = sides((1..=24).collect());
+        assert!(above >= 8 && below >= 8, "{} above, {} below", above, below);
+        // The tie-break spreads the middle file over both sides instead of one
+        let (above, below) = sides((0..8).map(|col| col * 3 + 2).collect());
+        assert!(above >= 2 && below >= 2, "middle file: {} above, {} below", above, below);
+    }
+
+    #[test]
+    fn non_finite_inputs_never_break_a_snapshot_round_trip() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 10.0, 10.0, 0.5, 1.0);
+        sim.add_agent(2, 14.0, 10.0, 0.5, 1.0);
+        let bad = [f64::NAN, f64::INFINITY, f64::NEG_INFINITY];
+        for &v in &bad {
+            assert!(!sim.add_agent(9, v, 10.0, 0.5, 1.0));
+            assert!(!sim.add_agent(9, 10.0, 10.0, 0.5, v));
+            sim.set_force(20, 20, v, 0.0);
+            sim.set_force(10, 10, 0.0, v);
+            sim.set_agent_path(1, &[30.0, v]);
+            assert!(sim.create_formation(1, &[2], &[v, 0.0]).is_err());
+            sim.set_agent_turning(1, v, false);
+            sim.set_pref_speed(1, v);
+            sim.set_cross_traffic_weight(v);
+            sim.set_max_displacement(v);
+            sim.set_export_quantization(v);
+            sim.flow_field.set_height(5, 5, v);
+            sim.flow_field.set_slope_penalty(v);
+            sim.flow_field.set_soft_costs(vec![v; sim.flow_field.width * sim.flow_field.height]);
+            sim.nav_mesh.set_vertex_epsilon(v);
+            sim.nav_mesh.set_corner_rounding(v, 0.5);
+            sim.nav_mesh.add_cost_zone(DVec2::new(5.0, 5.0), 2.0, v);
+            let request = sim.request_path_navmesh(v, 1.0, 5.0, 5.0);
+            assert_eq!(sim.take_path_result(request), Some(Vec::new()));
+        }
+        flow_to(&mut sim, 40.0, 10.0);
+        run(&mut sim, 5);
+
+        let bytes = sim.get_snapshot_bytes();
+        let mut restored = Simulation::new();
+        restored.load_snapshot_bytes(&bytes).unwrap();
+        assert_eq!(restored.get_checksum(), sim.get_checksum());
+        assert_eq!(restored.get_snapshot_bytes(), bytes);
+    }
+
+    #[test]
+    fn snapshots_without_the_target_flag_still_steer_group_zero() {
+        let mut sim = Simulation::new();
+        sim.add_agent(1, 5.5, 5.5, 0.5, 1.0);
+        flow_to(&mut sim, 30.0, 5.0);
+        let json = String::from_utf8(sim.get_snapshot_bytes()).unwrap();
+        assert!(json.contains(r#""has_target":true"#));
+        let old = json.replace(r#","has_target":true"#, "").replace(r#""has_target":true,"#, "");
+        assert!(!old.contains("has_target"));
+
+        let mut restored = Simulation::new();
+        restored.load_snapshot_bytes(old.as_bytes()).unwrap();
+        assert!(restored.flow_field.has_target());
+        let start = agent(&restored, 1).position;
+        run(&mut restored, 20);
+        assert!(agent(&restored, 1).position.x > start.x + 1.0, "group-0 agent idled after loading");
+
+        let mut empty = Simulation::new();
+        let untargeted = String::from_utf8(empty.get_snapshot_bytes()).unwrap().replace(r#","has_target":false"#, "").replace(r#""has_target":false,"#, "");
+        empty.load_snapshot_bytes(untargeted.as_bytes()).unwrap();
+        assert!(!empty.flow_field.has_target());
+    }
+
+    #[test]
+    fn replaying_discards_a_recording_in_progress() {
+        let mut recorded = Simulation::new();
+        recorded.add_agent(1, 5.5, 5.5, 0.5, 1.0);
+        recorded.start_recording();
+        flow_to(&mut recorded, 30.0, 5.0);
+        run(&mut recorded, 4);
+        let blob = recorded.stop_recording();
+
+        let mut sim = Simulation::new();
+        sim.start_recording();
+        run(&mut sim, 2);
+        sim.replay(&blob).unwrap();
+        assert_eq!(sim.get_checksum(), recorded.get_checksum());
+        assert_eq!(sim.stop_recording(), "", "the replayed ticks were recorded");
+
+        // Recording again after the replay captures the replayed state as its start
+        sim.start_recording();
+        run(&mut sim, 3);
+        let again = sim.stop_recording();
+        let mut fresh = Simulation::new();
+        fresh.replay(&again).unwrap();
+        assert_eq!(fresh.get_checksum(), sim.get_checksum());
+    }
+}
@@ -3,6 +3,9 @@ use std::collections::{BinaryHeap, HashMap};
 use std::hash::Hash;
 use std::ops::Add;
 
+/// Search result plus the number of expanded nodes: `(Some((cost, path)) or None, expansions)`.
+pub type Counted<N, C> = (Option<(C, Vec<N>)>, usize);
+
 /// A generic wrapper for the priority queue state.
 /// This handles the Min-Heap logic and floating point comparisons if C is f64.
 struct State<N, C> {
@@ -46,12 +49,38 @@ impl<N, C: PartialOrd> PartialOrd for State<N, C> {
 /// * `get_neighbors` - A closure returning a list of (Neighbor, EdgeCost).
 /// * `get_heuristic` - A closure returning the estimated cost to the goal.
 /// * `is_goal` - A closure returning true if the node is the target.
-pub fn a_star<N, C, FN, FH, FG>(
+///
+/// Also reports how many nodes were expanded (popped from the open set and had
+/// their neighbors explored). Useful for profiling query cost.
+pub fn a_star_counted<N, C, FN, FH, FG>(
+    start: N,
+    get_neighbors: FN,
+    get_heuristic: FH,
+    is_goal: FG,
+) -> Counted<N, C>
+where
+    N: Eq + Hash + Copy,
+    C: Default + Copy + PartialOrd + Add<Output = C>,
+    FN: FnMut(N) -> Vec<(N, C)>,
+    FH: FnMut(N) -> C,
+    FG: FnMut(N) -> bool,
+{
+    match a_star_limited(start, get_neighbors, get_heuristic, is_goal, usize::MAX) {
+        Ok(result) => result,
+        Err(expansions) => (None, expansions),
+    }
+}
+
+/// Same as `a_star_counted`, but gives up once `max_expansions` nodes have been expanded.
+/// Returns `Err(expansions)` if the search was cut short before reaching a verdict,
+/// so callers can tell "out of budget" apart from "no path".
+pub fn a_star_limited<N, C, FN, FH, FG>(
     start: N,
     mut get_neighbors: FN,
     mut get_heuristic: FH,
     mut is_goal: FG,
-) -> Option<(C, Vec<N>)>
+    max_expansions: usize,
+) -> Result<Counted<N, C>, usize>
 where
     N: Eq + Hash + Copy,
     C: Default + Copy + PartialOrd + Add<Output = C>,
@@ -59,6 +88,7 @@ where
     FH: FnMut(N) -> C,
     FG: FnMut(N) -> bool,
 {
+    let mut expansions = 0;
     let mut open_set = BinaryHeap::new();
     let mut came_from: HashMap<N, N> = HashMap::new();
     let mut g_score: HashMap<N, C> = HashMap::new();
@@ -85,8 +115,13 @@ where
             path.reverse();
             
             let total_cost = *g_score.get(&current).unwrap();
-            return Some((total_cost, path));
+            return Ok((Some((total_cost, path)), expansions));
+        }
+
+        if expansions >= max_expansions {
+            return Err(expansions);
         }
+        expansions += 1;
 
         // Optimization: If we found a shorter way to this node already in a previous iteration
         // (lazy deletion from heap), skip it.
@@ -114,5 +149,5 @@ where
         }
     }
 
-    None
+    Ok((None, expansions))
 }
\ No newline at end of file
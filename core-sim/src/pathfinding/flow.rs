@@ -25,6 +25,21 @@ impl Ord for State {
     }
 }
 
+/// How strongly a flee field prefers distant escape routes over simply backing away.
+/// Values above 1.0 make agents run past the threat rather than into dead ends.
+const FLEE_COEFFICIENT: f64 = 1.2;
+
+/// Terrain types are a per-cell byte in `FlowField::terrain` (0 = ground, 1 = water).
+/// Bit `t` of a terrain mask allows entering terrain type `t`.
+pub const TERRAIN_GROUND: u8 = 0;
+/// Mask of regular ground units: ground only, so water blocks them.
+pub const GROUND_MASK: u32 = 1 << TERRAIN_GROUND;
+
+/// Serde default for terrain masks (fields and agents saved before terrain existed): ground only.
+pub(crate) fn default_terrain_mask() -> u32 {
+    GROUND_MASK
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct FlowField {
     pub width: usize,
@@ -32,6 +47,43 @@ pub struct FlowField {
     pub costs: Vec<u8>,        // 1 = Walkable, 255 = Wall
     pub integration: Vec<f64>, // Distance to target (Heatmap)
     pub vectors: Vec<DVec2>,   // Final direction vectors for agents
+    /// Optional per-cell terrain height (2.5D maps). Empty = flat map.
+    #[serde(default)]
+    pub heights: Vec<f64>,
+    /// Extra traversal cost per unit of height climbed. 0 = slopes are free.
+    #[serde(default)]
+    pub slope_penalty: f64,
+    /// Optional per-cell environmental force (conveyors, rivers, wind). Empty = no forces.
+    #[serde(default)]
+    pub forces: Vec<DVec2>,
+    /// Optional fractional extra cost per cell (e.g. cross-traffic from other groups).
+    /// Unlike `costs`, these never block a tile. Empty = none.
+    #[serde(default)]
+    pub soft_costs: Vec<f64>,
+    /// Optional scratch cost layer for dynamic blockers (units, temporary barricades).
+    /// Integration uses the max of this and `costs`, so clearing it never touches the
+    /// authored map. Empty = none.
+    #[serde(default)]
+    pub dynamic_costs: Vec<u8>,
+    /// Optional per-cell terrain type. Empty = all ground.
+    #[serde(default)]
+    pub terrain: Vec<u8>,
+    /// Terrain types this field routes through (bit per type). Cells of any other
+    /// terrain count as walls, so one field per mask is needed (see `Agent::terrain_mask`).
+    #[serde(default = "default_terrain_mask")]
+    pub terrain_mask: u32,
+    /// Reset only the cells the previous integration wrote instead of the whole field.
+    /// Pays off when targets only reach a small part of a large map.
+    #[serde(default)]
+    pub reset_touched_only: bool,
+    /// Cells that got a finite integration cost since the last reset, or `None` if
+    /// unknown (fresh/deserialized field, after `merge_min`), which forces a full fill.
+    #[serde(skip)]
+    touched: Option<Vec<usize>>,
+    /// Set once a `generate_*` call seeded the integration (see `has_target`). `None` for
+    /// snapshots saved before the flag existed; `has_target` then derives it from `integration`.
+    #[serde(default)]
+    has_target: Option<bool>,
 }
 
 impl FlowField {
@@ -43,7 +95,107 @@ impl FlowField {
             costs: vec![1; size],
             integration: vec![f64::MAX; size],
             vectors: vec![DVec2::ZERO; size],
+            heights: Vec::new(),
+            slope_penalty: 0.0,
+            forces: Vec::new(),
+            soft_costs: Vec::new(),
+            dynamic_costs: Vec::new(),
+            terrain: Vec::new(),
+            terrain_mask: GROUND_MASK,
+            reset_touched_only: false,
+            touched: None,
+            has_target: Some(false),
+        }
+    }
+
+    /// Like `new`, but refuses grids with more than `max_cells` cells instead of
+    /// attempting a huge allocation (e.g. a bad `100000 x 100000` coming from JS).
+    pub fn try_new(width: usize, height: usize, max_cells: usize) -> Result<Self, String> {
+        match width.checked_mul(height) {
+            Some(size) if size <= max_cells => Ok(Self::new(width, height)),
+            _ => Err(format!(
+                "FlowField of {}x{} exceeds the limit of {} cells",
+                width, height, max_cells
+            )),
+        }
+    }
+
+    /// Replaces the soft cost overlay (one value per cell, or empty to disable).
+    /// Takes effect on the next `generate_target`. Wrongly sized overlays, and ones with
+    /// NaN/infinite costs, are ignored.
+    pub fn set_soft_costs(&mut self, soft_costs: Vec<f64>) {
+        if (soft_costs.is_empty() || soft_costs.len() == self.width * self.height) && soft_costs.iter().all(|c| c.is_finite()) {
+            self.soft_costs = soft_costs;
+        }
+    }
+
+    /// Sets the terrain height of a tile. The height layer is allocated on first use.
+    /// NaN/infinite heights are ignored.
+    pub fn set_height(&mut self, x: usize, y: usize, height: f64) {
+        if x < self.width && y < self.height && height.is_finite() {
+            if self.heights.is_empty() {
+                self.heights = vec![0.0; self.width * self.height];
+            }
+            self.heights[y * self.width + x] = height;
+        }
+    }
+
+    /// Sets how much climbing costs. With a high enough penalty, paths take
+    /// flatter detours around hills instead of going over them. NaN/infinite penalties
+    /// are ignored.
+    pub fn set_slope_penalty(&mut self, penalty: f64) {
+        if penalty.is_finite() {
+            self.slope_penalty = penalty;
+        }
+    }
+
+    /// Extra cost for moving from tile `from` to tile `to` (only climbing is penalized).
+    fn slope_cost(&self, from: usize, to: usize) -> f64 {
+        if self.heights.is_empty() || self.slope_penalty == 0.0 {
+            return 0.0;
+        }
+        (self.heights[to] - self.heights[from]).max(0.0) * self.slope_penalty
+    }
+
+    /// Speed multiplier (0..1] for moving in `dir` from the world coordinate,
+    /// so agents slow down while climbing. Always 1 on flat maps.
+    pub fn slope_speed_factor(&self, x: f64, y: f64, dir: DVec2) -> f64 {
+        let ix = x.round() as isize;
+        let iy = y.round() as isize;
+        let nx = ix + dir.x.round() as isize;
+        let ny = iy + dir.y.round() as isize;
+
+        if !self.in_bounds(ix, iy) || !self.in_bounds(nx, ny) {
+            return 1.0;
+        }
+
+        let from = iy as usize * self.width + ix as usize;
+        let to = ny as usize * self.width + nx as usize;
+        1.0 / (1.0 + self.slope_cost(from, to))
+    }
+
+    /// Sets the environmental force on a tile. Agents on it drift by (fx, fy) per tick
+    /// regardless of their own steering. The force layer is allocated on first use.
+    /// Non-finite forces are ignored, like out-of-bounds tiles.
+    pub fn set_force(&mut self, x: usize, y: usize, fx: f64, fy: f64) {
+        if x < self.width && y < self.height && fx.is_finite() && fy.is_finite() {
+            if self.forces.is_empty() {
+                self.forces = vec![DVec2::ZERO; self.width * self.height];
+            }
+            self.forces[y * self.width + x] = DVec2::new(fx, fy);
+        }
+    }
+
+    /// Samples the environmental force at a world coordinate (ZERO if none or out of bounds).
+    pub fn get_force(&self, x: f64, y: f64) -> DVec2 {
+        let ix = x.round() as isize;
+        let iy = y.round() as isize;
+
+        if self.forces.is_empty() || !self.in_bounds(ix, iy) {
+            return DVec2::ZERO;
         }
+
+        self.forces[iy as usize * self.width + ix as usize]
     }
 
     /// Sets a tile as an obstacle (Wall) or walkable.
@@ -54,27 +206,198 @@ impl FlowField {
         }
     }
 
+    /// Sets a tile's dynamic cost (255 = blocked, 0 = no extra cost). The layer is allocated
+    /// on first use. Takes effect on the next `generate_*` call; the static `costs` are untouched.
+    pub fn set_dynamic_cost(&mut self, x: usize, y: usize, cost: u8) {
+        if x < self.width && y < self.height {
+            if self.dynamic_costs.is_empty() {
+                self.dynamic_costs = vec![0; self.width * self.height];
+            }
+            self.dynamic_costs[y * self.width + x] = cost;
+        }
+    }
+
+    /// Clears every dynamic cost (e.g. before restamping blockers each tick).
+    pub fn clear_dynamic_costs(&mut self) {
+        self.dynamic_costs.fill(0);
+    }
+
+    /// Sets a tile's terrain type (0 = ground, 1 = water; below 32). The terrain layer is allocated on first use.
+    pub fn set_terrain(&mut self, x: usize, y: usize, terrain: u8) {
+        if x < self.width && y < self.height {
+            if self.terrain.is_empty() {
+                self.terrain = vec![TERRAIN_GROUND; self.width * self.height];
+            }
+            self.terrain[y * self.width + x] = terrain;
+        }
+    }
+
+    /// Cost of a tile as seen by integration: the higher of the static and dynamic layers,
+    /// or 255 (wall) if its terrain isn't in `terrain_mask`.
+    fn tile_cost(&self, idx: usize) -> u8 {
+        let terrain = self.terrain.get(idx).copied().unwrap_or(TERRAIN_GROUND);
+        if (self.terrain_mask.checked_shr(terrain as u32).unwrap_or(0) & 1) == 0 {
+            return 255;
+        }
+        match self.dynamic_costs.get(idx) {
+            Some(&dynamic) => self.costs[idx].max(dynamic),
+            None => self.costs[idx],
+        }
+    }
+
+    /// Makes every tile walkable again (e.g. resetting the map between rounds), keeping
+    /// the dimensions. Like `set_obstacle`, takes effect on the next `generate_*` call.
+    pub fn clear_obstacles(&mut self) {
+        self.costs.fill(1);
+    }
+
     /// Generates the Integration Field (Dijkstra) and then the Vector Field.
     /// This is called whenever the target changes or the map changes.
-    pub fn generate_target(&mut self, target_x: f64, target_y: f64) {
-        let tx = target_x.round() as usize;
-        let ty = target_y.round() as usize;
+    /// Fails (leaving the field unchanged) if the target is NaN/infinite or off the map.
+    pub fn generate_target(&mut self, target_x: f64, target_y: f64) -> Result<(), String> {
+        let target = self.target_cell(target_x, target_y)?;
+
+        // 1. Build the Integration Field from the target
+        self.integrate(target);
+
+        // 2. Generate Vector Field based on new integration costs.
+        // Ties lean toward the exact target position so open areas flow radially.
+        self.generate_vectors_toward(Some(DVec2::new(target_x, target_y)));
+        Ok(())
+    }
+
+    /// Cell index of a world target. Checked before casting: `as usize` would turn
+    /// NaN and negative coordinates into cell 0 instead of rejecting them.
+    pub(crate) fn target_cell(&self, x: f64, y: f64) -> Result<usize, String> {
+        if !x.is_finite() || !y.is_finite() {
+            return Err(format!("Invalid target ({}, {})", x, y));
+        }
+        let (tx, ty) = (x.round(), y.round());
+        if tx < 0.0 || ty < 0.0 || tx >= self.width as f64 || ty >= self.height as f64 {
+            return Err(format!("Target ({}, {}) is outside the {}x{} field", x, y, self.width, self.height));
+        }
+        Ok(ty as usize * self.width + tx as usize)
+    }
+
+    /// Multi-target version of `generate_target`: every cell flows toward its nearest target.
+    /// Invalid targets (NaN/infinite, off the map) are ignored; if none are valid the field
+    /// is left unchanged.
+    pub fn generate_targets(&mut self, targets: &[(f64, f64)]) {
+        let seeds: Vec<usize> = targets
+            .iter()
+            .filter_map(|&(x, y)| self.target_cell(x, y).ok())
+            .collect();
+
+        if seeds.is_empty() {
+            return;
+        }
 
-        // Bounds check
-        if tx >= self.width || ty >= self.height { 
-            return; 
+        self.integrate_from(&seeds);
+        self.generate_vectors();
+    }
+
+    /// Combines another field into this one by taking the element-wise minimum of the
+    /// Integration Fields, so agents route to whichever objective is closer.
+    /// Both fields must have the same dimensions.
+    pub fn merge_min(&mut self, other: &FlowField) -> Result<(), String> {
+        if self.width != other.width || self.height != other.height {
+            return Err(format!(
+                "FlowField size mismatch: {}x{} vs {}x{}",
+                self.width, self.height, other.width, other.height
+            ));
+        }
+
+        for (mine, &theirs) in self.integration.iter_mut().zip(&other.integration) {
+            *mine = mine.min(theirs);
+        }
+        // Cells reached only by `other` aren't tracked
+        self.touched = None;
+        self.has_target = Some(self.has_target() || other.has_target());
+
+        self.generate_vectors();
+        Ok(())
+    }
+
+    /// Generates a "Flee" field: vectors point away from the feared point instead of toward it.
+    /// Cells whose distance to the threat is `max_cost` or more are considered safe and get no vector.
+    pub fn generate_flee_target(&mut self, x: f64, y: f64, max_cost: f64) {
+        // Bounds check (also rejects NaN / negative threats)
+        let Ok(threat) = self.target_cell(x, y) else {
+            return;
+        };
+
+        // 1. Distance from the threat (regular Dijkstra)
+        self.integrate(threat);
+        let threat_dist = self.integration.clone();
+
+        // 2. Invert the field so the threat becomes a peak.
+        // Scaling by more than 1 and relaxing again lets cells inside dead-end corners
+        // "see" a cheaper escape route past the threat instead of getting cornered.
+        let mut heap = BinaryHeap::new();
+        for (idx, &dist) in threat_dist.iter().enumerate() {
+            if dist == f64::MAX { continue; } // Wall or unreachable
+
+            let inverted = -FLEE_COEFFICIENT * dist.min(max_cost);
+            self.integration[idx] = inverted;
+            heap.push(State { cost: inverted, index: idx });
         }
+        self.relax(&mut heap);
+
+        // 3. Downhill on the inverted field is uphill (away) from the threat
+        self.generate_vectors();
 
+        // 4. Agents outside the danger radius stop fleeing
+        for (idx, &dist) in threat_dist.iter().enumerate() {
+            if dist >= max_cost {
+                self.vectors[idx] = DVec2::ZERO;
+            }
+        }
+    }
+
+    /// Resets the Integration Field and runs Dijkstra outward from `target_idx`.
+    fn integrate(&mut self, target_idx: usize) {
+        self.integrate_from(&[target_idx]);
+    }
+
+    /// Resets the Integration Field and runs Dijkstra outward from every seed cell at once.
+    fn integrate_from(&mut self, seeds: &[usize]) {
         // 1. Reset Integration Field
-        self.integration.fill(f64::MAX);
-        
-        let target_idx = ty * self.width + tx;
-        self.integration[target_idx] = 0.0;
+        match self.touched.take() {
+            Some(mut touched) if self.reset_touched_only => {
+                for &idx in &touched {
+                    self.integration[idx] = f64::MAX;
+                }
+                touched.clear();
+                self.touched = Some(touched);
+            }
+            _ => {
+                self.integration.fill(f64::MAX);
+                self.touched = self.reset_touched_only.then(Vec::new);
+            }
+        }
 
         // 2. Dijkstra's Algorithm
+        self.has_target = Some(!seeds.is_empty());
         let mut heap = BinaryHeap::new();
-        heap.push(State { cost: 0.0, index: target_idx });
+        for &idx in seeds {
+            self.touch(idx);
+            self.integration[idx] = 0.0;
+            heap.push(State { cost: 0.0, index: idx });
+        }
+        self.relax(&mut heap);
+    }
+
+    /// Records a cell about to get its first finite cost since the last reset.
+    fn touch(&mut self, idx: usize) {
+        if self.integration[idx] == f64::MAX {
+            if let Some(touched) = &mut self.touched {
+                touched.push(idx);
+            }
+        }
+    }
 
+    /// Dijkstra relaxation over the current Integration Field, seeded by `heap`.
+    fn relax(&mut self, heap: &mut BinaryHeap<State>) {
         // 4-way connectivity (Up, Down, Left, Right)
         let neighbors = [(0, 1), (1, 0), (0, -1), (-1, 0)];
 
@@ -86,17 +409,20 @@ impl FlowField {
             let cy = index / self.width;
 
             for (dx, dy) in neighbors.iter() {
-                let nx = (cx as isize + dx) as usize;
-                let ny = (cy as isize + dy) as usize;
+                let nx = cx as isize + dx;
+                let ny = cy as isize + dy;
 
-                if nx < self.width && ny < self.height {
-                    let n_idx = ny * self.width + nx;
-                    let tile_cost = self.costs[n_idx];
+                if self.in_bounds(nx, ny) {
+                    let n_idx = ny as usize * self.width + nx as usize;
+                    let tile_cost = self.tile_cost(n_idx);
                     
                     // If walkable
                     if tile_cost < 255 {
-                        let next_cost = cost + tile_cost as f64;
+                        // Integration runs outward from the target, so agents travel n_idx -> index.
+                        let soft_cost = self.soft_costs.get(n_idx).copied().unwrap_or(0.0);
+                        let next_cost = cost + tile_cost as f64 + soft_cost + self.slope_cost(n_idx, index);
                         if next_cost < self.integration[n_idx] {
+                            self.touch(n_idx);
                             self.integration[n_idx] = next_cost;
                             heap.push(State { cost: next_cost, index: n_idx });
                         }
@@ -104,26 +430,34 @@ impl FlowField {
                 }
             }
         }
-
-        // 3. Generate Vector Field based on new integration costs
-        self.generate_vectors();
     }
 
     /// Calculates gradients: Units look at neighbors and move toward the one 
     /// with the lowest integration cost (closest to target).
     fn generate_vectors(&mut self) {
+        self.generate_vectors_toward(None);
+    }
+
+    /// Same as `generate_vectors`, but when several neighbors share the lowest cost,
+    /// picks the one pointing most directly at `aim` instead of the first one scanned
+    /// (which bands open fields into diagonal stripes). `None` keeps the scan order.
+    fn generate_vectors_toward(&mut self, aim: Option<DVec2>) {
         for y in 0..self.height {
             for x in 0..self.width {
                 let idx = y * self.width + x;
                 
-                // If this tile is a wall, it has no vector
-                if self.costs[idx] == 255 { 
+                // If this tile is a wall (static or dynamic), it has no vector
+                if self.tile_cost(idx) == 255 { 
                     self.vectors[idx] = DVec2::ZERO;
                     continue; 
                 }
 
                 let mut best_cost = self.integration[idx];
                 let mut grad = DVec2::ZERO;
+                let mut best_align = f64::NEG_INFINITY;
+                let to_aim = aim
+                    .map(|a| (a - DVec2::new(x as f64, y as f64)).normalize_or_zero())
+                    .unwrap_or(DVec2::ZERO);
 
                 // Check 4 neighbors to find the "downhill" slope
                 let neighbors = [
@@ -134,17 +468,22 @@ impl FlowField {
                 ];
 
                 for (dx, dy, dir) in neighbors {
-                    let nx = (x as isize + dx) as usize;
-                    let ny = (y as isize + dy) as usize;
+                    let nx = x as isize + dx;
+                    let ny = y as isize + dy;
 
-                    if nx < self.width && ny < self.height {
-                        let n_idx = ny * self.width + nx;
-                        let n_cost = self.integration[n_idx];
+                    if self.in_bounds(nx, ny) {
+                        let n_idx = ny as usize * self.width + nx as usize;
+                        // Climbing onto the neighbor counts too, otherwise agents walk
+                        // straight up a plateau whose top is cheap to cross.
+                        let n_cost = self.integration[n_idx] + self.slope_cost(idx, n_idx);
                         
-                        // If neighbor is closer to target, point that way
-                        if n_cost < best_cost {
+                        // If neighbor is closer to target, point that way.
+                        // On an exact tie, keep whichever points closer at the aim.
+                        let align = dir.dot(to_aim);
+                        if n_cost < best_cost || (n_cost == best_cost && grad != DVec2::ZERO && align > best_align) {
                             best_cost = n_cost;
                             grad = dir;
+                            best_align = align;
                         }
                     }
                 }
@@ -155,15 +494,630 @@ impl FlowField {
         }
     }
 
+    /// Signed bounds check for grid coordinates.
+    /// Neighbor offsets can go negative at the left/top edges, so they must be
+    /// rejected here rather than relying on `usize` wraparound.
+    pub fn in_bounds(&self, x: isize, y: isize) -> bool {
+        x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height
+    }
+
+    /// Reads a single cell as `(cost, integration, vector)`, or `None` if out of bounds.
+    pub fn cell(&self, x: usize, y: usize) -> Option<(u8, f64, DVec2)> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+
+        let idx = y * self.width + x;
+        Some((self.costs[idx], self.integration[idx], self.vectors[idx]))
+    }
+
+    /// Returns true if the world coordinate lies on a target cell (integration cost 0).
+    pub fn is_target(&self, x: f64, y: f64) -> bool {
+        let ix = x.round() as isize;
+        let iy = y.round() as isize;
+
+        self.in_bounds(ix, iy) && self.integration[iy as usize * self.width + ix as usize] == 0.0
+    }
+
+    /// Returns true once the field has been generated with a target (or merged with one that
+    /// was). Tracked by the `generate_*` calls, so it's cheap to ask every tick; fields loaded
+    /// from older snapshots without the flag count as targeted if any cell has a finite cost.
+    pub fn has_target(&self) -> bool {
+        self.has_target.unwrap_or_else(|| self.integration.iter().any(|&c| c < f64::MAX))
+    }
+
+    /// Returns true if a target is reachable from the world coordinate, i.e. the field
+    /// has been generated and this cell got a finite integration cost.
+    pub fn has_route(&self, x: f64, y: f64) -> bool {
+        let ix = x.round() as isize;
+        let iy = y.round() as isize;
+
+        self.in_bounds(ix, iy) && self.integration[iy as usize * self.width + ix as usize] != f64::MAX
+    }
+
+    /// Returns true if the world coordinate lies on an in-bounds, non-wall cell.
+    pub fn is_walkable_at(&self, x: f64, y: f64) -> bool {
+        let ix = x.round() as isize;
+        let iy = y.round() as isize;
+
+        self.in_bounds(ix, iy) && self.costs[iy as usize * self.width + ix as usize] != 255
+    }
+
+    /// Center of the walkable cell closest to the world coordinate (lowest index on ties),
+    /// or `None` if every cell is a wall. Scans the whole grid, so keep it off hot paths.
+    pub fn nearest_walkable(&self, x: f64, y: f64) -> Option<DVec2> {
+        let p = DVec2::new(x, y);
+        let mut best: Option<(f64, DVec2)> = None;
+        for (idx, _) in self.costs.iter().enumerate().filter(|(_, &c)| c != 255) {
+            let center = DVec2::new((idx % self.width) as f64, (idx / self.width) as f64);
+            let dist = p.distance_squared(center);
+            if best.is_none_or(|(d, _)| dist < d) {
+                best = Some((dist, center));
+            }
+        }
+        best.map(|(_, center)| center)
+    }
+
+    /// Remaining integration cost to the target from the world coordinate,
+    /// or `None` if it is out of bounds, has no route, or isn't a finite non-negative coordinate.
+    pub fn cost_to_target(&self, x: f64, y: f64) -> Option<f64> {
+        // NaN would cast to cell 0 and small negatives round onto row/column 0
+        if !(x.is_finite() && y.is_finite() && x >= 0.0 && y >= 0.0) {
+            return None;
+        }
+        let ix = x.round() as isize;
+        let iy = y.round() as isize;
+
+        if !self.in_bounds(ix, iy) {
+            return None;
+        }
+        let cost = self.integration[iy as usize * self.width + ix as usize];
+        (cost != f64::MAX).then_some(cost)
+    }
+
+    /// Batched `cost_to_target` (e.g. AI ranking many candidate spots in one WASM call).
+    /// Walls, unreachable, out-of-bounds and non-finite points get `f64::MAX`.
+    pub fn cost_at_cells(&self, points: &[(f64, f64)]) -> Vec<f64> {
+        points
+            .iter()
+            .map(|&(x, y)| self.cost_to_target(x, y).unwrap_or(f64::MAX))
+            .collect()
+    }
+
+    /// Debug overlay: per cell, how steeply the integration field drops toward the target,
+    /// i.e. its cost minus the cheapest of its 4 neighbors. 0 for walls, target cells and
+    /// cells without a route. On a uniform map this is the tile cost everywhere; it rises
+    /// where paths pay extra to get through (slopes, soft or dynamic costs). For geometric
+    /// chokepoints, see `flow_accumulation`.
+    pub fn gradient_magnitude(&self) -> Vec<f64> {
+        let neighbors = [(0, -1), (1, 0), (0, 1), (-1, 0)];
+
+        (0..self.width * self.height)
+            .map(|idx| {
+                let cost = self.integration[idx];
+                if self.tile_cost(idx) == 255 || cost == f64::MAX {
+                    return 0.0;
+                }
+                let (x, y) = ((idx % self.width) as isize, (idx / self.width) as isize);
+                let best = neighbors
+                    .iter()
+                    .filter(|&&(dx, dy)| self.in_bounds(x + dx, y + dy))
+                    .map(|&(dx, dy)| self.integration[(y + dy) as usize * self.width + (x + dx) as usize])
+                    .fold(f64::MAX, f64::min);
+                (cost - best).max(0.0)
+            })
+            .collect()
+    }
+
+    /// Debug overlay for chokepoints: per cell, how many cells' flow routes pass through it
+    /// (itself included), following the vectors down to the target. Routes funnelling through
+    /// a gap all count on the gap's cells, so they stand out against open ground even on a
+    /// uniform map. 0 for walls and cells without a route.
+    pub fn flow_accumulation(&self) -> Vec<u32> {
+        let size = self.width * self.height;
+        let downstream = |idx: usize| -> Option<usize> {
+            let v = self.vectors[idx];
+            if v == DVec2::ZERO {
+                return None;
+            }
+            let (x, y) = ((idx % self.width) as isize + v.x.round() as isize, (idx / self.width) as isize + v.y.round() as isize);
+            self.in_bounds(x, y).then(|| y as usize * self.width + x as usize)
+        };
+
+        let mut counts: Vec<u32> = (0..size)
+            .map(|idx| u32::from(self.tile_cost(idx) != 255 && self.integration[idx] != f64::MAX))
+            .collect();
+        // Vectors always point to a strictly cheaper cell, so handing counts down from the most
+        // expensive cells first visits every cell after all of its upstream cells
+        let mut order: Vec<usize> = (0..size).filter(|&idx| counts[idx] > 0).collect();
+        order.sort_by(|&a, &b| self.integration[b].total_cmp(&self.integration[a]).then(a.cmp(&b)));
+        for idx in order {
+            if let Some(next) = downstream(idx) {
+                counts[next] += counts[idx];
+            }
+        }
+        counts
+    }
+
     /// Helper to sample the flow field at a specific world coordinate.
     pub fn get_direction(&self, x: f64, y: f64) -> DVec2 {
-        let ix = x.round() as usize;
-        let iy = y.round() as usize;
+        let ix = x.round() as isize;
+        let iy = y.round() as isize;
         
-        if ix >= self.width || iy >= self.height { 
+        if !self.in_bounds(ix, iy) { 
             return DVec2::ZERO; 
         }
         
-        self.vectors[iy * self.width + ix]
+        self.vectors[iy as usize * self.width + ix as usize]
+    }
+
+    /// Per-agent version of `get_direction` that splits crowds on equal-cost ridges: where
+    /// stepping along the cell's vector and against it cost the same (integration plus slope
+    /// cost, as `generate_vectors` weighs them), e.g. in front of an obstacle with equally
+    /// short ways around both sides, `salt` (e.g. the agent id) picks the side, so a crowd
+    /// divides about evenly instead of all taking the side the scan found first.
+    pub fn get_direction_split(&self, x: f64, y: f64, salt: u32) -> DVec2 {
+        let dir = self.get_direction(x, y);
+        if dir == DVec2::ZERO {
+            return dir;
+        }
+
+        let (ix, iy) = (x.round() as isize, y.round() as isize);
+        let (ox, oy) = (ix - dir.x as isize, iy - dir.y as isize);
+        if !self.in_bounds(ox, oy) {
+            return dir;
+        }
+        let idx = iy as usize * self.width + ix as usize;
+        let cost = |n: usize| self.integration[n] + self.slope_cost(idx, n);
+        let ahead = cost((iy + dir.y as isize) as usize * self.width + (ix + dir.x as isize) as usize);
+        let behind = cost(oy as usize * self.width + ox as usize);
+        // Top bit of a multiplicative hash: ids split about evenly whatever their spacing
+        if behind == ahead && salt.wrapping_mul(0x9E37_79B9) >> 31 == 1 {
+            -dir
+        } else {
+            dir
+        }
+    }
+
+    /// Smooth version of `get_direction`: blends the vectors of the four cells around the
+    /// point (cell centers at integer coordinates) and returns the normalized result.
+    /// Walls and off-map cells get zero weight and the rest are renormalized, so a wall
+    /// never bends the direction toward itself. ZERO if all four are walls; the point's own
+    /// cell vector if that is the only walkable one.
+    pub fn get_direction_bilinear(&self, x: f64, y: f64) -> DVec2 {
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+
+        let corners = [
+            (x0, y0, (1.0 - fx) * (1.0 - fy)),
+            (x0 + 1, y0, fx * (1.0 - fy)),
+            (x0, y0 + 1, (1.0 - fx) * fy),
+            (x0 + 1, y0 + 1, fx * fy),
+        ];
+        let walkable: Vec<(usize, f64)> = corners
+            .iter()
+            .filter(|&&(cx, cy, _)| self.in_bounds(cx, cy))
+            .map(|&(cx, cy, weight)| (cy as usize * self.width + cx as usize, weight))
+            .filter(|&(idx, _)| self.tile_cost(idx) != 255)
+            .collect();
+
+        match walkable.as_slice() {
+            [] => DVec2::ZERO,
+            [(idx, _)] => self.vectors[*idx],
+            cells => {
+                let total: f64 = cells.iter().map(|&(_, weight)| weight).sum();
+                if total <= 0.0 {
+                    // Exactly on a wall's edge: only zero-weight cells are walkable
+                    return self.get_direction(x, y);
+                }
+                let blended: DVec2 = cells.iter().map(|&(idx, weight)| self.vectors[idx] * (weight / total)).sum();
+                blended.normalize_or_zero()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flee_vectors_point_away_from_the_threat() {
+        let mut field = FlowField::new(21, 21);
+        field.generate_flee_target(10.0, 10.0, 8.0);
+
+        let threat = DVec2::new(10.0, 10.0);
+        for (x, y) in [(12, 10), (8, 10), (10, 13), (10, 7), (12, 12), (9, 8)] {
+            let cell = DVec2::new(x as f64, y as f64);
+            let dir = field.get_direction(cell.x, cell.y);
+            assert!((cell + dir).distance(threat) > cell.distance(threat), "({}, {}) flees along {:?}", x, y, dir);
+        }
+        // Beyond the danger radius agents stop fleeing
+        assert_eq!(field.get_direction(20.0, 20.0), DVec2::ZERO);
+    }
+
+    #[test]
+    fn flee_escapes_dead_end_corners_past_the_threat() {
+        // Dead end along the top edge, left of the threat: the long way out runs past it
+        let mut field = FlowField::new(40, 3);
+        for x in 0..6 {
+            field.set_obstacle(x, 1, true);
+        }
+        field.generate_flee_target(4.0, 0.0, 40.0);
+        assert_eq!(field.get_direction(1.0, 0.0), DVec2::X);
+    }
+
+    #[test]
+    fn in_bounds_rejects_negative_and_past_the_edge() {
+        let field = FlowField::new(4, 3);
+        assert!(field.in_bounds(0, 0) && field.in_bounds(3, 2));
+        for (x, y) in [(-1, 0), (0, -1), (-1, -1), (4, 0), (0, 3), (isize::MIN, 0)] {
+            assert!(!field.in_bounds(x, y), "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn edge_cells_never_point_off_the_map() {
+        // Targets in the top-left corner and along the left/top edges probe the negative neighbors
+        for target in [(0.0, 0.0), (0.0, 3.0), (4.0, 0.0)] {
+            let mut field = FlowField::new(6, 5);
+            field.generate_target(target.0, target.1).unwrap();
+            for y in 0..5 {
+                for x in 0..6 {
+                    let next = DVec2::new(x as f64, y as f64) + field.get_direction(x as f64, y as f64);
+                    assert!(field.in_bounds(next.x as isize, next.y as isize), "{:?} -> {:?}", (x, y), next);
+                    assert!(field.cost_to_target(x as f64, y as f64).is_some());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn merge_min_matches_a_two_target_field() {
+        let mut a = FlowField::new(30, 20);
+        let mut b = FlowField::new(30, 20);
+        let mut both = FlowField::new(30, 20);
+        for field in [&mut a, &mut b, &mut both] {
+            for y in 3..17 {
+                field.set_obstacle(15, y, true);
+            }
+        }
+        a.generate_targets(&[(5.0, 10.0)]);
+        b.generate_targets(&[(25.0, 4.0)]);
+        both.generate_targets(&[(5.0, 10.0), (25.0, 4.0)]);
+
+        a.merge_min(&b).unwrap();
+        assert_eq!(a.integration, both.integration);
+        assert_eq!(a.vectors, both.vectors);
+
+        assert!(a.merge_min(&FlowField::new(10, 10)).is_err());
+    }
+
+    #[test]
+    fn cell_reads_the_target_and_rejects_out_of_bounds() {
+        let mut field = FlowField::new(8, 8);
+        field.generate_target(3.0, 4.0).unwrap();
+        assert_eq!(field.cell(3, 4), Some((1, 0.0, DVec2::ZERO)));
+
+        let (_, integration, vector) = field.cell(5, 4).unwrap();
+        assert!(integration > 0.0);
+        assert_eq!(vector, -DVec2::X);
+        assert_eq!(field.cell(8, 0), None);
+        assert_eq!(field.cell(0, 8), None);
+    }
+
+    /// Cells visited following the field from (x, y) until it stops pointing anywhere.
+    fn trace(field: &FlowField, x: f64, y: f64) -> Vec<(i32, i32)> {
+        let mut pos = DVec2::new(x, y);
+        let mut cells = vec![(x as i32, y as i32)];
+        for _ in 0..field.width * field.height {
+            let dir = field.get_direction(pos.x, pos.y);
+            if dir == DVec2::ZERO {
+                break;
+            }
+            pos += dir;
+            cells.push((pos.x.round() as i32, pos.y.round() as i32));
+        }
+        cells
+    }
+
+    #[test]
+    fn steep_slopes_route_around_the_hill() {
+        // Plateau of height 5 between (0, 3) and the target at (8, 3), flat rows above and below
+        let mut field = FlowField::new(9, 7);
+        for x in 3..=5 {
+            for y in 1..=5 {
+                field.set_height(x, y, 5.0);
+            }
+        }
+        let on_hill = |&(x, y): &(i32, i32)| (3..=5).contains(&x) && (1..=5).contains(&y);
+
+        field.generate_target(8.0, 3.0).unwrap();
+        let over = trace(&field, 0.0, 3.0);
+        assert!(over.iter().any(on_hill));
+
+        field.set_slope_penalty(10.0);
+        field.generate_target(8.0, 3.0).unwrap();
+        let around = trace(&field, 0.0, 3.0);
+        assert_eq!(around.last(), Some(&(8, 3)));
+        assert!(!around.iter().any(on_hill), "climbed the hill: {:?}", around);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn open_field_ties_point_radially_at_the_target() {
+        let mut field = FlowField::new(41, 41);
+        field.generate_target(20.0, 20.0).unwrap();
+
+        for y in 0..41 {
+            for x in 0..41 {
+                if (x, y) == (20, 20) {
+                    continue;
+                }
+                let to_target = (DVec2::new(20.0, 20.0) - DVec2::new(x as f64, y as f64)).normalize();
+                let dir = field.get_direction(x as f64, y as f64);
+                // 4-way vectors are at most 45 degrees off the true bearing
+                assert!(dir.dot(to_target) >= std::f64::consts::FRAC_1_SQRT_2 - 1e-9, "({}, {}) points {:?}", x, y, dir);
+            }
+        }
+    }
+
+    #[test]
+    fn try_new_rejects_oversized_grids() {
+        assert!(FlowField::try_new(100_000, 100_000, 1 << 24).is_err());
+        assert!(FlowField::try_new(usize::MAX, 2, usize::MAX).is_err());
+
+        let field = FlowField::try_new(200, 100, 1 << 24).unwrap();
+        assert_eq!((field.width, field.height), (200, 100));
+    }
+
+    #[test]
+    fn clear_obstacles_reopens_every_tile() {
+        let mut field = FlowField::new(12, 9);
+        for y in 0..9 {
+            for x in (0..12).filter(|x| (x + y) % 3 != 0) {
+                field.set_obstacle(x, y, true);
+            }
+        }
+        field.clear_obstacles();
+        assert_eq!((field.width, field.height, field.costs.len()), (12, 9, 12 * 9));
+        assert!(field.costs.iter().all(|&c| c == 1));
+
+        // The next generation sees the open map: the far corner is reachable again
+        field.generate_target(0.0, 0.0).unwrap();
+        assert!(field.integration[field.costs.len() - 1] < f64::MAX);
+    }
+
+    #[test]
+    fn cost_to_target_rejects_nan_and_negative_coordinates() {
+        let mut field = FlowField::new(5, 5);
+        field.generate_target(2.0, 2.0).unwrap();
+        assert_eq!(field.cost_to_target(0.0, 0.0), Some(field.integration[0]));
+        for (x, y) in [(f64::NAN, 1.0), (1.0, f64::NAN), (f64::INFINITY, 1.0), (-0.3, 1.0), (1.0, -0.3), (-1.0, -1.0)] {
+            assert_eq!(field.cost_to_target(x, y), None, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn dynamic_blockers_come_and_go_without_touching_walls() {
+        // Column 2 is walled except for a gap at the top
+        let mut field = FlowField::new(5, 3);
+        field.set_obstacle(2, 0, true);
+        field.set_obstacle(2, 1, true);
+        let walls = field.costs.clone();
+        field.generate_target(4.0, 0.0).unwrap();
+        assert!(field.integration[0] < f64::MAX);
+
+        field.set_dynamic_cost(2, 2, 255);
+        field.generate_target(4.0, 0.0).unwrap();
+        assert_eq!(field.integration[0], f64::MAX, "blocker didn't close the gap");
+        assert_eq!(field.costs, walls);
+
+        field.clear_dynamic_costs();
+        field.generate_target(4.0, 0.0).unwrap();
+        assert!(field.integration[0] < f64::MAX);
+        assert_eq!(field.costs, walls);
+        assert_eq!(field.integration[7], f64::MAX, "static wall at (2, 1) went missing");
+    }
+
+    #[test]
+    fn bilinear_directions_never_lead_into_an_l_shaped_wall() {
+        // L: a column at x = 5 (y = 2..=6) and a row at y = 6 (x = 5..=8)
+        let mut field = FlowField::new(12, 12);
+        for y in 2..=6 {
+            field.set_obstacle(5, y, true);
+        }
+        for x in 6..=8 {
+            field.set_obstacle(x, 6, true);
+        }
+        field.generate_target(2.0, 9.0).unwrap();
+
+        // Sample a fine lattice around the wall (its inner corner included)
+        let mut checked = 0;
+        for i in 0..=60 {
+            for j in 0..=60 {
+                let p = DVec2::new(3.0 + i as f64 * 0.1, 0.5 + j as f64 * 0.1);
+                if !field.is_walkable_at(p.x, p.y) {
+                    continue;
+                }
+                let dir = field.get_direction_bilinear(p.x, p.y);
+                let ahead = p + dir * 0.45;
+                assert!(field.is_walkable_at(ahead.x, ahead.y), "at {:?} the blend {:?} points into the wall", p, dir);
+                checked += 1;
+            }
+        }
+        assert!(checked > 2000);
+
+        // Own cell walkable, every other corner a wall: the cell's own vector
+        let mut pocket = FlowField::new(4, 4);
+        for (x, y) in [(2, 1), (1, 2), (2, 2)] {
+            pocket.set_obstacle(x, y, true);
+        }
+        pocket.generate_target(0.0, 0.0).unwrap();
+        let own = pocket.vectors[pocket.width + 1];
+        assert_ne!(own, DVec2::ZERO);
+        assert_eq!(pocket.get_direction_bilinear(1.3, 1.3), own);
+        // ...and ZERO with all four corners walled
+        pocket.set_obstacle(1, 1, true);
+        pocket.generate_target(0.0, 0.0).unwrap();
+        assert_eq!(pocket.get_direction_bilinear(1.3, 1.3), DVec2::ZERO);
+    }
+
+    #[test]
+    fn has_target_tracks_generation_and_merges() {
+        let mut field = FlowField::new(6, 6);
+        assert!(!field.has_target());
+        assert!(field.generate_target(f64::NAN, 1.0).is_err());
+        field.generate_targets(&[(-5.0, 1.0), (40.0, 1.0)]);
+        assert!(!field.has_target(), "rejected targets set the flag");
+
+        let mut targeted = FlowField::new(6, 6);
+        targeted.generate_target(1.0, 1.0).unwrap();
+        assert!(targeted.has_target());
+        field.merge_min(&targeted).unwrap();
+        assert!(field.has_target());
+
+        let mut fleeing = FlowField::new(6, 6);
+        fleeing.generate_flee_target(3.0, 3.0, 4.0);
+        assert!(fleeing.has_target());
+    }
+
+    #[test]
+    fn flow_accumulation_peaks_at_a_chokepoint() {
+        // 11x11 room split by a wall at x = 5 with a one-cell gap at y = 5; target on the right
+        let mut field = FlowField::new(11, 11);
+        for y in (0..11).filter(|&y| y != 5) {
+            field.set_obstacle(5, y, true);
+        }
+        field.generate_target(9.0, 5.0).unwrap();
+        let counts = field.flow_accumulation();
+        let at = |x: usize, y: usize| counts[y * 11 + x];
+
+        // Every walkable cell drains into the target; the whole left half squeezes through the gap
+        assert_eq!(at(9, 5), 11 * 11 - 10);
+        assert_eq!(at(5, 5), 5 * 11 + 1);
+        assert_eq!(at(5, 0), 0);
+        // Open ground on the same side as the gap carries far less
+        let open_max = (0..11).filter(|&y| y != 5).map(|y| at(7, y)).max().unwrap();
+        assert!(at(5, 5) > 4 * open_max, "gap {} vs open {}", at(5, 5), open_max);
+        // Upstream corners only carry themselves
+        assert_eq!((at(0, 0), at(0, 10)), (1, 1));
+    }
+
+    #[test]
+    fn gradient_magnitude_rises_where_the_gap_costs_extra() {
+        let mut field = FlowField::new(11, 11);
+        for y in (0..11).filter(|&y| y != 5) {
+            field.set_obstacle(5, y, true);
+        }
+        let mut soft = vec![0.0; 11 * 11];
+        soft[5 * 11 + 5] = 3.0;
+        field.set_soft_costs(soft);
+        field.generate_target(9.0, 5.0).unwrap();
+        let gradient = field.gradient_magnitude();
+
+        assert_eq!(gradient[5 * 11 + 5], 4.0);
+        assert_eq!(gradient[2 * 11 + 2], 1.0);
+        assert_eq!((gradient[5], gradient[5 * 11 + 9]), (0.0, 0.0), "wall and target");
+    }
+
+    #[test]
+    fn touched_reset_matches_a_full_fill() {
+        let mut full = FlowField::new(60, 60);
+        // A small walled room, so a target inside it only reaches a few cells
+        for i in 4..=16 {
+            for (x, y) in [(i, 4), (i, 16), (4, i), (16, i)] {
+                full.set_obstacle(x, y, true);
+            }
+        }
+        let mut touched = full.clone();
+        touched.reset_touched_only = true;
+
+        let mut step = |edit: &dyn Fn(&mut FlowField)| {
+            edit(&mut full);
+            edit(&mut touched);
+            assert_eq!(touched.integration, full.integration);
+            assert_eq!(touched.vectors, full.vectors);
+        };
+        step(&|f| f.generate_target(10.0, 10.0).unwrap());
+        step(&|f| f.generate_target(6.0, 14.0).unwrap());
+        // Open a door: the next fill reaches the whole map, and the one after shrinks back
+        step(&|f| {
+            f.set_obstacle(16, 10, false);
+            f.generate_target(30.0, 40.0).unwrap();
+        });
+        step(&|f| {
+            f.set_obstacle(16, 10, true);
+            f.generate_target(10.0, 10.0).unwrap();
+        });
+        step(&|f| f.generate_flee_target(8.0, 8.0, 20.0));
+        step(&|f| f.generate_targets(&[(6.0, 6.0), (14.0, 14.0)]));
+
+        // The selective path really ran: only the room's cells are on the list
+        let list = touched.touched.as_ref().unwrap();
+        assert!(!list.is_empty() && list.len() <= 11 * 11, "{}", list.len());
+    }
+
+    #[test]
+    fn batched_costs_match_single_queries() {
+        let mut field = FlowField::new(12, 8);
+        for y in 0..6 {
+            field.set_obstacle(5, y, true);
+        }
+        // A sealed-off corner cell: walkable but unreachable
+        field.set_obstacle(10, 6, true);
+        field.set_obstacle(11, 6, true);
+        field.set_obstacle(10, 7, true);
+        field.generate_target(9.0, 2.0).unwrap();
+
+        let points = [
+            (1.0, 1.0), (9.0, 2.0), (4.4, 6.6), (7.2, 3.9), // reachable
+            (5.0, 2.0), (11.0, 7.0), (20.0, 2.0), (-0.4, 0.0), (f64::NAN, 1.0), (1.0, f64::INFINITY),
+        ];
+        let costs = field.cost_at_cells(&points);
+        assert_eq!(costs.len(), points.len());
+        for (&(x, y), &cost) in points.iter().zip(&costs) {
+            assert_eq!(cost, field.cost_to_target(x, y).unwrap_or(f64::MAX), "({}, {})", x, y);
+        }
+        assert!(costs[..4].iter().all(|&c| c < f64::MAX));
+        assert!(costs[4..].iter().all(|&c| c == f64::MAX));
+        // Around the wall costs more than the straight-line distance
+        assert!(costs[0] > costs[3]);
+    }
+
+    #[test]
+    fn generate_targets_skips_nan_and_negative_targets() {
+        let mut field = FlowField::new(8, 8);
+        field.generate_targets(&[(f64::NAN, f64::NAN), (-3.0, 2.0), (2.0, f64::INFINITY), (-0.7, -0.7)]);
+        assert!(!field.has_target());
+        assert_eq!(field.cost_to_target(0.0, 0.0), None);
+
+        // Valid targets in the same batch still seed the field, and only they do
+        field.generate_targets(&[(f64::NAN, 1.0), (6.0, 5.0), (-2.0, -2.0)]);
+        assert!(field.has_target());
+        assert_eq!(field.cost_to_target(6.0, 5.0), Some(0.0));
+        assert!(field.cost_to_target(0.0, 0.0).unwrap() > 0.0);
+    }
+
+    #[test]
+    fn direction_split_only_flips_on_an_equal_cost_ridge() {
+        // Cell 2 sits between two cells of equal integration cost
+        let mut field = FlowField::new(5, 1);
+        field.integration = vec![0.0, 1.0, 2.0, 1.0, 0.0];
+        field.generate_vectors();
+        let dir = field.get_direction(2.0, 0.0);
+        let sides: Vec<DVec2> = (0..64).map(|salt| field.get_direction_split(2.0, 0.0, salt)).collect();
+        assert!(sides.contains(&dir) && sides.contains(&-dir), "flat ridge isn't split");
+
+        // Raising the right neighbour makes that way uphill: no salt may flip onto it
+        field.set_height(3, 0, 1.0);
+        field.set_slope_penalty(2.0);
+        field.generate_vectors();
+        let downhill = DVec2::new(-1.0, 0.0);
+        assert_eq!(field.get_direction(2.0, 0.0), downhill);
+        for salt in 0..64 {
+            assert_eq!(field.get_direction_split(2.0, 0.0, salt), downhill, "salt {} went uphill", salt);
+        }
+    }
+}
@@ -2,12 +2,17 @@ use crate::pathfinding::astar;
 use glam::IVec2;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
 
 // ============================================================================
 // Data Structures
 // ============================================================================
 
+/// Grid step costs, scaled by 10 so a diagonal (~sqrt(2)) stays an exact integer.
+/// Every HPA cost (local A*, abstract edges, heuristic) is in these units.
+pub const CARDINAL_COST: u32 = 10;
+pub const DIAGONAL_COST: u32 = 14;
+
 /// Represents a location in the abstract graph (a specific portal).
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PortalId(pub usize);
@@ -24,6 +29,7 @@ pub struct PortalNode {
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct AbstractEdge {
     pub to: PortalId,
+    /// In `CARDINAL_COST` units (10 per cardinal step, 14 per diagonal, or 10 with free diagonals).
     pub cost: u32,
     /// If true, this is a transition between clusters (len 1).
     /// If false, it is a path inside a cluster.
@@ -33,6 +39,15 @@ pub struct AbstractEdge {
     pub cached_path: Option<Vec<IVec2>>, 
 }
 
+/// Axis a `GridMap` is mirrored along.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MirrorAxis {
+    /// Flip left-right: x -> width - 1 - x.
+    X,
+    /// Flip top-bottom: y -> height - 1 - y.
+    Y,
+}
+
 /// The map data (walls/floors).
 #[derive(Clone, Serialize, Deserialize)]
 pub struct GridMap {
@@ -40,6 +55,39 @@ pub struct GridMap {
     pub height: i32,
     /// Row-major grid: index = y * width + x. True = Wall, False = Walkable.
     pub walls: Vec<bool>,
+    /// If true, searches may also step diagonally (never cutting a wall corner).
+    #[serde(default)]
+    pub diagonals: bool,
+    /// King-move model: diagonal steps cost the same as cardinal ones (`CARDINAL_COST`).
+    /// Only meaningful with `diagonals`.
+    #[serde(default)]
+    pub free_diagonals: bool,
+}
+
+/// Lower-bound distance estimate used by the grid searches. Each one is admissible
+/// (never overestimates) only for its own movement model, see `GridMap::heuristic`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Heuristic {
+    /// 4-way moves: `|dx| + |dy|`.
+    Manhattan,
+    /// 8-way moves, diagonals cost `DIAGONAL_COST`.
+    Octile,
+    /// 8-way moves, diagonals cost the same as cardinals: `max(|dx|, |dy|)`.
+    Chebyshev,
+}
+
+impl Heuristic {
+    /// Estimated cost from `a` to `b`, in `CARDINAL_COST` units.
+    pub fn estimate(self, a: IVec2, b: IVec2) -> u32 {
+        let dx = (a.x - b.x).unsigned_abs();
+        let dy = (a.y - b.y).unsigned_abs();
+        let (lo, hi) = (dx.min(dy), dx.max(dy));
+        match self {
+            Heuristic::Manhattan => CARDINAL_COST * (dx + dy),
+            Heuristic::Octile => DIAGONAL_COST * lo + CARDINAL_COST * (hi - lo),
+            Heuristic::Chebyshev => CARDINAL_COST * hi,
+        }
+    }
 }
 
 impl GridMap {
@@ -48,9 +96,37 @@ impl GridMap {
             width,
             height,
             walls: vec![false; (width * height) as usize],
+            diagonals: false,
+            free_diagonals: false,
         }
     }
 
+    /// Builds a map from row-major wall data in one call (nonzero = wall).
+    /// Fails if the dimensions are negative or `walls` doesn't hold exactly `width * height` cells.
+    pub fn from_flat(width: i32, height: i32, walls: &[u8]) -> Result<GridMap, String> {
+        if width < 0 || height < 0 {
+            return Err(format!("Invalid grid dimensions {}x{}", width, height));
+        }
+        let expected = width as usize * height as usize;
+        if walls.len() != expected {
+            return Err(format!(
+                "Wall data has {} cells, expected {} ({}x{})",
+                walls.len(),
+                expected,
+                width,
+                height
+            ));
+        }
+
+        Ok(Self {
+            width,
+            height,
+            walls: walls.iter().map(|&w| w != 0).collect(),
+            diagonals: false,
+            free_diagonals: false,
+        })
+    }
+
     pub fn is_walkable(&self, pos: IVec2) -> bool {
         if pos.x < 0 || pos.x >= self.width || pos.y < 0 || pos.y >= self.height {
             return false;
@@ -58,11 +134,163 @@ impl GridMap {
         !self.walls[(pos.y * self.width + pos.x) as usize]
     }
 
+    /// Enables diagonal moves. An HPA graph must be rebuilt for its edges to pick this up.
+    pub fn set_diagonals(&mut self, diagonals: bool) {
+        self.diagonals = diagonals;
+    }
+
+    /// Makes diagonal moves cost the same as cardinal ones (enabling diagonals if needed).
+    /// Searches then use the Chebyshev heuristic, the admissible one for that model.
+    /// An HPA graph must be rebuilt for its edges to pick this up.
+    pub fn set_free_diagonals(&mut self, free: bool) {
+        self.free_diagonals = free;
+        if free {
+            self.diagonals = true;
+        }
+    }
+
+    /// Cost of one diagonal step under this map's movement model.
+    pub fn diagonal_cost(&self) -> u32 {
+        if self.free_diagonals { CARDINAL_COST } else { DIAGONAL_COST }
+    }
+
+    /// The admissible heuristic for this map's movement model.
+    pub fn heuristic(&self) -> Heuristic {
+        match (self.diagonals, self.free_diagonals) {
+            (false, _) => Heuristic::Manhattan,
+            (true, false) => Heuristic::Octile,
+            (true, true) => Heuristic::Chebyshev,
+        }
+    }
+
     pub fn set_obstacle(&mut self, pos: IVec2, is_wall: bool) {
         if pos.x >= 0 && pos.x < self.width && pos.y >= 0 && pos.y < self.height {
             self.walls[(pos.y * self.width + pos.x) as usize] = is_wall;
         }
     }
+
+    /// Mirrored copy of the map (e.g. to build a symmetric map from one authored half).
+    pub fn mirrored(&self, axis: MirrorAxis) -> GridMap {
+        let (w, h) = (self.width, self.height);
+        self.transformed(w, h, |x, y| match axis {
+            MirrorAxis::X => IVec2::new(w - 1 - x, y),
+            MirrorAxis::Y => IVec2::new(x, h - 1 - y),
+        })
+    }
+
+    /// Copy of the map rotated by 180 degrees (point symmetry around the center).
+    pub fn rotated_180(&self) -> GridMap {
+        let (w, h) = (self.width, self.height);
+        self.transformed(w, h, |x, y| IVec2::new(w - 1 - x, h - 1 - y))
+    }
+
+    /// Copy of the map rotated by 90 degrees clockwise (y down), so width and height swap.
+    pub fn rotated_90(&self) -> GridMap {
+        let h = self.height;
+        self.transformed(h, self.width, |x, y| IVec2::new(h - 1 - y, x))
+    }
+
+    /// New `width x height` map where source cell (x, y) lands on `map(x, y)`.
+    fn transformed(&self, width: i32, height: i32, map: impl Fn(i32, i32) -> IVec2) -> GridMap {
+        let mut out = GridMap::new(width, height);
+        out.diagonals = self.diagonals;
+        out.free_diagonals = self.free_diagonals;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let to = map(x, y);
+                out.walls[(to.y * width + to.x) as usize] = self.walls[(y * self.width + x) as usize];
+            }
+        }
+        out
+    }
+
+    /// Makes every cell walkable, keeping the dimensions.
+    pub fn clear(&mut self) {
+        self.walls.fill(false);
+    }
+
+    /// Outlines of the walkable area as closed polylines on cell corners: one loop per
+    /// region border and one per hole. Only the corner vertices are kept (the last one
+    /// connects back to the first). Loops keep the walkable side on their right in
+    /// screen coordinates (y down), so outer borders run clockwise and holes counter-clockwise.
+    pub fn trace_boundaries(&self) -> Vec<Vec<IVec2>> {
+        // 1. Directed boundary edges: every side of a walkable cell that faces a wall or the map edge
+        let mut edges: Vec<(IVec2, IVec2)> = Vec::new();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let cell = IVec2::new(x, y);
+                if !self.is_walkable(cell) { continue; }
+
+                let (tl, tr) = (IVec2::new(x, y), IVec2::new(x + 1, y));
+                let (br, bl) = (IVec2::new(x + 1, y + 1), IVec2::new(x, y + 1));
+                if !self.is_walkable(cell + IVec2::new(0, -1)) { edges.push((tl, tr)); }
+                if !self.is_walkable(cell + IVec2::new(1, 0)) { edges.push((tr, br)); }
+                if !self.is_walkable(cell + IVec2::new(0, 1)) { edges.push((br, bl)); }
+                if !self.is_walkable(cell + IVec2::new(-1, 0)) { edges.push((bl, tl)); }
+            }
+        }
+
+        let mut outgoing: HashMap<IVec2, Vec<usize>> = HashMap::new();
+        for (i, &(from, _)) in edges.iter().enumerate() {
+            outgoing.entry(from).or_default().push(i);
+        }
+
+        // 2. Follow edges into loops, in scan order so the output is deterministic
+        let mut used = vec![false; edges.len()];
+        let mut loops = Vec::new();
+
+        for first in 0..edges.len() {
+            if used[first] { continue; }
+
+            let mut points = Vec::new();
+            let mut current = first;
+            loop {
+                used[current] = true;
+                let (from, to) = edges[current];
+                points.push(from);
+
+                let dir = to - from;
+                // Where two regions touch diagonally a corner has two exits: take the
+                // sharpest right turn so the loop keeps hugging its own region.
+                let right = IVec2::new(-dir.y, dir.x);
+                let next = outgoing.get(&to).and_then(|candidates| {
+                    candidates
+                        .iter()
+                        .copied()
+                        .filter(|&e| !used[e])
+                        .min_by_key(|&e| {
+                            let d = edges[e].1 - edges[e].0;
+                            if d == right { 0 } else if d == dir { 1 } else { 2 }
+                        })
+                });
+
+                match next {
+                    Some(e) => current = e,
+                    None => break, // Back at the start
+                }
+            }
+
+            // 3. Keep only the vertices where the outline changes direction
+            let n = points.len();
+            let corners: Vec<IVec2> = (0..n)
+                .filter(|&i| {
+                    let prev = points[(i + n - 1) % n];
+                    let next = points[(i + 1) % n];
+                    points[i] - prev != next - points[i]
+                })
+                .map(|i| points[i])
+                .collect();
+            loops.push(corners);
+        }
+
+        loops
+    }
+
+    /// Plain A* over the whole grid, without any hierarchy.
+    /// Slow on big maps; used as the fallback while an HPA graph is still building.
+    pub fn find_path_astar(&self, start: IVec2, end: IVec2) -> Option<Vec<IVec2>> {
+        a_star_local(self, start, end, IVec2::ZERO, IVec2::new(self.width, self.height)).map(|(_, path)| path)
+    }
 }
 
 // ============================================================================
@@ -81,8 +309,14 @@ pub struct HPAGrid {
     pub graph: Vec<Vec<AbstractEdge>>,
     
     /// Spatial lookup: Map Cluster Coordinate (x,y) -> List of Portal IDs in that cluster.
-    /// Using String key "x,y" for simple JSON compatibility.
-    pub cluster_lookup: HashMap<String, Vec<PortalId>>,
+    /// Using String key "x,y" for simple JSON compatibility. A BTreeMap, so it serializes
+    /// in a stable order and identical graphs give identical bytes.
+    pub cluster_lookup: BTreeMap<String, Vec<PortalId>>,
+
+    /// Clusters whose intra-cluster edges haven't been built yet (time-sliced build).
+    /// Empty once the graph is complete.
+    #[serde(default)]
+    pub pending_clusters: Vec<IVec2>,
 }
 
 impl HPAGrid {
@@ -92,15 +326,25 @@ impl HPAGrid {
             cluster_size,
             portals: Vec::new(),
             graph: Vec::new(),
-            cluster_lookup: HashMap::new(),
+            cluster_lookup: BTreeMap::new(),
+            pending_clusters: Vec::new(),
         }
     }
 
     /// Full build pipeline: Detect portals, build edges, finalize graph.
     pub fn build(&mut self) {
+        self.begin_build();
+        self.build_step(usize::MAX);
+    }
+
+    /// Time-sliced build, part 1: portals and inter-cluster edges (cheap).
+    /// The expensive intra-cluster edges are then built by `build_step`.
+    /// Paths can be queried at any point; see `find_path_with_stats`.
+    pub fn begin_build(&mut self) {
         self.portals.clear();
         self.graph.clear();
         self.cluster_lookup.clear();
+        self.pending_clusters.clear();
 
         // 1. Detect Portals along cluster boundaries
         self.create_portals();
@@ -111,8 +355,67 @@ impl HPAGrid {
         // 3. Connect Inter-Cluster edges (Portal <-> Portal neighbors)
         self.build_inter_cluster_edges();
 
-        // 4. Connect Intra-Cluster edges (Portal <-> Portal within same chunk)
-        self.build_intra_cluster_edges();
+        // 4. Queue every cluster for its Intra-Cluster edges, in row order
+        let clusters_w = (self.grid.width + self.cluster_size - 1) / self.cluster_size;
+        let clusters_h = (self.grid.height + self.cluster_size - 1) / self.cluster_size;
+        for cy in 0..clusters_h {
+            for cx in 0..clusters_w {
+                self.pending_clusters.push(IVec2::new(cx, cy));
+            }
+        }
+    }
+
+    /// Time-sliced build, part 2: builds the intra-cluster edges of up to
+    /// `max_clusters` pending clusters. Returns true once the graph is complete.
+    pub fn build_step(&mut self, max_clusters: usize) -> bool {
+        let count = max_clusters.min(self.pending_clusters.len());
+        let batch: Vec<IVec2> = self.pending_clusters.drain(..count).collect();
+        for cluster in batch {
+            self.build_intra_cluster_edges(cluster);
+        }
+
+        if self.pending_clusters.is_empty() {
+            self.sort_edges();
+        }
+        self.pending_clusters.is_empty()
+    }
+
+    /// Orders every portal's edges by target portal (then cost), so the abstract search
+    /// breaks ties the same way on every run, whatever order the edges were built in.
+    fn sort_edges(&mut self) {
+        for edges in &mut self.graph {
+            edges.sort_by_key(|edge| (edge.to.0, edge.cost));
+        }
+    }
+
+    /// Portal distribution across clusters, for tuning `cluster_size`.
+    /// Clusters without any portal count toward the min and mean.
+    pub fn portal_stats(&self) -> PortalStats {
+        let clusters_w = (self.grid.width + self.cluster_size - 1) / self.cluster_size;
+        let clusters_h = (self.grid.height + self.cluster_size - 1) / self.cluster_size;
+        let clusters = (clusters_w.max(0) * clusters_h.max(0)) as usize;
+
+        let mut counts = Vec::with_capacity(clusters);
+        for cy in 0..clusters_h {
+            for cx in 0..clusters_w {
+                let key = format!("{},{}", cx, cy);
+                counts.push(self.cluster_lookup.get(&key).map_or(0, |ids| ids.len()));
+            }
+        }
+
+        PortalStats {
+            clusters,
+            min_portals: counts.iter().copied().min().unwrap_or(0),
+            max_portals: counts.iter().copied().max().unwrap_or(0),
+            mean_portals: if clusters > 0 { self.portals.len() as f64 / clusters as f64 } else { 0.0 },
+            total_portals: self.portals.len(),
+            total_edges: self.graph.iter().map(|edges| edges.len()).sum(),
+        }
+    }
+
+    /// True once every cluster's intra-cluster edges are built.
+    pub fn is_built(&self) -> bool {
+        self.pending_clusters.is_empty()
     }
 
     /// Scans grid boundaries to place portals.
@@ -271,7 +574,7 @@ impl HPAGrid {
                     if neighbor_node.cluster_xy != p.cluster_xy {
                         self.graph[p.id.0].push(AbstractEdge {
                             to: neighbor_id,
-                            cost: 1, // Adjacent cost
+                            cost: CARDINAL_COST, // Adjacent cost
                             is_inter_cluster: true,
                             cached_path: None, // Trivial path
                         });
@@ -281,16 +584,14 @@ impl HPAGrid {
         }
     }
 
-    fn build_intra_cluster_edges(&mut self) {
-        // For each cluster, find all portals.
-        // Compute path between every pair of portals in that cluster.
-        for (key, portal_ids) in &self.cluster_lookup {
-            if portal_ids.len() < 2 { continue; }
+    fn build_intra_cluster_edges(&mut self, cluster: IVec2) {
+        // Find all portals of the cluster and
+        // compute path between every pair of them.
+        let key = format!("{},{}", cluster.x, cluster.y);
+        if let Some(portal_ids) = self.cluster_lookup.get(&key) {
+            if portal_ids.len() < 2 { return; }
 
-            // Get cluster bounds based on key (x,y)
-            let parts: Vec<&str> = key.split(',').collect();
-            let cx: i32 = parts[0].parse().unwrap();
-            let cy: i32 = parts[1].parse().unwrap();
+            let (cx, cy) = (cluster.x, cluster.y);
             
             let min_bound = IVec2::new(cx * self.cluster_size, cy * self.cluster_size);
             let max_bound = IVec2::new(
@@ -334,95 +635,293 @@ impl HPAGrid {
     // ========================================================================
 
     pub fn find_path(&self, start: IVec2, end: IVec2) -> Option<Vec<IVec2>> {
+        self.find_path_with_stats(start, end).0
+    }
+
+    /// Same as `find_path`, but also returns the true walked length of the grid path
+    /// (1 per cardinal step, sqrt(2) per diagonal), not the abstract edge cost.
+    pub fn find_path_with_length(&self, start: IVec2, end: IVec2) -> Option<(f64, Vec<IVec2>)> {
+        let path = self.find_path(start, end)?;
+        let length = path.windows(2).map(|w| w[0].as_dvec2().distance(w[1].as_dvec2())).sum();
+        Some((length, path))
+    }
+
+    /// Same as `find_path`, but also reports the total number of nodes expanded
+    /// across every local and abstract A* search the query needed.
+    pub fn find_path_with_stats(&self, start: IVec2, end: IVec2) -> (Option<Vec<IVec2>>, usize) {
+        let mut expansions = 0;
+
         if !self.grid.is_walkable(start) || !self.grid.is_walkable(end) {
-            return None;
+            return (None, expansions);
         }
 
-        let start_c = IVec2::new(start.x / self.cluster_size, start.y / self.cluster_size);
-        let end_c = IVec2::new(end.x / self.cluster_size, end.y / self.cluster_size);
+        let start_c = self.cluster_of(start);
+        let end_c = self.cluster_of(end);
 
         // Case 1: Same cluster. Just run local A*.
         if start_c == end_c {
              let bounds_min = start_c * self.cluster_size;
              let bounds_max = bounds_min + self.cluster_size;
-             return a_star_local(&self.grid, start, end, bounds_min, bounds_max).map(|x| x.1);
+             let path = a_star_local_counted(&self.grid, start, end, bounds_min, bounds_max, &mut expansions).map(|x| x.1);
+             return (path, expansions);
         }
 
         // Case 2: Different clusters. Hierarchical search.
-        
+        let path = self.hierarchical_search(start, end, start_c, end_c, &mut expansions).map(|(_, path)| path);
+
+        // Mid-build, the abstract graph can't yet cross clusters that are still pending.
+        // Fall back to a plain grid search so callers still get a valid path.
+        if path.is_none() && !self.is_built() {
+            return (self.grid.find_path_astar(start, end), expansions);
+        }
+        (path, expansions)
+    }
+
+    /// Debug/audit helper: `(hpa_cost, optimal_cost)` for the same query, both in grid step
+    /// costs (`CARDINAL_COST` / `diagonal_cost` per step), where the optimum comes from a
+    /// full-grid A*. Expensive on big maps; not meant for runtime use.
+    /// HPA paths detour through portals at the middle of boundary openings: across the open
+    /// and walled-rooms maps in the tests they average under 15% over optimal, but a short hop
+    /// across a cluster border can cost up to 2x.
+    /// None if either search finds no path.
+    pub fn path_quality(&self, start: IVec2, end: IVec2) -> Option<(u32, u32)> {
+        let hpa_path = self.find_path(start, end)?;
+        let (optimal_cost, _) = a_star_local(&self.grid, start, end, IVec2::ZERO, IVec2::new(self.grid.width, self.grid.height))?;
+
+        let hpa_cost = hpa_path
+            .windows(2)
+            .map(|w| {
+                let step = (w[1] - w[0]).abs();
+                if step.x != 0 && step.y != 0 { self.grid.diagonal_cost() } else { CARDINAL_COST }
+            })
+            .sum();
+        Some((hpa_cost, optimal_cost))
+    }
+
+    /// Batched version of `find_path` for groups of units (e.g. a formation).
+    ///
+    /// Requests sharing the same start and end clusters reuse the abstract portal
+    /// sequence found for the first of them, so only the local connectors
+    /// (start -> first portal, last portal -> end) are recomputed per request.
+    /// The reused sequence may be slightly longer than a fresh search would find.
+    pub fn request_paths(&self, requests: &[(IVec2, IVec2)]) -> PathBatch {
+        // Abstract portal sequences already found, keyed by (start cluster, end cluster).
+        let mut chains: HashMap<(IVec2, IVec2), Vec<PortalId>> = HashMap::new();
+        let mut abstract_searches = 0;
+
+        let paths = requests
+            .iter()
+            .map(|&(start, end)| {
+                if !self.grid.is_walkable(start) || !self.grid.is_walkable(end) {
+                    return None;
+                }
+
+                let start_c = self.cluster_of(start);
+                let end_c = self.cluster_of(end);
+
+                if start_c == end_c {
+                    let bounds_min = start_c * self.cluster_size;
+                    let bounds_max = bounds_min + self.cluster_size;
+                    return a_star_local(&self.grid, start, end, bounds_min, bounds_max).map(|x| x.1);
+                }
+
+                // Reuse a known portal sequence if our connectors can reach it.
+                if let Some(chain) = chains.get(&(start_c, end_c)) {
+                    if let Some(path) = self.path_along_chain(start, end, start_c, end_c, chain) {
+                        return Some(path);
+                    }
+                }
+
+                abstract_searches += 1;
+                match self.hierarchical_search(start, end, start_c, end_c, &mut 0) {
+                    Some((chain, path)) => {
+                        chains.insert((start_c, end_c), chain);
+                        Some(path)
+                    }
+                    // Same mid-build fallback as `find_path_with_stats`
+                    None if !self.is_built() => self.grid.find_path_astar(start, end),
+                    None => None,
+                }
+            })
+            .collect();
+
+        PathBatch { paths, abstract_searches }
+    }
+
+    /// Cluster coordinate containing a grid position.
+    fn cluster_of(&self, pos: IVec2) -> IVec2 {
+        IVec2::new(pos.x / self.cluster_size, pos.y / self.cluster_size)
+    }
+
+    /// Runs the full hierarchical search between two different clusters.
+    /// Returns the abstract portal sequence alongside the stitched grid path.
+    /// Adds the number of expanded nodes to `expansions`.
+    fn hierarchical_search(&self, start: IVec2, end: IVec2, start_c: IVec2, end_c: IVec2, expansions: &mut usize) -> Option<(Vec<PortalId>, Vec<IVec2>)> {
         // 1. Connect Start to Portals in Start Cluster
+        let start_edges = self.connect_start(start, start_c, expansions);
+        if start_edges.is_empty() { return None; } // Trapped in start cluster
+
+        // 2. Connect Portals in End Cluster to End
+        let end_costs = self.connect_end(end, end_c, expansions);
+        if end_costs.is_empty() { return None; } // End is unreachable from its own cluster borders
+
+        // 3. Run Abstract A*
+        let chain = self.abstract_search(&start_edges, &end_costs, end, expansions)?;
+
+        // 4. Reconstruct Path
+        let first = chain[0];
+        let last = *chain.last().unwrap();
+        let start_segment = &start_edges.iter().find(|(p_id, _, _)| *p_id == first)?.2;
+        let (_, end_segment) = end_costs.get(&last)?;
+
+        let path = self.stitch_path(start_segment, &chain, end_segment);
+        Some((chain, path))
+    }
+
+    /// Returns only the ordered portals the abstract search traverses from `start` to `end`,
+    /// skipping the (expensive) grid path reconstruction. Useful for caching or custom smoothing.
+    /// Same-cluster queries never touch the abstract graph and return an empty sequence.
+    pub fn find_portal_path(&self, start: IVec2, end: IVec2) -> Option<Vec<PortalId>> {
+        if !self.grid.is_walkable(start) || !self.grid.is_walkable(end) {
+            return None;
+        }
+
+        let start_c = self.cluster_of(start);
+        let end_c = self.cluster_of(end);
+
+        if start_c == end_c {
+            let bounds_min = start_c * self.cluster_size;
+            let bounds_max = bounds_min + self.cluster_size;
+            return a_star_local(&self.grid, start, end, bounds_min, bounds_max).map(|_| Vec::new());
+        }
+
+        let mut expansions = 0;
+        let start_edges = self.connect_start(start, start_c, &mut expansions);
+        let end_costs = self.connect_end(end, end_c, &mut expansions);
+        if start_edges.is_empty() || end_costs.is_empty() {
+            return None;
+        }
+
+        self.abstract_search(&start_edges, &end_costs, end, &mut expansions)
+    }
+
+    /// All portals reachable from `start` with a total abstract cost of at most `max_cost`
+    /// (in `CARDINAL_COST` units, i.e. 10 per cell), paired with that cost (bounded Dijkstra). Includes `start` itself at cost 0.
+    /// Sorted by portal id. Useful for region-of-control / influence queries.
+    pub fn portals_within(&self, start: PortalId, max_cost: u32) -> Vec<(PortalId, u32)> {
+        if start.0 >= self.portals.len() {
+            return Vec::new();
+        }
+
+        let mut dists = vec![u32::MAX; self.portals.len()];
+        let mut pq = BinaryHeap::new();
+
+        dists[start.0] = 0;
+        pq.push(State { cost: 0, position: start, heuristic_cost: 0 });
+
+        while let Some(State { cost, position, .. }) = pq.pop() {
+            if cost > dists[position.0] {
+                continue;
+            }
+
+            if let Some(edges) = self.graph.get(position.0) {
+                for edge in edges {
+                    let new_cost = cost.saturating_add(edge.cost);
+                    if new_cost <= max_cost && new_cost < dists[edge.to.0] {
+                        dists[edge.to.0] = new_cost;
+                        pq.push(State { cost: new_cost, position: edge.to, heuristic_cost: new_cost });
+                    }
+                }
+            }
+        }
+
+        dists
+            .iter()
+            .enumerate()
+            .filter(|(_, &d)| d != u32::MAX)
+            .map(|(i, &d)| (PortalId(i), d))
+            .collect()
+    }
+
+    /// Local paths from `start` to every reachable portal of its cluster.
+    fn connect_start(&self, start: IVec2, start_c: IVec2, expansions: &mut usize) -> Vec<(PortalId, u32, Vec<IVec2>)> {
         let start_key = format!("{},{}", start_c.x, start_c.y);
         let start_portals = self.cluster_lookup.get(&start_key).unwrap_or(&Vec::new()).clone();
         
         let mut start_edges: Vec<(PortalId, u32, Vec<IVec2>)> = Vec::new();
         
-        {
-             let b_min = start_c * self.cluster_size;
-             let b_max = b_min + self.cluster_size;
-             for &p_id in &start_portals {
-                 let p_pos = self.portals[p_id.0].pos;
-                 if let Some((cost, path)) = a_star_local(&self.grid, start, p_pos, b_min, b_max) {
-                     start_edges.push((p_id, cost, path));
-                 }
-             }
+        let b_min = start_c * self.cluster_size;
+        let b_max = b_min + self.cluster_size;
+        for &p_id in &start_portals {
+            let p_pos = self.portals[p_id.0].pos;
+            if let Some((cost, path)) = a_star_local_counted(&self.grid, start, p_pos, b_min, b_max, expansions) {
+                start_edges.push((p_id, cost, path));
+            }
         }
 
-        if start_edges.is_empty() { return None; } // Trapped in start cluster
+        start_edges
+    }
 
-        // 2. Connect Portals in End Cluster to End
+    /// Local paths from every portal of the end cluster that can reach `end`.
+    fn connect_end(&self, end: IVec2, end_c: IVec2, expansions: &mut usize) -> HashMap<PortalId, (u32, Vec<IVec2>)> {
         let end_key = format!("{},{}", end_c.x, end_c.y);
         let end_portals = self.cluster_lookup.get(&end_key).unwrap_or(&Vec::new()).clone();
         
         let mut end_costs: HashMap<PortalId, (u32, Vec<IVec2>)> = HashMap::new();
-        {
-             let b_min = end_c * self.cluster_size;
-             let b_max = b_min + self.cluster_size;
-             for &p_id in &end_portals {
-                 let p_pos = self.portals[p_id.0].pos;
-                 // Note: Calculate FROM portal TO end
-                 if let Some((cost, path)) = a_star_local(&self.grid, p_pos, end, b_min, b_max) {
-                     end_costs.insert(p_id, (cost, path));
-                 }
-             }
+
+        let b_min = end_c * self.cluster_size;
+        let b_max = b_min + self.cluster_size;
+        for &p_id in &end_portals {
+            let p_pos = self.portals[p_id.0].pos;
+            // Note: Calculate FROM portal TO end
+            if let Some((cost, path)) = a_star_local_counted(&self.grid, p_pos, end, b_min, b_max, expansions) {
+                end_costs.insert(p_id, (cost, path));
+            }
         }
-        
-        if end_costs.is_empty() { return None; } // End is unreachable from its own cluster borders
 
-        // 3. Run Abstract A*
-        // Nodes are PortalIds. 
-        // Start Set: start_edges.
-        // Goal: Any node in end_costs.
-        
+        end_costs
+    }
+
+    /// A* over the abstract graph.
+    /// Nodes are PortalIds. Start Set: `start_edges`. Goal: Any node in `end_costs`.
+    /// Returns the portal sequence from the first portal to the last one.
+    fn abstract_search(
+        &self,
+        start_edges: &[(PortalId, u32, Vec<IVec2>)],
+        end_costs: &HashMap<PortalId, (u32, Vec<IVec2>)>,
+        end: IVec2,
+        expansions: &mut usize,
+    ) -> Option<Vec<PortalId>> {
         let mut dists: HashMap<PortalId, u32> = HashMap::new();
-        let mut came_from: HashMap<PortalId, (PortalId, Vec<IVec2>)> = HashMap::new(); // (Parent, PathSegment)
+        let mut came_from: HashMap<PortalId, PortalId> = HashMap::new();
         let mut pq = BinaryHeap::new();
 
         // Initialize queue with Start->Portal connections
-        for (p_id, cost, _path) in &start_edges {
+        for (p_id, cost, _path) in start_edges {
             dists.insert(*p_id, *cost);
-            pq.push(State { cost: *cost, position: *p_id, heuristic_cost: *cost + heuristic(self.portals[p_id.0].pos, end) });
+            pq.push(State { cost: *cost, position: *p_id, heuristic_cost: *cost + self.grid.heuristic().estimate(self.portals[p_id.0].pos, end) });
         }
 
-        // Store the initial path from start to the first portal separately
-        let mut start_connections: HashMap<PortalId, Vec<IVec2>> = HashMap::new();
-        for (p_id, _, path) in &start_edges {
-             start_connections.insert(*p_id, path.clone());
-        }
-
-        let mut final_portal: Option<PortalId> = None;
-
         while let Some(State { cost, position, .. }) = pq.pop() {
             // Check if we found a connection to the end
-            if let Some((to_end_cost, _)) = end_costs.get(&position) {
-                let _total = cost + to_end_cost;
-                final_portal = Some(position);
-                break; 
+            if end_costs.contains_key(&position) {
+                // Walk back to the first portal in the chain
+                let mut chain = vec![position];
+                let mut curr = position;
+                while let Some(&parent) = came_from.get(&curr) {
+                    chain.push(parent);
+                    curr = parent;
+                }
+                chain.reverse();
+                return Some(chain);
             }
 
             if let Some(&d) = dists.get(&position) {
                 if cost > d { continue; }
             }
 
+            *expansions += 1;
+
             // Expand abstract neighbors
             if let Some(edges) = self.graph.get(position.0) {
                 for edge in edges {
@@ -430,68 +929,89 @@ impl HPAGrid {
                     
                     if new_cost < *dists.get(&edge.to).unwrap_or(&u32::MAX) {
                         dists.insert(edge.to, new_cost);
-                        let h = new_cost + heuristic(self.portals[edge.to.0].pos, end);
+                        let h = new_cost + self.grid.heuristic().estimate(self.portals[edge.to.0].pos, end);
                         pq.push(State { cost: new_cost, position: edge.to, heuristic_cost: h });
-                        
-                        // If cached path exists, use it. If inter-cluster, it's just 1 step.
-                        let segment = if edge.is_inter_cluster {
-                            vec![self.portals[position.0].pos, self.portals[edge.to.0].pos]
-                        } else {
-                            edge.cached_path.clone().unwrap_or_default()
-                        };
-                        came_from.insert(edge.to, (position, segment));
+                        came_from.insert(edge.to, position);
                     }
                 }
             }
         }
 
-        // 4. Reconstruct Path
-        if let Some(last_p) = final_portal {
-            let mut full_path = Vec::new();
-            
-            // A. End part
-            let (_, end_segment) = end_costs.get(&last_p).unwrap();
-            
-            // B. Abstract Graph part
-            let mut curr = last_p;
-            
-            let mut backward_segments: Vec<Vec<IVec2>> = Vec::new();
-            backward_segments.push(end_segment.clone()); // P_last -> End
+        None
+    }
 
-            while let Some((parent, segment)) = came_from.get(&curr) {
-                backward_segments.push(segment.clone());
-                curr = *parent;
-            }
+    /// Builds a path that follows a previously found portal sequence,
+    /// recomputing only the local connectors at both ends.
+    fn path_along_chain(&self, start: IVec2, end: IVec2, start_c: IVec2, end_c: IVec2, chain: &[PortalId]) -> Option<Vec<IVec2>> {
+        let first = self.portals[chain[0].0].pos;
+        let last = self.portals[chain.last()?.0].pos;
+
+        let s_min = start_c * self.cluster_size;
+        let (_, start_segment) = a_star_local(&self.grid, start, first, s_min, s_min + self.cluster_size)?;
+
+        let e_min = end_c * self.cluster_size;
+        let (_, end_segment) = a_star_local(&self.grid, last, end, e_min, e_min + self.cluster_size)?;
 
-            // C. Start part
-            // curr is now the first portal in the chain
-            if let Some(start_segment) = start_connections.get(&curr) {
-                 backward_segments.push(start_segment.clone());
+        Some(self.stitch_path(&start_segment, chain, &end_segment))
+    }
+
+    /// Joins Start->P_first, the abstract edges along `chain`, and P_last->End into one grid path.
+    fn stitch_path(&self, start_segment: &[IVec2], chain: &[PortalId], end_segment: &[IVec2]) -> Vec<IVec2> {
+        let mut segments: Vec<Vec<IVec2>> = Vec::with_capacity(chain.len() + 1);
+        segments.push(start_segment.to_vec());
+
+        for pair in chain.windows(2) {
+            segments.push(self.edge_segment(pair[0], pair[1]));
+        }
+        segments.push(end_segment.to_vec());
+
+        let mut full_path = Vec::new();
+        for segment in &segments {
+            // Avoid duplicating points where segments join
+            if !full_path.is_empty() && !segment.is_empty() && *full_path.last().unwrap() == segment[0] {
+                full_path.extend_from_slice(&segment[1..]);
             } else {
-                return None; 
-            }
-            
-            // backward_segments contains [P_last->End, P_prev->P_last, ..., Start->P_first]
-            for segment in backward_segments.iter().rev() {
-                // Avoid duplicating points where segments join
-                if !full_path.is_empty() && !segment.is_empty() {
-                    if *full_path.last().unwrap() == segment[0] {
-                        full_path.extend_from_slice(&segment[1..]);
-                    } else {
-                        full_path.extend_from_slice(segment);
-                    }
-                } else {
-                    full_path.extend_from_slice(segment);
-                }
+                full_path.extend_from_slice(segment);
             }
-            
-            return Some(full_path);
         }
+        full_path
+    }
 
-        None
+    /// Grid waypoints for the abstract edge `from -> to`.
+    fn edge_segment(&self, from: PortalId, to: PortalId) -> Vec<IVec2> {
+        let Some(edge) = self.graph[from.0].iter().filter(|e| e.to == to).min_by_key(|e| e.cost) else {
+            return Vec::new();
+        };
+
+        // If cached path exists, use it. If inter-cluster, it's just 1 step.
+        if edge.is_inter_cluster {
+            vec![self.portals[from.0].pos, self.portals[to.0].pos]
+        } else {
+            edge.cached_path.clone().unwrap_or_default()
+        }
     }
 }
 
+/// Result of `HPAGrid::request_paths`.
+pub struct PathBatch {
+    /// One entry per request, in request order.
+    pub paths: Vec<Option<Vec<IVec2>>>,
+    /// How many full abstract searches actually ran (the rest reused a portal sequence).
+    pub abstract_searches: usize,
+}
+
+/// Result of `HPAGrid::portal_stats`: how portals spread over clusters.
+#[derive(Serialize, Debug)]
+pub struct PortalStats {
+    pub clusters: usize,
+    pub min_portals: usize,
+    pub max_portals: usize,
+    pub mean_portals: f64,
+    pub total_portals: usize,
+    /// Directed edges in the abstract graph (each connection counts once per direction).
+    pub total_edges: usize,
+}
+
 // ============================================================================
 // Helpers
 // ============================================================================
@@ -519,25 +1039,41 @@ impl PartialOrd for State {
     }
 }
 
-fn heuristic(a: IVec2, b: IVec2) -> u32 {
-    ((a.x - b.x).abs() + (a.y - b.y).abs()) as u32
-}
-
 /// Standard A* limited to a bounding box (for intra-cluster search).
 /// Uses the generic implementation from `crate::pathfinding::astar`.
 fn a_star_local(grid: &GridMap, start: IVec2, end: IVec2, min: IVec2, max: IVec2) -> Option<(u32, Vec<IVec2>)> {
+    a_star_local_counted(grid, start, end, min, max, &mut 0)
+}
+
+/// `a_star_local` that adds the number of expanded nodes to `expansions`.
+fn a_star_local_counted(grid: &GridMap, start: IVec2, end: IVec2, min: IVec2, max: IVec2, expansions: &mut usize) -> Option<(u32, Vec<IVec2>)> {
+    a_star_local_with(grid, start, end, min, max, grid.heuristic(), expansions)
+}
+
+/// `a_star_local_counted` guided by an explicit `heuristic` instead of the map's own.
+fn a_star_local_with(grid: &GridMap, start: IVec2, end: IVec2, min: IVec2, max: IVec2, heuristic: Heuristic, expansions: &mut usize) -> Option<(u32, Vec<IVec2>)> {
     
     // Define neighbors closure
+    let in_box = |p: IVec2| p.x >= min.x && p.x < max.x && p.y >= min.y && p.y < max.y;
     let get_neighbors = |pos: IVec2| -> Vec<(IVec2, u32)> {
-        let mut neighbors = Vec::with_capacity(4);
+        let mut neighbors = Vec::with_capacity(8);
         // Directions: Up, Down, Right, Left
         for dir in [IVec2::new(0, 1), IVec2::new(0, -1), IVec2::new(1, 0), IVec2::new(-1, 0)] {
             let next = pos + dir;
             
             // Check Bounds
-            if next.x >= min.x && next.x < max.x && next.y >= min.y && next.y < max.y {
-                if grid.is_walkable(next) {
-                    neighbors.push((next, 1));
+            if in_box(next) && grid.is_walkable(next) {
+                neighbors.push((next, CARDINAL_COST));
+            }
+        }
+        if grid.diagonals {
+            for dir in [IVec2::new(1, 1), IVec2::new(1, -1), IVec2::new(-1, 1), IVec2::new(-1, -1)] {
+                let next = pos + dir;
+                // Both cells beside the diagonal must be open, so paths never clip a wall corner
+                let side_a = IVec2::new(pos.x + dir.x, pos.y);
+                let side_b = IVec2::new(pos.x, pos.y + dir.y);
+                if in_box(next) && grid.is_walkable(next) && grid.is_walkable(side_a) && grid.is_walkable(side_b) {
+                    neighbors.push((next, grid.diagonal_cost()));
                 }
             }
         }
@@ -546,7 +1082,7 @@ fn a_star_local(grid: &GridMap, start: IVec2, end: IVec2, min: IVec2, max: IVec2
 
     // Define heuristic closure
     let get_heuristic = |pos: IVec2| -> u32 {
-        heuristic(pos, end)
+        heuristic.estimate(pos, end)
     };
 
     // Define goal check closure
@@ -555,5 +1091,349 @@ fn a_star_local(grid: &GridMap, start: IVec2, end: IVec2, min: IVec2, max: IVec2
     };
 
     // Execute generic A*
-    astar::a_star(start, get_neighbors, get_heuristic, is_goal)
-}
\ No newline at end of file
+    let (result, count) = astar::a_star_counted(start, get_neighbors, get_heuristic, is_goal);
+    *expansions += count;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn built(grid: GridMap, cluster_size: i32) -> HPAGrid {
+        let mut hpa = HPAGrid::new(grid, cluster_size);
+        hpa.build();
+        hpa
+    }
+
+    #[test]
+    fn batched_requests_reuse_the_abstract_search() {
+        let hpa = built(GridMap::new(40, 40), 10);
+        let end = IVec2::new(35, 35);
+        let requests: Vec<(IVec2, IVec2)> = (0..50).map(|i| (IVec2::new(1 + i % 5, 1 + i / 5), end)).collect();
+
+        let batch = hpa.request_paths(&requests);
+        assert!(batch.abstract_searches < 5, "{} searches", batch.abstract_searches);
+        for (&(start, end), path) in requests.iter().zip(&batch.paths) {
+            let path = path.as_ref().unwrap();
+            assert_eq!((path[0], path[path.len() - 1]), (start, end));
+        }
+    }
+
+    #[test]
+    fn expansions_grow_with_map_size() {
+        let expansions = |size: i32| {
+            let hpa = built(GridMap::new(size, size), 10);
+            let (path, expansions) = hpa.find_path_with_stats(IVec2::new(1, 1), IVec2::new(size - 2, size - 2));
+            assert!(path.is_some());
+            expansions
+        };
+        let small = expansions(20);
+        let large = expansions(80);
+        assert!(small > 0);
+        assert!(large > small, "{} expansions on 80x80 vs {} on 20x20", large, small);
+    }
+
+    #[test]
+    fn portal_path_crosses_the_same_clusters_as_the_full_path() {
+        // Walls at x = 15 and x = 25 with gaps at opposite ends force a zigzag
+        let mut walls = vec![0u8; 40 * 40];
+        for y in 0..40 {
+            if y != 35 {
+                walls[y * 40 + 15] = 1;
+            }
+            if y != 3 {
+                walls[y * 40 + 25] = 1;
+            }
+        }
+        let hpa = built(GridMap::from_flat(40, 40, &walls).unwrap(), 10);
+        let (start, end) = (IVec2::new(2, 2), IVec2::new(37, 37));
+
+        let mut path_clusters: Vec<IVec2> = hpa.find_path(start, end).unwrap().iter().map(|&p| hpa.cluster_of(p)).collect();
+        path_clusters.dedup();
+        let mut portal_clusters: Vec<IVec2> = hpa.find_portal_path(start, end).unwrap()
+            .iter()
+            .map(|id| hpa.portals[id.0].cluster_xy)
+            .collect();
+        portal_clusters.dedup();
+
+        assert_eq!(portal_clusters, path_clusters);
+    }
+
+    #[test]
+    fn from_flat_validates_length_and_maps_walls() {
+        assert!(GridMap::from_flat(3, 2, &[0, 0, 0, 0, 0]).is_err());
+        assert!(GridMap::from_flat(-1, 2, &[]).is_err());
+
+        let grid = GridMap::from_flat(3, 2, &[0, 1, 0, 0, 0, 7]).unwrap();
+        assert!(grid.is_walkable(IVec2::new(0, 0)));
+        assert!(!grid.is_walkable(IVec2::new(1, 0)));
+        assert!(grid.is_walkable(IVec2::new(0, 1)));
+        assert!(!grid.is_walkable(IVec2::new(2, 1)));
+    }
+
+    #[test]
+    fn portals_within_stops_at_the_budget() {
+        // 0 -10- 1 -15- 2, and 0 -40- 3
+        let mut hpa = HPAGrid::new(GridMap::new(1, 1), 1);
+        let edge = |to: usize, cost: u32| AbstractEdge { to: PortalId(to), cost, is_inter_cluster: true, cached_path: None };
+        hpa.portals = (0..4).map(|i| PortalNode { id: PortalId(i), pos: IVec2::ZERO, cluster_xy: IVec2::ZERO }).collect();
+        hpa.graph = vec![
+            vec![edge(1, 10), edge(3, 40)],
+            vec![edge(0, 10), edge(2, 15)],
+            vec![edge(1, 15)],
+            vec![edge(0, 40)],
+        ];
+
+        assert_eq!(hpa.portals_within(PortalId(0), 25), vec![(PortalId(0), 0), (PortalId(1), 10), (PortalId(2), 25)]);
+        assert_eq!(hpa.portals_within(PortalId(0), 24), vec![(PortalId(0), 0), (PortalId(1), 10)]);
+        assert_eq!(hpa.portals_within(PortalId(2), 100).last(), Some(&(PortalId(3), 65)));
+        assert!(hpa.portals_within(PortalId(4), 100).is_empty());
+    }
+
+    /// Asserts `path` runs from `start` to `end` over walkable cells, one step at a time.
+    fn assert_walkable_path(grid: &GridMap, path: &[IVec2], start: IVec2, end: IVec2) {
+        assert_eq!(path.first(), Some(&start));
+        assert_eq!(path.last(), Some(&end));
+        assert!(path.iter().all(|&p| grid.is_walkable(p)));
+        assert!(path.windows(2).all(|w| (w[1] - w[0]).abs().max_element() == 1));
+    }
+
+    #[test]
+    fn paths_are_found_mid_build() {
+        let mut walls = vec![0u8; 40 * 40];
+        for y in 0..39 {
+            walls[y * 40 + 20] = 1;
+        }
+        let mut hpa = HPAGrid::new(GridMap::from_flat(40, 40, &walls).unwrap(), 10);
+        let (start, end) = (IVec2::new(2, 2), IVec2::new(37, 2));
+
+        hpa.begin_build();
+        assert!(!hpa.build_step(2));
+        let path = hpa.find_path(start, end).expect("no path mid-build");
+        assert_walkable_path(&hpa.grid, &path, start, end);
+
+        while !hpa.build_step(2) {}
+        let path = hpa.find_path(start, end).expect("no path once built");
+        assert_walkable_path(&hpa.grid, &path, start, end);
+    }
+
+    #[test]
+    fn path_length_sums_the_grid_steps() {
+        let mut walls = vec![0u8; 30 * 30];
+        for y in 5..30 {
+            walls[y * 30 + 12] = 1;
+        }
+        let mut grid = GridMap::from_flat(30, 30, &walls).unwrap();
+        grid.diagonals = true;
+        let hpa = built(grid, 10);
+
+        let (length, path) = hpa.find_path_with_length(IVec2::new(2, 25), IVec2::new(25, 25)).unwrap();
+        let (mut cardinal, mut diagonal) = (0, 0);
+        for w in path.windows(2) {
+            match (w[1] - w[0]).abs() {
+                IVec2 { x: 1, y: 1 } => diagonal += 1,
+                _ => cardinal += 1,
+            }
+        }
+        assert!(diagonal > 0);
+        assert!((length - (cardinal as f64 + diagonal as f64 * std::f64::consts::SQRT_2)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn portal_stats_match_the_graph() {
+        let mut walls = vec![0u8; 30 * 20];
+        for y in 0..20 {
+            if y % 4 != 0 {
+                walls[y * 30 + 10] = 1;
+            }
+        }
+        let hpa = built(GridMap::from_flat(30, 20, &walls).unwrap(), 10);
+        let stats = hpa.portal_stats();
+
+        assert_eq!(stats.clusters, 6);
+        assert_eq!(stats.total_portals, hpa.portals.len());
+        assert_eq!(stats.total_edges, hpa.graph.iter().map(|edges| edges.len()).sum::<usize>());
+        assert!(stats.min_portals <= stats.max_portals);
+        assert!((stats.mean_portals - hpa.portals.len() as f64 / 6.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn boundaries_trace_a_rectangle_and_its_hole() {
+        // 8x6 walkable area with a 2x2 wall block at (3, 2)
+        let mut grid = GridMap::new(8, 6);
+        for (x, y) in [(3, 2), (4, 2), (3, 3), (4, 3)] {
+            grid.set_obstacle(IVec2::new(x, y), true);
+        }
+        let mut loops = grid.trace_boundaries();
+        loops.sort_by_key(|l| std::cmp::Reverse(l.len()));
+
+        assert_eq!(loops.len(), 2);
+        let corners = |l: &[IVec2]| l.iter().map(|p| (p.x, p.y)).collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(loops[0].len(), 4);
+        assert_eq!(corners(&loops[0]), corners(&[IVec2::new(0, 0), IVec2::new(8, 0), IVec2::new(8, 6), IVec2::new(0, 6)]));
+        assert_eq!(loops[1].len(), 4);
+        assert_eq!(corners(&loops[1]), corners(&[IVec2::new(3, 2), IVec2::new(5, 2), IVec2::new(5, 4), IVec2::new(3, 4)]));
+    }
+
+    #[test]
+    fn rebuilt_graphs_give_identical_paths() {
+        // Scattered single-cell walls, so many routes tie on cost
+        let mut walls: Vec<u8> = (0..50 * 50).map(|i| u8::from((i * 7919) % 11 == 0)).collect();
+        walls[2 * 50 + 2] = 0;
+        walls[47 * 50 + 47] = 0;
+        let grid = GridMap::from_flat(50, 50, &walls).unwrap();
+
+        let first = built(grid.clone(), 10);
+        let second = built(grid, 10);
+        let query = (IVec2::new(2, 2), IVec2::new(47, 47));
+        let path = first.find_path(query.0, query.1);
+        assert!(path.is_some());
+        assert_eq!(path, second.find_path(query.0, query.1));
+        assert_eq!(serde_json::to_vec(&first).unwrap(), serde_json::to_vec(&second).unwrap());
+    }
+
+    #[test]
+    fn clear_reopens_every_cell() {
+        let mut grid = GridMap::new(7, 5);
+        for x in 0..7 {
+            grid.set_obstacle(IVec2::new(x, 2), true);
+        }
+        grid.clear();
+        assert_eq!((grid.width, grid.height), (7, 5));
+        for y in 0..5 {
+            for x in 0..7 {
+                assert!(grid.is_walkable(IVec2::new(x, y)));
+            }
+        }
+    }
+
+    #[test]
+    fn diagonal_paths_cost_fourteen_per_diagonal() {
+        let mut grid = GridMap::new(10, 10);
+        grid.diagonals = true;
+        let (min, max) = (IVec2::ZERO, IVec2::new(9, 9));
+
+        let (cost, path) = a_star_local(&grid, IVec2::ZERO, IVec2::new(4, 4), min, max).unwrap();
+        assert_eq!((cost, path.len()), (4 * DIAGONAL_COST, 5));
+
+        // 3 diagonals + 2 straight steps, and the octile heuristic is exact on an open map
+        let end = IVec2::new(3, 5);
+        let (cost, path) = a_star_local(&grid, IVec2::ZERO, end, min, max).unwrap();
+        assert_eq!(cost, 3 * DIAGONAL_COST + 2 * CARDINAL_COST);
+        let step_sum: u32 = path.windows(2)
+            .map(|w| if (w[1] - w[0]).abs() == IVec2::ONE { DIAGONAL_COST } else { CARDINAL_COST })
+            .sum();
+        assert_eq!(step_sum, cost);
+        assert_eq!(grid.heuristic().estimate(IVec2::ZERO, end), cost);
+    }
+
+    #[test]
+    fn mirrors_and_rotations_move_walls_to_the_matching_cells() {
+        let mut grid = GridMap::new(6, 4);
+        grid.set_obstacle(IVec2::new(1, 0), true);
+        let walls = |g: &GridMap| -> Vec<IVec2> {
+            (0..g.height).flat_map(|y| (0..g.width).map(move |x| IVec2::new(x, y)))
+                .filter(|&p| !g.is_walkable(p))
+                .collect()
+        };
+
+        assert_eq!(walls(&grid.mirrored(MirrorAxis::X)), vec![IVec2::new(4, 0)]);
+        assert_eq!(walls(&grid.mirrored(MirrorAxis::Y)), vec![IVec2::new(1, 3)]);
+        assert_eq!(walls(&grid.rotated_180()), vec![IVec2::new(4, 3)]);
+        let quarter = grid.rotated_90();
+        assert_eq!((quarter.width, quarter.height), (4, 6));
+        assert_eq!(walls(&quarter), vec![IVec2::new(3, 1)]);
+        // Mirroring twice is the identity
+        assert_eq!(grid.mirrored(MirrorAxis::X).mirrored(MirrorAxis::X).walls, grid.walls);
+    }
+
+    #[test]
+    fn hpa_paths_stay_within_the_documented_ratio_of_optimal() {
+        let mut rooms = GridMap::new(40, 40);
+        for y in (5..40).step_by(10) {
+            for x in (0..40).filter(|x| x % 10 != 3) {
+                rooms.set_obstacle(IVec2::new(x, y), true);
+            }
+        }
+        for grid in [GridMap::new(40, 40), rooms] {
+            let hpa = built(grid, 10);
+            let points: Vec<IVec2> = (0..5)
+                .flat_map(|i| (0..5).map(move |j| IVec2::new(2 + i * 9, 3 + j * 8)))
+                .filter(|&p| hpa.grid.is_walkable(p))
+                .collect();
+            let mut ratios = Vec::new();
+            for &a in &points {
+                for &b in points.iter().filter(|&&b| b != a) {
+                    let (hpa_cost, optimal_cost) = hpa.path_quality(a, b).unwrap();
+                    assert!(hpa_cost >= optimal_cost);
+                    ratios.push(hpa_cost as f64 / optimal_cost as f64);
+                }
+            }
+            let worst = ratios.iter().copied().fold(1.0, f64::max);
+            let mean = ratios.iter().sum::<f64>() / ratios.len() as f64;
+            assert!(worst <= 2.0 && mean < 1.15, "worst {} mean {}", worst, mean);
+        }
+
+        let walled = {
+            let mut grid = GridMap::new(10, 10);
+            grid.set_obstacle(IVec2::new(5, 5), true);
+            built(grid, 5)
+        };
+        assert_eq!(walled.path_quality(IVec2::ZERO, IVec2::new(5, 5)), None);
+    }
+
+    #[test]
+    fn free_diagonals_need_the_chebyshev_heuristic() {
+        let mut grid = GridMap::new(8, 8);
+        grid.set_free_diagonals(true);
+        for i in [1, 3, 5, 9, 20, 29, 34, 36, 38, 48, 51] {
+            grid.set_obstacle(IVec2::new(i % 8, i / 8), true);
+        }
+        assert_eq!(grid.heuristic(), Heuristic::Chebyshev);
+        let (start, end, max) = (IVec2::ZERO, IVec2::new(7, 7), IVec2::new(8, 8));
+
+        // Every step costs the same, so a breadth-first search gives the true optimum
+        let mut steps = HashMap::from([(start, 0u32)]);
+        let mut frontier = std::collections::VecDeque::from([start]);
+        while let Some(pos) = frontier.pop_front() {
+            for dir in [IVec2::X, IVec2::NEG_X, IVec2::Y, IVec2::NEG_Y, IVec2::ONE, IVec2::NEG_ONE, IVec2::new(1, -1), IVec2::new(-1, 1)] {
+                let next = pos + dir;
+                let open = [next, IVec2::new(next.x, pos.y), IVec2::new(pos.x, next.y)].iter().all(|&p| grid.is_walkable(p));
+                if open && !steps.contains_key(&next) {
+                    steps.insert(next, steps[&pos] + 1);
+                    frontier.push_back(next);
+                }
+            }
+        }
+        let optimal = steps[&end] * CARDINAL_COST;
+
+        let (cost, path) = a_star_local_with(&grid, start, end, IVec2::ZERO, max, Heuristic::Chebyshev, &mut 0).unwrap();
+        assert_eq!(cost, optimal);
+        assert_eq!(path.len() as u32, optimal / CARDINAL_COST + 1);
+        assert!(path.windows(2).any(|w| (w[1] - w[0]).abs() == IVec2::ONE));
+        assert_eq!(grid.find_path_astar(start, end).unwrap(), path);
+
+        // Manhattan overestimates diagonal progress and settles for a longer route
+        let (manhattan_cost, _) = a_star_local_with(&grid, start, end, IVec2::ZERO, max, Heuristic::Manhattan, &mut 0).unwrap();
+        assert!(manhattan_cost > optimal, "{} vs {}", manhattan_cost, optimal);
+    }
+
+    #[test]
+    fn identical_grids_serialize_to_identical_bytes() {
+        let mut grid = GridMap::new(40, 30);
+        for y in 0..25 {
+            grid.set_obstacle(IVec2::new(17, y), true);
+        }
+        let first = serde_json::to_vec(&built(grid.clone(), 8)).unwrap();
+        let second = serde_json::to_vec(&built(grid, 8)).unwrap();
+        assert_eq!(first, second);
+
+        // Lookups still work on a graph loaded back from those bytes
+        let loaded: HPAGrid = serde_json::from_slice(&first).unwrap();
+        assert!(loaded.cluster_lookup.len() > 1);
+        let path = loaded.find_path(IVec2::new(2, 2), IVec2::new(35, 2)).unwrap();
+        assert_eq!(path.last(), Some(&IVec2::new(35, 2)));
+        assert!(path.iter().any(|p| p.y >= 25), "went through the wall: {:?}", path);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::pathfinding::astar;
-use glam::DVec2;
+use crate::pathfinding::hpa::GridMap;
+use glam::{DVec2, IVec2};
 use serde::{Deserialize, Serialize};
 
 // ============================================================================
@@ -24,25 +25,178 @@ impl Triangle {
 #[derive(Clone, Serialize, Deserialize)]
 pub struct NavMesh {
     pub triangles: Vec<Triangle>,
+    /// Max squared distance at which two vertices are treated as the same point
+    /// when matching shared edges. Raise it for meshes authored at large world scales,
+    /// where float error between "equal" vertices exceeds the default.
+    #[serde(default = "default_vertex_epsilon")]
+    pub vertex_epsilon: f64,
+    /// Corner rounding: distance at which the path swings around sharp funnel corners.
+    /// 0 = off (plain funnel output).
+    #[serde(default)]
+    pub corner_radius: f64,
+    /// Corner rounding: only corners turning by more than this (radians) are rounded.
+    #[serde(default)]
+    pub corner_min_turn: f64,
+    /// Circular "avoid but passable" areas that scale A* traversal cost.
+    #[serde(default)]
+    pub cost_zones: Vec<CostZone>,
 }
 
+/// A circle on the mesh whose crossing costs `multiplier` times the normal distance.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct CostZone {
+    pub center: DVec2,
+    pub radius: f64,
+    pub multiplier: f64,
+}
+
+fn default_vertex_epsilon() -> f64 {
+    1e-5
+}
+
+/// Number of points used to replace each rounded corner (including the arc's ends).
+const CORNER_ARC_POINTS: usize = 4;
+
 // ============================================================================
 // NavMesh Implementation
 // ============================================================================
 
 impl NavMesh {
     pub fn new() -> Self {
-        Self { triangles: Vec::new() }
+        Self {
+            triangles: Vec::new(),
+            vertex_epsilon: default_vertex_epsilon(),
+            corner_radius: 0.0,
+            corner_min_turn: 0.0,
+            cost_zones: Vec::new(),
+        }
+    }
+
+    /// Builds a navmesh covering the walkable cells of `grid`: two triangles per cell,
+    /// with adjacency wired across every shared edge (so no T-junctions to worry about).
+    /// Cell (x, y) covers [x - 0.5, x + 0.5] x [y - 0.5, y + 0.5], matching the way the
+    /// flow field rounds world positions to cells.
+    pub fn from_grid(grid: &GridMap) -> NavMesh {
+        let mut mesh = NavMesh::new();
+        let (w, h) = (grid.width.max(0), grid.height.max(0));
+
+        // First triangle id of each walkable cell (the second one is id + 1)
+        let mut cell_tris: Vec<Option<usize>> = vec![None; (w * h) as usize];
+        let mut next_id = 0;
+        for y in 0..h {
+            for x in 0..w {
+                if grid.is_walkable(IVec2::new(x, y)) {
+                    cell_tris[(y * w + x) as usize] = Some(next_id);
+                    next_id += 2;
+                }
+            }
+        }
+
+        let tri_at = |x: i32, y: i32, second: bool| -> Option<usize> {
+            if x < 0 || y < 0 || x >= w || y >= h {
+                return None;
+            }
+            cell_tris[(y * w + x) as usize].map(|id| if second { id + 1 } else { id })
+        };
+
+        for y in 0..h {
+            for x in 0..w {
+                let Some(id) = tri_at(x, y, false) else { continue };
+
+                // CCW corners: a = (-,-), b = (+,-), c = (+,+), d = (-,+)
+                let (fx, fy) = (x as f64, y as f64);
+                let a = DVec2::new(fx - 0.5, fy - 0.5);
+                let b = DVec2::new(fx + 0.5, fy - 0.5);
+                let c = DVec2::new(fx + 0.5, fy + 0.5);
+                let d = DVec2::new(fx - 0.5, fy + 0.5);
+
+                // [a, b, c]: edges a-b (cell y-1), b-c (cell x+1), c-a (diagonal)
+                mesh.triangles.push(Triangle {
+                    id,
+                    vertices: [a, b, c],
+                    neighbors: [tri_at(x, y - 1, true), tri_at(x + 1, y, true), Some(id + 1)],
+                });
+                // [a, c, d]: edges a-c (diagonal), c-d (cell y+1), d-a (cell x-1)
+                mesh.triangles.push(Triangle {
+                    id: id + 1,
+                    vertices: [a, c, d],
+                    neighbors: [Some(id), tri_at(x, y + 1, false), tri_at(x - 1, y, false)],
+                });
+            }
+        }
+
+        mesh
+    }
+
+    /// Sets the vertex matching tolerance (squared distance) used by `find_shared_edge`.
+    /// NaN/infinite values are ignored.
+    pub fn set_vertex_epsilon(&mut self, epsilon: f64) {
+        if epsilon.is_finite() {
+            self.vertex_epsilon = epsilon;
+        }
+    }
+
+    /// Enables corner rounding: funnel corners turning by more than `min_turn` radians are
+    /// replaced by a short arc at `radius` around the corner, so followers turn smoothly.
+    /// Arcs that would leave the mesh are skipped. A radius of 0 disables it.
+    /// NaN/infinite values are ignored.
+    pub fn set_corner_rounding(&mut self, radius: f64, min_turn: f64) {
+        if !radius.is_finite() || !min_turn.is_finite() {
+            return;
+        }
+        self.corner_radius = radius.max(0.0);
+        self.corner_min_turn = min_turn;
+    }
+
+    /// Adds a danger zone: any A* step whose center-to-center segment passes within `radius`
+    /// of `center` costs `multiplier` times as much. Overlapping zones use the highest
+    /// multiplier. Multipliers below 1 are raised to 1 so the heuristic stays admissible.
+    /// Zones with NaN/infinite values are ignored.
+    pub fn add_cost_zone(&mut self, center: DVec2, radius: f64, multiplier: f64) {
+        if !center.is_finite() || !radius.is_finite() || !multiplier.is_finite() {
+            return;
+        }
+        self.cost_zones.push(CostZone {
+            center,
+            radius: radius.max(0.0),
+            multiplier: multiplier.max(1.0),
+        });
+    }
+
+    /// Removes every cost zone.
+    pub fn clear_cost_zones(&mut self) {
+        self.cost_zones.clear();
+    }
+
+    /// Cost multiplier for moving from `a` to `b` (1.0 outside every zone).
+    fn zone_multiplier(&self, a: DVec2, b: DVec2) -> f64 {
+        self.cost_zones
+            .iter()
+            .filter(|z| closest_point_on_segment(z.center, a, b).distance_squared(z.center) <= z.radius * z.radius)
+            .fold(1.0, |m, z| m.max(z.multiplier))
     }
 
     /// Finds the shortest path from start to end using A* on the mesh graph,
     /// followed by the Funnel Algorithm to smooth the path.
     pub fn find_path(&self, start: DVec2, end: DVec2) -> Vec<DVec2> {
+        self.find_path_with_stats(start, end).0
+    }
+
+    /// Same as `find_path`, but also reports how many triangles the A* expanded
+    /// (0 when the fast paths skip the search entirely).
+    pub fn find_path_with_stats(&self, start: DVec2, end: DVec2) -> (Vec<DVec2>, usize) {
+        self.find_path_limited(start, end, usize::MAX)
+            .unwrap_or_else(|expansions| (vec![], expansions))
+    }
+
+    /// Same as `find_path_with_stats`, but the A* gives up after `max_expansions` triangles.
+    /// Returns `Err(expansions)` if it was cut short (the query can be retried with more budget).
+    pub fn find_path_limited(&self, start: DVec2, end: DVec2, max_expansions: usize) -> Result<(Vec<DVec2>, usize), usize> {
         let start_tri_idx = self.find_triangle(start);
         let end_tri_idx = self.find_triangle(end);
 
         if start_tri_idx.is_none() || end_tri_idx.is_none() {
-            return vec![];
+            return Ok((vec![], 0));
         }
 
         let start_idx = start_tri_idx.unwrap();
@@ -50,18 +204,26 @@ impl NavMesh {
 
         // 1. If in the same triangle, straight line
         if start_idx == end_idx {
-            return vec![start, end];
+            return Ok((vec![start, end], 0));
+        }
+
+        // 2. If the triangles are direct neighbors, the corridor is just the two of them.
+        // Common for short moves, and it skips the A* heap setup entirely.
+        if self.triangles[start_idx].neighbors.contains(&Some(end_idx)) {
+            let path = self.string_pulling(start, end, &[start_idx, end_idx]);
+            return Ok((self.round_corners(path), 0));
         }
 
-        // 2. Perform A* to get list of triangle indices
-        let path_indices = self.compute_a_star(start_idx, end_idx);
+        // 3. Perform A* to get list of triangle indices
+        let (path_indices, expansions) = self.compute_a_star(start_idx, end_idx, max_expansions)?;
 
         if path_indices.is_empty() {
-            return vec![];
+            return Ok((vec![], expansions));
         }
 
-        // 3. Apply Funnel Algorithm
-        self.string_pulling(start, end, &path_indices)
+        // 4. Apply Funnel Algorithm (and optional corner rounding)
+        let path = self.string_pulling(start, end, &path_indices);
+        Ok((self.round_corners(path), expansions))
     }
 
     fn find_triangle(&self, point: DVec2) -> Option<usize> {
@@ -75,6 +237,64 @@ impl NavMesh {
         None
     }
 
+    /// Returns `p` unchanged if it lies on the mesh, otherwise the nearest point
+    /// on the nearest triangle. Returns `None` only if the mesh is empty.
+    pub fn clamp_to_mesh(&self, p: DVec2) -> Option<DVec2> {
+        let (tri_id, q) = self.closest_point(p)?;
+        if q == p {
+            return Some(p);
+        }
+
+        // Nudge slightly inward so float error on the edge can't leave the point outside again
+        Some(q + (self.triangles[tri_id].center() - q) * 1e-6)
+    }
+
+    /// The nearest mesh point to `p` and the triangle it lies on: `p` itself if it is on the
+    /// mesh, otherwise its projection onto the closest triangle's edges (e.g. to snap a click
+    /// just off the mesh). There is no spatial index, so triangles whose bounding box is
+    /// already farther than the best hit are culled before the full projection.
+    /// Returns `None` only if the mesh is empty.
+    pub fn closest_point(&self, p: DVec2) -> Option<(usize, DVec2)> {
+        if let Some(tri_id) = self.find_triangle(p) {
+            return Some((tri_id, p));
+        }
+
+        let mut best: Option<(f64, usize, DVec2)> = None; // (dist_sq, triangle id, point)
+        for tri in &self.triangles {
+            let [a, b, c] = tri.vertices;
+            let box_point = p.clamp(a.min(b).min(c), a.max(b).max(c));
+            if best.is_some_and(|(best_d, _, _)| p.distance_squared(box_point) >= best_d) {
+                continue;
+            }
+
+            let q = closest_point_on_triangle(p, tri.vertices);
+            let d = p.distance_squared(q);
+            if best.is_none_or(|(best_d, _, _)| d < best_d) {
+                best = Some((d, tri.id, q));
+            }
+        }
+        best.map(|(_, tri_id, q)| (tri_id, q))
+    }
+
+    /// True if `p` lies inside (or on the border of) triangle `tri_id`.
+    /// False for an unknown triangle id.
+    pub fn triangle_contains(&self, tri_id: usize, p: DVec2) -> bool {
+        self.triangles
+            .get(tri_id)
+            .is_some_and(|tri| self.point_in_triangle(p, tri.vertices))
+    }
+
+    /// True if `p` lies in any triangle of `tris` (e.g. the triangles making up a room).
+    /// Each triangle's bounding box is checked first, so only nearby ones get the full test.
+    /// Unknown triangle ids are ignored.
+    pub fn point_in_region(&self, p: DVec2, tris: &[usize]) -> bool {
+        tris.iter().filter_map(|&id| self.triangles.get(id)).any(|tri| {
+            let [a, b, c] = tri.vertices;
+            let (min, max) = (a.min(b).min(c), a.max(b).max(c));
+            p.cmpge(min).all() && p.cmple(max).all() && self.point_in_triangle(p, tri.vertices)
+        })
+    }
+
     fn point_in_triangle(&self, p: DVec2, v: [DVec2; 3]) -> bool {
         // Robust Cross Product method (Same Side technique)
         fn sign(p1: DVec2, p2: DVec2, p3: DVec2) -> f64 {
@@ -92,8 +312,10 @@ impl NavMesh {
     }
 
     /// A* Implementation on the Triangle Graph using the generic helper.
-    fn compute_a_star(&self, start_idx: usize, end_idx: usize) -> Vec<usize> {
-        let end_center = self.triangles[end_idx].center();
+    /// Returns the triangle corridor and the number of expanded triangles,
+    /// or `Err(expansions)` if the search ran out of `max_expansions`.
+    fn compute_a_star(&self, start_idx: usize, end_idx: usize, max_expansions: usize) -> Result<(Vec<usize>, usize), usize> {
+        let end_vertices = self.triangles[end_idx].vertices;
 
         // 1. Define Neighbors Closure
         let get_neighbors = |idx: usize| -> Vec<(usize, f64)> {
@@ -104,18 +326,24 @@ impl NavMesh {
             for &neighbor_opt in &current_tri.neighbors {
                 if let Some(n_idx) = neighbor_opt {
                     let neighbor_tri = &self.triangles[n_idx];
-                    // Cost is Euclidean distance between triangle centers
-                    let dist = current_center.distance(neighbor_tri.center());
-                    neighbors.push((n_idx, dist));
+                    // Cost is Euclidean distance between triangle centers, scaled by cost zones
+                    let neighbor_center = neighbor_tri.center();
+                    let dist = current_center.distance(neighbor_center);
+                    neighbors.push((n_idx, dist * self.zone_multiplier(current_center, neighbor_center)));
                 }
             }
             neighbors
         };
 
         // 2. Define Heuristic Closure
+        // Distance to the nearest point of the goal triangle (not its center): it can never
+        // exceed the remaining cost, even when a huge goal triangle puts its center far away.
         let get_heuristic = |idx: usize| -> f64 {
+            if idx == end_idx {
+                return 0.0;
+            }
             let center = self.triangles[idx].center();
-            center.distance(end_center)
+            center.distance(closest_point_on_triangle(center, end_vertices))
         };
 
         // 3. Define Goal Closure
@@ -124,10 +352,11 @@ impl NavMesh {
         };
 
         // 4. Run Generic A*
-        if let Some((_, path)) = astar::a_star(start_idx, get_neighbors, get_heuristic, is_goal) {
-            path
+        let (result, expansions) = astar::a_star_limited(start_idx, get_neighbors, get_heuristic, is_goal, max_expansions)?;
+        if let Some((_, path)) = result {
+            Ok((path, expansions))
         } else {
-            vec![]
+            Ok((vec![], expansions))
         }
     }
 
@@ -176,7 +405,9 @@ impl NavMesh {
                     right_index = i;
                 } else {
                     // Right crossed Left -> Add Left as a corner point
-                    points.push(portal_left);
+                    if points.last() != Some(&portal_left) {
+                        points.push(portal_left);
+                    }
                     portal_apex = portal_left;
                     portal_left = portal_apex;
                     portal_right = portal_apex;
@@ -200,7 +431,9 @@ impl NavMesh {
                     left_index = i;
                 } else {
                     // Left crossed Right -> Add Right as a corner point
-                    points.push(portal_right);
+                    if points.last() != Some(&portal_right) {
+                        points.push(portal_right);
+                    }
                     portal_apex = portal_right;
                     portal_left = portal_apex;
                     portal_right = portal_apex;
@@ -217,15 +450,70 @@ impl NavMesh {
             i += 1;
         }
 
-        points.push(end);
+        // The last portal is `end` itself, so it may already have been added as a corner
+        if points.last() != Some(&end) {
+            points.push(end);
+        }
         points
     }
 
-    // Helper: Signed triangle area * 2.
-    // Positive if CCW, Negative if CW, Zero if collinear.
-    // Used to determine if a point is to the left or right of a vector (apex -> p2).
+    /// Post-funnel pass: swaps each sharp interior corner for a small arc around it,
+    /// on the outside of the turn (the inside is where the obstacle is).
+    /// Uses normalized lerps instead of trig so the output stays deterministic.
+    fn round_corners(&self, path: Vec<DVec2>) -> Vec<DVec2> {
+        if self.corner_radius <= 0.0 || path.len() < 3 {
+            return path;
+        }
+
+        let min_turn_cos = self.corner_min_turn.cos();
+        let mut rounded = vec![path[0]];
+
+        for i in 1..path.len() - 1 {
+            let (prev, corner, next) = (path[i - 1], path[i], path[i + 1]);
+            let d_in = (corner - prev).normalize_or_zero();
+            let d_out = (next - corner).normalize_or_zero();
+
+            // Gentle turns (or degenerate segments) are kept as-is
+            if d_in == DVec2::ZERO || d_out == DVec2::ZERO || d_in.dot(d_out) >= min_turn_cos {
+                rounded.push(corner);
+                continue;
+            }
+
+            // Don't swing wider than half of either segment, or arcs could overlap
+            let radius = self.corner_radius
+                .min(corner.distance(prev) * 0.5)
+                .min(corner.distance(next) * 0.5);
+
+            // Outward normals: right of travel for a left turn, left for a right turn
+            let left_turn = d_in.perp_dot(d_out) > 0.0;
+            let outward = |d: DVec2| if left_turn { DVec2::new(d.y, -d.x) } else { d.perp() };
+            let (n_in, n_out) = (outward(d_in), outward(d_out));
+
+            let arc: Vec<DVec2> = (0..CORNER_ARC_POINTS)
+                .map(|k| {
+                    let t = k as f64 / (CORNER_ARC_POINTS - 1) as f64;
+                    corner + n_in.lerp(n_out, t).normalize_or_zero() * radius
+                })
+                .collect();
+
+            // The arc must stay on walkable triangles, otherwise keep the sharp corner
+            if arc.iter().all(|&p| self.find_triangle(p).is_some()) {
+                rounded.extend(arc);
+            } else {
+                rounded.push(corner);
+            }
+        }
+
+        rounded.push(path[path.len() - 1]);
+        rounded
+    }
+
+    // Helper: Signed triangle area * 2, with the sign flipped relative to the usual cross product.
+    // Positive if CW, Negative if CCW, Zero if collinear.
+    // Used to determine if a point is to the left or right of a vector (apex -> p2):
+    // negative = left, matching the (left, right) portal order from `find_shared_edge`.
     fn tri_area_2(&self, a: DVec2, b: DVec2, c: DVec2) -> f64 {
-        (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+        (c.x - a.x) * (b.y - a.y) - (b.x - a.x) * (c.y - a.y)
     }
 
     // Identifies the shared edge between two triangles and orients it relative to the path.
@@ -233,7 +521,7 @@ impl NavMesh {
     fn find_shared_edge(&self, curr: &Triangle, next: &Triangle) -> Option<(DVec2, DVec2)> {
         // Find the two vertices shared by both triangles
         let mut shared = Vec::with_capacity(2);
-        let epsilon = 1e-5;
+        let epsilon = self.vertex_epsilon;
 
         for &v_curr in &curr.vertices {
             for &v_next in &next.vertices {
@@ -272,4 +560,270 @@ impl NavMesh {
             Some((v1, v2))
         }
     }
-}
\ No newline at end of file
+}
+
+// ============================================================================
+// Helpers
+// ============================================================================
+
+/// Closest point to `p` on the segment a-b.
+fn closest_point_on_segment(p: DVec2, a: DVec2, b: DVec2) -> DVec2 {
+    let ab = b - a;
+    let len_sq = ab.length_squared();
+    if len_sq == 0.0 {
+        return a;
+    }
+    let t = ((p - a).dot(ab) / len_sq).clamp(0.0, 1.0);
+    a + ab * t
+}
+
+/// Closest point to `p` on the boundary of triangle `v` (for points outside it).
+fn closest_point_on_triangle(p: DVec2, v: [DVec2; 3]) -> DVec2 {
+    let candidates = [
+        closest_point_on_segment(p, v[0], v[1]),
+        closest_point_on_segment(p, v[1], v[2]),
+        closest_point_on_segment(p, v[2], v[0]),
+    ];
+
+    let mut best = candidates[0];
+    for &c in &candidates[1..] {
+        if p.distance_squared(c) < p.distance_squared(best) {
+            best = c;
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Linked mesh of one unit square per cell (see `cell_triangles`).
+    fn mesh_from_cells(cells: &[(i32, i32)]) -> NavMesh {
+        mesh_from_triangles(&cell_triangles(cells))
+    }
+
+    /// Two CCW triangles per unit square, each square's lower-left corner at its cell.
+    fn cell_triangles(cells: &[(i32, i32)]) -> Vec<[DVec2; 3]> {
+        let mut triangles = Vec::new();
+        for &(x, y) in cells {
+            let (x, y) = (x as f64, y as f64);
+            let quad = [DVec2::new(x, y), DVec2::new(x + 1.0, y), DVec2::new(x + 1.0, y + 1.0), DVec2::new(x, y + 1.0)];
+            triangles.extend([[quad[0], quad[1], quad[2]], [quad[0], quad[2], quad[3]]]);
+        }
+        triangles
+    }
+
+    /// Mesh of CCW triangles, linked wherever two share an edge exactly.
+    fn mesh_from_triangles(triangles: &[[DVec2; 3]]) -> NavMesh {
+        let mut mesh = NavMesh::new();
+        for &vertices in triangles {
+            let id = mesh.triangles.len();
+            mesh.triangles.push(Triangle { id, vertices, neighbors: [None; 3] });
+        }
+        let edge = |t: &Triangle, i: usize| (t.vertices[i], t.vertices[(i + 1) % 3]);
+        for a in 0..mesh.triangles.len() {
+            for i in 0..3 {
+                let (p, q) = edge(&mesh.triangles[a], i);
+                mesh.triangles[a].neighbors[i] = (0..mesh.triangles.len())
+                    .find(|&b| b != a && (0..3).any(|j| edge(&mesh.triangles[b], j) == (q, p)));
+            }
+        }
+        mesh
+    }
+
+    #[test]
+    fn funnel_keeps_straight_corridors_straight() {
+        let mesh = mesh_from_cells(&[(0, 0), (1, 0), (2, 0), (3, 0), (4, 0), (5, 0)]);
+        let (start, end) = (DVec2::new(0.2, 0.5), DVec2::new(5.8, 0.5));
+        assert_eq!(mesh.find_path(start, end), vec![start, end]);
+    }
+
+    #[test]
+    fn funnel_turns_once_around_an_inner_corner() {
+        let mesh = mesh_from_cells(&[(0, 0), (1, 0), (2, 0), (3, 0), (3, 1), (3, 2), (3, 3)]);
+        let (start, end) = (DVec2::new(0.5, 0.5), DVec2::new(3.5, 3.5));
+        assert_eq!(mesh.find_path(start, end), vec![start, DVec2::new(3.0, 1.0), end]);
+    }
+
+    #[test]
+    fn adjacent_triangles_skip_the_search() {
+        let mesh = mesh_from_cells(&[(0, 0), (1, 0), (2, 0)]);
+        let (start, end) = (DVec2::new(0.8, 0.3), DVec2::new(1.2, 0.7));
+        let (start_idx, end_idx) = (mesh.find_triangle(start).unwrap(), mesh.find_triangle(end).unwrap());
+        assert!(mesh.triangles[start_idx].neighbors.contains(&Some(end_idx)));
+
+        let (path, expansions) = mesh.find_path_with_stats(start, end);
+        assert_eq!(expansions, 0);
+
+        let (corridor, full_expansions) = mesh.compute_a_star(start_idx, end_idx, usize::MAX).unwrap();
+        assert!(full_expansions > 0);
+        assert_eq!(path, mesh.string_pulling(start, end, &corridor));
+    }
+
+    #[test]
+    fn vertex_epsilon_links_large_scale_meshes() {
+        // Two triangles around x = 10000 whose "shared" vertices drifted by 0.01
+        let drift = DVec2::new(0.01, -0.01);
+        let (a, b, c, d) = (DVec2::new(10000.0, 10000.0), DVec2::new(10010.0, 10000.0), DVec2::new(10010.0, 10010.0), DVec2::new(10000.0, 10010.0));
+        let curr = Triangle { id: 0, vertices: [a, b, c], neighbors: [None, None, Some(1)] };
+        let next = Triangle { id: 1, vertices: [a + drift, c + drift, d], neighbors: [Some(0), None, None] };
+
+        let mut mesh = NavMesh::new();
+        mesh.triangles = vec![curr.clone(), next.clone()];
+        assert_eq!(mesh.find_shared_edge(&curr, &next), None);
+
+        mesh.set_vertex_epsilon(1e-3);
+        let (left, right) = mesh.find_shared_edge(&curr, &next).unwrap();
+        assert_eq!([left, right].iter().filter(|&&v| v == a || v == c).count(), 2);
+        let (start, end) = (DVec2::new(10008.0, 10002.0), DVec2::new(10002.0, 10008.0));
+        assert_eq!(mesh.find_path(start, end), vec![start, end]);
+    }
+
+    #[test]
+    fn corridor_is_shortest_with_a_giant_triangle_detour() {
+        // A U of unit cells from (0, 0) to (5, 0), plus a shortcut between its feet
+        // through three slivers reaching 30 units down
+        let mut cells: Vec<(i32, i32)> = (0..6).map(|y| (0, y)).collect();
+        cells.extend((1..6).map(|x| (x, 5)));
+        cells.extend((0..5).map(|y| (5, y)));
+        let mut triangles = cell_triangles(&cells);
+        let (deep, inner_left, inner_right) = (DVec2::new(3.0, -30.0), DVec2::new(1.0, 1.0), DVec2::new(5.0, 1.0));
+        triangles.push([DVec2::new(1.0, 0.0), deep, inner_left]);
+        triangles.push([inner_left, deep, inner_right]);
+        triangles.push([deep, DVec2::new(5.0, 0.0), inner_right]);
+        let mesh = mesh_from_triangles(&triangles);
+
+        let center_cost = |path: &[usize]| -> f64 {
+            path.windows(2).map(|w| mesh.triangles[w[0]].center().distance(mesh.triangles[w[1]].center())).sum()
+        };
+        let (start, end) = (mesh.find_triangle(DVec2::new(0.5, 0.2)).unwrap(), mesh.find_triangle(DVec2::new(5.5, 0.2)).unwrap());
+        let (corridor, _) = mesh.compute_a_star(start, end, usize::MAX).unwrap();
+
+        // Uninformed search over the same graph is the ground truth
+        let neighbors = |idx: usize| -> Vec<(usize, f64)> {
+            mesh.triangles[idx].neighbors.iter().flatten()
+                .map(|&n| (n, center_cost(&[idx, n])))
+                .collect()
+        };
+        let (best, _) = astar::a_star_counted(start, neighbors, |_| 0.0, |idx| idx == end);
+        let (best_cost, _) = best.unwrap();
+        assert!((center_cost(&corridor) - best_cost).abs() < 1e-9);
+    }
+
+    #[test]
+    fn corner_rounding_swings_an_arc_around_sharp_turns() {
+        let mut mesh = mesh_from_cells(&[(0, 0), (1, 0), (2, 0), (3, 0), (3, 1), (3, 2), (3, 3)]);
+        let (start, end, corner) = (DVec2::new(0.5, 0.5), DVec2::new(3.5, 3.5), DVec2::new(3.0, 1.0));
+
+        mesh.set_corner_rounding(0.3, 0.5);
+        let path = mesh.find_path(start, end);
+        assert_eq!(path.len(), CORNER_ARC_POINTS + 2);
+        assert_eq!((path[0], path[path.len() - 1]), (start, end));
+        for &p in &path[1..path.len() - 1] {
+            assert!((p.distance(corner) - 0.3).abs() < 1e-9);
+            assert!(mesh.find_triangle(p).is_some(), "arc leaves the mesh at {:?}", p);
+        }
+
+        // The turn is about 67 degrees, gentler than the threshold: the corner stays sharp
+        mesh.set_corner_rounding(0.3, 1.3);
+        assert_eq!(mesh.find_path(start, end), vec![start, corner, end]);
+    }
+
+    #[test]
+    fn grid_meshes_route_around_obstacles() {
+        // 12x8 grid with columns 5-6 walled off in rows 0-5, open below
+        let mut grid = GridMap::new(12, 8);
+        for y in 0..6 {
+            for x in 5..7 {
+                grid.set_obstacle(IVec2::new(x, y), true);
+            }
+        }
+        let mesh = NavMesh::from_grid(&grid);
+        assert!(mesh.find_triangle(DVec2::new(5.5, 2.5)).is_none());
+
+        let (start, end) = (DVec2::new(1.5, 1.5), DVec2::new(10.5, 1.5));
+        let path = mesh.find_path(start, end);
+        assert_eq!((path[0], path[path.len() - 1]), (start, end));
+        // Every leg stays on walkable cells
+        for leg in path.windows(2) {
+            for k in 0..=100 {
+                let p = leg[0].lerp(leg[1], k as f64 / 100.0);
+                assert!(grid.is_walkable(p.round().as_ivec2()), "leg {:?} crosses the wall at {:?}", leg, p);
+            }
+        }
+        // ...which means going around the wall's open end
+        assert!(path.iter().any(|p| p.y >= 5.5), "{:?}", path);
+    }
+
+    #[test]
+    fn cost_zones_push_paths_around_the_hazard() {
+        let mut mesh = NavMesh::from_grid(&GridMap::new(20, 11));
+        let (start, end, hazard) = (DVec2::new(1.0, 5.0), DVec2::new(18.0, 5.0), DVec2::new(10.0, 5.0));
+        assert_eq!(mesh.find_path(start, end), vec![start, end]);
+
+        // A mild zone isn't worth the detour
+        mesh.add_cost_zone(hazard, 2.5, 1.1);
+        assert_eq!(mesh.find_path(start, end), vec![start, end]);
+
+        mesh.clear_cost_zones();
+        mesh.add_cost_zone(hazard, 2.5, 10.0);
+        let path = mesh.find_path(start, end);
+        assert!(path.iter().any(|p| (p.y - hazard.y).abs() >= 2.5), "{:?}", path);
+        let closest = path.windows(2)
+            .map(|leg| closest_point_on_segment(hazard, leg[0], leg[1]).distance(hazard))
+            .fold(f64::INFINITY, f64::min);
+        assert!(closest > 1.5, "path passes {} from the hazard: {:?}", closest, path);
+
+        mesh.clear_cost_zones();
+        assert_eq!(mesh.find_path(start, end), vec![start, end]);
+    }
+
+    #[test]
+    fn point_in_region_covers_both_triangles_of_a_room() {
+        let mesh = NavMesh::from_grid(&GridMap::new(3, 1));
+        // The room is the middle cell: the two triangles spanning [0.5, 1.5] x [-0.5, 0.5]
+        let room: Vec<usize> = mesh.triangles.iter()
+            .filter(|tri| (tri.center().x - 1.0).abs() < 0.5)
+            .map(|tri| tri.id)
+            .collect();
+        assert_eq!(room.len(), 2);
+
+        for &id in &room {
+            assert!(mesh.point_in_region(mesh.triangles[id].center(), &room));
+        }
+        assert!(mesh.point_in_region(DVec2::new(1.0, 0.0), &room));
+        for p in [DVec2::new(0.0, 0.0), DVec2::new(2.0, 0.0), DVec2::new(1.0, 2.0), DVec2::new(-3.0, -3.0)] {
+            assert!(!mesh.point_in_region(p, &room), "{:?}", p);
+        }
+        assert!(!mesh.point_in_region(DVec2::new(1.0, 0.0), &[]));
+        assert!(!mesh.point_in_region(DVec2::new(1.0, 0.0), &[99]));
+    }
+
+    #[test]
+    fn closest_point_snaps_clicks_onto_the_nearest_edge() {
+        let mut grid = GridMap::new(5, 3);
+        grid.set_obstacle(IVec2::new(2, 1), true);
+        let mesh = NavMesh::from_grid(&grid);
+        let on_edge = |tri_id: usize, q: DVec2| {
+            let v = mesh.triangles[tri_id].vertices;
+            (0..3).any(|i| closest_point_on_segment(q, v[i], v[(i + 1) % 3]).distance(q) < 1e-9)
+        };
+
+        // Just below the bottom border and just inside the walled-off cell
+        for (click, expected) in [(DVec2::new(1.2, -0.8), DVec2::new(1.2, -0.5)), (DVec2::new(2.4, 1.1), DVec2::new(2.5, 1.1))] {
+            let (tri_id, q) = mesh.closest_point(click).unwrap();
+            assert!(q.distance(expected) < 1e-9, "{:?} snapped to {:?}", click, q);
+            assert!(on_edge(tri_id, q), "{:?} isn't on triangle {}'s edges", q, tri_id);
+            assert!(mesh.triangle_contains(tri_id, q));
+        }
+
+        // Points already on the mesh stay put
+        let inside = DVec2::new(3.2, 0.1);
+        let (tri_id, q) = mesh.closest_point(inside).unwrap();
+        assert_eq!(q, inside);
+        assert!(mesh.triangle_contains(tri_id, inside));
+
+        assert_eq!(NavMesh::new().closest_point(inside), None);
+    }
+}
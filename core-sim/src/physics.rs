@@ -1,5 +1,7 @@
+use crate::pathfinding::flow::{default_terrain_mask, GROUND_MASK};
 use glam::DVec2;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct Agent {
@@ -8,17 +10,328 @@ pub struct Agent {
     pub velocity: DVec2,
     pub radius: f64,
     pub max_speed: f64,
+    /// Comfortable walking speed used when seeking. Avoidance may still push the agent
+    /// up to `max_speed`. 0 = walk at `max_speed` (see `cruise_speed`).
+    #[serde(default)]
+    pub pref_speed: f64,
     pub pref_velocity: DVec2, // The velocity the pathfinder WANTS
+    /// If true, the agent is kept on the navmesh: after moving, it is snapped
+    /// back onto the nearest walkable triangle if avoidance shoved it off.
+    #[serde(default)]
+    pub navmesh_bound: bool,
+    /// Flow group. Group 0 follows the global flow field; other groups follow
+    /// their own field, created by their first FLOW/FLEE order.
+    #[serde(default)]
+    pub group: u32,
+    /// Frozen agents (stunned, garrisoned) neither steer nor move, but still
+    /// block others like an immovable obstacle.
+    #[serde(default)]
+    pub frozen: bool,
+    /// Tick of the agent's most recent order. When two agents fight over the same
+    /// spot, the more recently ordered one holds it and the other gives way.
+    #[serde(default)]
+    pub last_order_tick: u64,
+    /// Unit vector the agent faces (ZERO until it first wants to move).
+    #[serde(default)]
+    pub facing: DVec2,
+    /// Max rotation per tick, in radians. 0 = turns instantly.
+    #[serde(default)]
+    pub max_turn_rate: f64,
+    /// Tank-style movement: while facing too far from its desired direction,
+    /// the agent pivots in place instead of moving.
+    #[serde(default)]
+    pub turn_in_place: bool,
+    /// Terrain types the agent may enter (bit per terrain type). Ground units can't
+    /// cross water; e.g. amphibious units add the water bit.
+    #[serde(default = "default_terrain_mask")]
+    pub terrain_mask: u32,
+    /// Props (loot, corpses): never seek or steer, only get pushed out of overlaps
+    /// (at most `max_speed * SEPARATION_MAX_SPEED` per tick).
+    #[serde(default)]
+    pub separation_only: bool,
+    /// Consecutive ticks the agent has wanted to move but barely did (see the unstick nudge).
+    #[serde(default)]
+    pub stuck_ticks: u32,
+    /// Side the agent fights for. Agents on different teams are enemies.
+    #[serde(default)]
+    pub team: u32,
+    /// Attack-move detection radius: while following its flow field, the agent halts
+    /// whenever an enemy is this close. 0 = plain move.
+    #[serde(default)]
+    pub attack_radius: f64,
 }
 
+impl Agent {
+    pub fn new(id: u32, position: DVec2, radius: f64, max_speed: f64) -> Self {
+        Self {
+            id,
+            position,
+            velocity: DVec2::ZERO,
+            radius,
+            max_speed,
+            pref_speed: 0.0,
+            pref_velocity: DVec2::ZERO,
+            navmesh_bound: false,
+            group: 0,
+            frozen: false,
+            last_order_tick: 0,
+            facing: DVec2::ZERO,
+            max_turn_rate: 0.0,
+            turn_in_place: false,
+            terrain_mask: GROUND_MASK,
+            separation_only: false,
+            stuck_ticks: 0,
+            team: 0,
+            attack_radius: 0.0,
+        }
+    }
+
+    /// Speed the agent seeks at: `pref_speed` if set, never above `max_speed`.
+    pub fn cruise_speed(&self) -> f64 {
+        if self.pref_speed > 0.0 {
+            self.pref_speed.min(self.max_speed)
+        } else {
+            self.max_speed
+        }
+    }
+}
+
+// Crowd relaxation tuning: agents with more than CROWD_THRESHOLD close neighbors
+// shrink their avoidance radius by CROWD_SHRINK_PER_NEIGHBOR per extra neighbor,
+// never below (1 - CROWD_MAX_SHRINK) of their real radius.
+const CROWD_THRESHOLD: usize = 4;
+const CROWD_SHRINK_PER_NEIGHBOR: f64 = 0.05;
+const CROWD_MAX_SHRINK: f64 = 0.25;
+/// Neighbors count as "close" within this multiple of the combined radii.
+const CROWD_RANGE: f64 = 1.5;
+
+// Density slowdown tuning: agents with more than DENSITY_THRESHOLD neighbors ahead of
+// them (within DENSITY_RANGE times the combined radii) lose DENSITY_SLOWDOWN_PER_NEIGHBOR
+// of their preferred speed per extra neighbor, down to DENSITY_MIN_SPEED.
+const DENSITY_THRESHOLD: usize = 2;
+const DENSITY_SLOWDOWN_PER_NEIGHBOR: f64 = 0.15;
+const DENSITY_MIN_SPEED: f64 = 0.3;
+const DENSITY_RANGE: f64 = 3.0;
+
+/// Turn-in-place agents only translate once within this angle (radians) of their desired direction.
+const TURN_IN_PLACE_ANGLE: f64 = 0.25;
+
+/// Fraction of the overlap each agent resolves per tick when already colliding.
+const SEPARATION_GAIN: f64 = 0.5;
+/// Cap on the separation push, as a fraction of the agent's max speed.
+const SEPARATION_MAX_SPEED: f64 = 0.5;
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct RvoManager {
     pub agents: Vec<Agent>,
+    /// If true, agents in dense crowds use a slightly smaller avoidance radius
+    /// so packs compress gracefully instead of jittering against each other.
+    #[serde(default)]
+    pub crowd_relaxation: bool,
+    /// If true, agents walking into a dense crowd scale their preferred speed down,
+    /// so they ease into chokepoints instead of ramming the jam at full speed.
+    #[serde(default)]
+    pub density_slowdown: bool,
+    /// Per-tick avoidance radii (parallel to `agents`), filled by `update_effective_radii`.
+    #[serde(skip)]
+    effective_radii: Vec<f64>,
+    /// Per-tick neighbor buckets, filled by `update_spatial_hash`.
+    #[serde(skip)]
+    spatial_hash: SpatialHash,
+}
+
+/// Uniform grid of agent indices used to narrow the avoidance neighbor search.
+/// Cells are as wide as the largest avoidance range, so every neighbor that can
+/// affect an agent sits in its own cell or one of the 8 around it.
+#[derive(Clone, Default)]
+pub struct SpatialHash {
+    pub cell_size: f64,
+    /// Agent indices per cell, keyed by cell coordinate. BTreeMap keeps iteration stable.
+    pub cells: BTreeMap<(i64, i64), Vec<usize>>,
+    /// Number of agents indexed (the hash is only trusted if this matches `agents.len()`).
+    indexed: usize,
+}
+
+/// One occupied spatial hash cell, as reported by `SpatialHash::buckets`.
+#[derive(Serialize, Debug)]
+pub struct SpatialBucket {
+    pub min_x: f64,
+    pub min_y: f64,
+    pub max_x: f64,
+    pub max_y: f64,
+    pub count: usize,
+}
+
+impl SpatialHash {
+    fn build(agents: &[Agent], cell_size: f64) -> SpatialHash {
+        let mut hash = SpatialHash { cell_size, cells: BTreeMap::new(), indexed: 0 };
+        if !(cell_size > 0.0 && cell_size.is_finite()) {
+            return hash;
+        }
+        for (i, agent) in agents.iter().enumerate() {
+            hash.cells.entry(hash.cell_of(agent.position)).or_default().push(i);
+        }
+        hash.indexed = agents.len();
+        hash
+    }
+
+    fn cell_of(&self, p: DVec2) -> (i64, i64) {
+        ((p.x / self.cell_size).floor() as i64, (p.y / self.cell_size).floor() as i64)
+    }
+
+    /// Bounds and agent count of every non-empty cell, for debug views.
+    pub fn buckets(&self) -> Vec<SpatialBucket> {
+        self.cells.iter()
+            .map(|(&(cx, cy), bucket)| SpatialBucket {
+                min_x: cx as f64 * self.cell_size,
+                min_y: cy as f64 * self.cell_size,
+                max_x: (cx + 1) as f64 * self.cell_size,
+                max_y: (cy + 1) as f64 * self.cell_size,
+                count: bucket.len(),
+            })
+            .collect()
+    }
+
+    /// Indices in every cell overlapping the square of half-size `radius` around `p`,
+    /// in ascending order. Callers still need an exact distance check.
+    pub fn within(&self, p: DVec2, radius: f64) -> Vec<usize> {
+        let (min_x, min_y) = self.cell_of(p - DVec2::splat(radius));
+        let (max_x, max_y) = self.cell_of(p + DVec2::splat(radius));
+        let mut out: Vec<usize> = self.cells
+            .range((min_x, i64::MIN)..=(max_x, i64::MAX))
+            .filter(|(&(_, cy), _)| (min_y..=max_y).contains(&cy))
+            .flat_map(|(_, bucket)| bucket.iter().copied())
+            .collect();
+        out.sort_unstable();
+        out
+    }
+
+    /// Indices in the 3x3 block of cells around `p`, in ascending order.
+    fn nearby(&self, p: DVec2) -> Vec<usize> {
+        let (cx, cy) = self.cell_of(p);
+        let mut out = Vec::new();
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                if let Some(bucket) = self.cells.get(&(cx + dx, cy + dy)) {
+                    out.extend_from_slice(bucket);
+                }
+            }
+        }
+        // Same order as a full scan, so results are bit-identical either way
+        out.sort_unstable();
+        out
+    }
 }
 
 impl RvoManager {
     pub fn new() -> Self {
-        Self { agents: Vec::new() }
+        Self {
+            agents: Vec::new(),
+            crowd_relaxation: false,
+            density_slowdown: false,
+            effective_radii: Vec::new(),
+            spatial_hash: SpatialHash::default(),
+        }
+    }
+
+    /// Recomputes each agent's avoidance radius from its local density.
+    /// Must be called once per tick before `compute_new_velocity`.
+    /// Radii relax back to normal automatically as the crowd thins out.
+    pub fn update_effective_radii(&mut self) {
+        self.effective_radii.clear();
+        if !self.crowd_relaxation {
+            return;
+        }
+
+        for agent in &self.agents {
+            let crowd = self.agents.iter()
+                .filter(|other| other.id != agent.id)
+                .filter(|other| {
+                    let range = (agent.radius + other.radius) * CROWD_RANGE;
+                    agent.position.distance_squared(other.position) < range * range
+                })
+                .count();
+
+            let extra = crowd.saturating_sub(CROWD_THRESHOLD) as f64;
+            let shrink = (extra * CROWD_SHRINK_PER_NEIGHBOR).min(CROWD_MAX_SHRINK);
+            self.effective_radii.push(agent.radius * (1.0 - shrink));
+        }
+    }
+
+    /// Rebuckets agents for the avoidance neighbor search.
+    /// Must be called once per tick, after `update_effective_radii` and before `compute_new_velocity`.
+    pub fn update_spatial_hash(&mut self) {
+        self.spatial_hash = self.build_spatial_hash();
+    }
+
+    /// Buckets agents at their current positions, the same way `update_spatial_hash` does.
+    pub fn build_spatial_hash(&self) -> SpatialHash {
+        // Avoidance ignores agents beyond 2x the combined radii, i.e. at most 4x the largest radius
+        let max_radius = (0..self.agents.len()).map(|i| self.radius_of(i)).fold(0.0, f64::max);
+        SpatialHash::build(&self.agents, max_radius * 4.0)
+    }
+
+    /// Rotates each agent's facing toward its preferred velocity, limited by `max_turn_rate`.
+    /// Turn-in-place agents that are still too far off get their preferred velocity zeroed,
+    /// so they pivot before moving. Call once per tick after preferred velocities are set.
+    pub fn apply_turning(&mut self) {
+        for agent in self.agents.iter_mut().filter(|a| !a.frozen) {
+            let desired = agent.pref_velocity.normalize_or_zero();
+            if desired == DVec2::ZERO {
+                continue;
+            }
+            if agent.facing == DVec2::ZERO {
+                agent.facing = desired;
+            }
+
+            let angle = agent.facing.angle_between(desired);
+            if agent.max_turn_rate <= 0.0 || angle <= agent.max_turn_rate {
+                agent.facing = desired;
+            } else {
+                // perp_dot picks the shorter way round (0 = straight behind: turn left)
+                let step = agent.max_turn_rate.copysign(agent.facing.perp_dot(desired));
+                agent.facing = DVec2::from_angle(step).rotate(agent.facing).normalize();
+            }
+
+            if agent.turn_in_place && agent.facing.angle_between(desired) > TURN_IN_PLACE_ANGLE {
+                agent.pref_velocity = DVec2::ZERO;
+            }
+        }
+    }
+
+    /// Scales each agent's preferred velocity down by the crowd density ahead of it.
+    /// Call once per tick after preferred velocities are set. No-op unless enabled.
+    /// Only neighbors in the direction of travel count, so the front of a moving
+    /// group isn't held back by the agents behind it.
+    pub fn apply_density_slowdown(&mut self) {
+        if !self.density_slowdown {
+            return;
+        }
+
+        let factors: Vec<f64> = self.agents.iter()
+            .map(|agent| {
+                let ahead = self.agents.iter()
+                    .filter(|other| other.id != agent.id)
+                    .filter(|other| {
+                        let rel_pos = other.position - agent.position;
+                        let range = (agent.radius + other.radius) * DENSITY_RANGE;
+                        rel_pos.length_squared() < range * range && rel_pos.dot(agent.pref_velocity) > 0.0
+                    })
+                    .count();
+
+                let extra = ahead.saturating_sub(DENSITY_THRESHOLD) as f64;
+                (1.0 - extra * DENSITY_SLOWDOWN_PER_NEIGHBOR).max(DENSITY_MIN_SPEED)
+            })
+            .collect();
+
+        for (agent, factor) in self.agents.iter_mut().zip(factors) {
+            agent.pref_velocity *= factor;
+        }
+    }
+
+    /// Avoidance radius for this tick (the real radius unless crowd relaxation shrank it).
+    fn radius_of(&self, idx: usize) -> f64 {
+        self.effective_radii.get(idx).copied().unwrap_or(self.agents[idx].radius)
     }
 
     pub fn add_agent(&mut self, agent: Agent) {
@@ -30,6 +343,17 @@ impl RvoManager {
         self.agents.clear();
     }
 
+    /// True if a disc of `radius` at `pos` overlaps no agent other than `ignore_id`.
+    /// Linear scan, same as the avoidance loop.
+    pub fn is_position_clear(&self, pos: DVec2, radius: f64, ignore_id: u32) -> bool {
+        self.agents.iter()
+            .filter(|agent| agent.id != ignore_id)
+            .all(|agent| {
+                let min_dist = radius + agent.radius;
+                pos.distance_squared(agent.position) >= min_dist * min_dist
+            })
+    }
+
     pub fn update_agent_state(&mut self, id: u32, pos: DVec2, pref_vel: DVec2) {
         if let Some(agent) = self.agents.iter_mut().find(|a| a.id == id) {
             agent.position = pos;
@@ -41,14 +365,25 @@ impl RvoManager {
     /// Uses a simplified RVO logic (Velocity Obstacles)
     pub fn compute_new_velocity(&self, agent_idx: usize) -> DVec2 {
         let agent = self.agents[agent_idx];
+        if agent.frozen {
+            return DVec2::ZERO;
+        }
         let mut new_vel = agent.pref_velocity;
 
-        // In a real engine, use a QuadTree/Grid here. For <500 units, O(N^2) is acceptable in Wasm.
-        for (i, other) in self.agents.iter().enumerate() {
+        // Only agents in nearby cells can be in range. Falls back to a full scan if the
+        // hash is stale (agents added or removed since `update_spatial_hash`).
+        let candidates: Vec<usize> = if self.spatial_hash.indexed == self.agents.len() {
+            self.spatial_hash.nearby(agent.position)
+        } else {
+            (0..self.agents.len()).collect()
+        };
+
+        for i in candidates {
             if i == agent_idx { continue; }
+            let other = &self.agents[i];
 
             let dist_sq = agent.position.distance_squared(other.position);
-            let combined_radius = agent.radius + other.radius;
+            let combined_radius = self.radius_of(agent_idx) + self.radius_of(i);
             
             // Optimization: Ignore far agents
             if dist_sq > (combined_radius * 2.0).powi(2) { continue; }
@@ -59,11 +394,32 @@ impl RvoManager {
             
             // Simple Repulsion / Velocity Obstacle Logic
             // If we are going to collide...
-            if dist < combined_radius {
-                // Already colliding: strong separation force
+            if dist_sq == 0.0 {
+                // Exactly on top of each other: there is no separation axis (and dividing by
+                // dist_sq would poison the sim with NaN). Pick one deterministically from the ids.
+                let push = coincident_push_dir(agent.id, other.id);
+                let strength = (combined_radius * SEPARATION_GAIN).min(agent.max_speed * SEPARATION_MAX_SPEED);
+                new_vel += push * strength;
+            } else if dist < combined_radius {
+                // Already colliding: separation force proportional to penetration depth.
+                // Capped so deep overlaps (e.g. after a teleport) resolve over a few ticks
+                // instead of launching both agents apart at full speed.
                 let push = rel_pos.normalize_or_zero() * -1.0;
-                new_vel += push * agent.max_speed;
-            } else {
+                let penetration = combined_radius - dist;
+                // A frozen agent won't give way, so resolve the whole overlap from this side.
+                // Otherwise the fresher order wins: the older one yields fully, the newer holds.
+                let gain = if other.frozen {
+                    1.0
+                } else {
+                    match agent.last_order_tick.cmp(&other.last_order_tick) {
+                        std::cmp::Ordering::Less => 1.0,
+                        std::cmp::Ordering::Greater => 0.0,
+                        std::cmp::Ordering::Equal => SEPARATION_GAIN,
+                    }
+                };
+                let strength = (penetration * gain).min(agent.max_speed * SEPARATION_MAX_SPEED);
+                new_vel += push * strength;
+            } else if !agent.separation_only {
                 // Future collision check (Time to collision)
                 // Project relative velocity onto relative position
                 let proj = rel_vel.dot(rel_pos) / dist_sq;
@@ -73,7 +429,7 @@ impl RvoManager {
                     // Calculate "Time to Interaction"
                     // Determine if the velocity vector falls inside the "Velocity Obstacle" cone
                     // Simplified: Steer perpendicular to the collision vector
-                    let tangent = DVec2::new(-rel_pos.y, rel_pos.x).normalize();
+                    let tangent = DVec2::new(-rel_pos.y, rel_pos.x).normalize_or_zero();
                     
                     // Choose the side that is closer to current velocity
                     let steer_dir = if new_vel.dot(tangent) > 0.0 { tangent } else { -tangent };
@@ -90,6 +446,49 @@ impl RvoManager {
             new_vel = new_vel.normalize() * agent.max_speed;
         }
 
+        // Last line of defense: never let a bad input (NaN/Inf) leak into positions
+        if !new_vel.is_finite() {
+            return DVec2::ZERO;
+        }
+
         new_vel
     }
-}
\ No newline at end of file
+}
+
+/// Deterministic separation direction for two agents at the exact same position.
+/// Both agents derive the same axis from their id pair and push in opposite directions.
+fn coincident_push_dir(id: u32, other_id: u32) -> DVec2 {
+    // Fixed table (no trig) so every platform picks bit-identical directions
+    const DIRS: [(f64, f64); 4] = [(1.0, 0.0), (0.0, 1.0), (0.6, 0.8), (0.8, -0.6)];
+
+    let (lo, hi) = if id < other_id { (id, other_id) } else { (other_id, id) };
+    let (x, y) = DIRS[(lo ^ hi) as usize % DIRS.len()];
+    let axis = DVec2::new(x, y);
+
+    if id < other_id { -axis } else { axis }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spatial_buckets_account_for_every_agent() {
+        let mut rvo = RvoManager::new();
+        // Radius 0.5 gives 2-unit cells; a 5x4 lattice 1.5 apart spans several of them
+        for i in 0..20 {
+            let position = DVec2::new((i % 5) as f64 * 1.5, (i / 5) as f64 * 1.5);
+            rvo.add_agent(Agent::new(i, position, 0.5, 1.0));
+        }
+
+        let buckets = rvo.build_spatial_hash().buckets();
+        assert!(buckets.len() > 1);
+        assert_eq!(buckets.iter().map(|b| b.count).sum::<usize>(), 20);
+        for agent in &rvo.agents {
+            let p = agent.position;
+            let holding: Vec<_> = buckets.iter()
+                .filter(|b| (b.min_x..b.max_x).contains(&p.x) && (b.min_y..b.max_y).contains(&p.y))
+                .collect();
+            assert_eq!(holding.len(), 1, "agent {} at {:?}", agent.id, p);
+        }
+    }
+}
@@ -32,9 +32,10 @@ export class SimBridge {
      * @param y - Initial Y position
      * @param radius - Collision radius
      * @param speed - Max movement speed
+     * @returns false if the simulation rejected the agent (max agent cap reached)
      */
-    addAgent(id: number, x: number, y: number, radius: number, speed: number) {
-        this.sim?.add_agent(id, x, y, radius, speed);
+    addAgent(id: number, x: number, y: number, radius: number, speed: number): boolean {
+        return this.sim?.add_agent(id, x, y, radius, speed) ?? false;
     }
 
     /**